@@ -1,21 +1,121 @@
 //! Client CLI
 //! The CLI for interacting with the client.
+//! Defaults for some options can be kept in a TOML config file, by default
+//! `~/.rusty-pub-sub.toml`, see `ConfigFile`. Options given on the command
+//! line take precedence over the file, which takes precedence over the
+//! built-in defaults.
 
-use std::io::Write;
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fs};
 
-use crate::client::Client;
+use crate::client::{Client, Encoding, Message, Priority, Sequence, CHANNEL_SEPARATOR};
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
+/// The host connected to when neither the command line nor the config file
+/// gives one.
+const DEFAULT_HOST: &str = "localhost";
+
+/// The name of the config file read from the home directory when no
+/// `--config` is given.
+const CONFIG_FILE_NAME: &str = ".rusty-pub-sub.toml";
+
+/// Seconds to wait for the server to confirm each subscription before
+/// listening when no `--timeout` is given.
+const SUBSCRIBE_TIMEOUT: u64 = 5;
+
+/// The defaults read from a config file, such as:
+///
+/// ```toml
+/// host = "pubsub.example.com"
+/// port = 7878
+/// timeout = 10
+/// channels = ["news", "sport"]
+/// ```
+///
+/// Every key is optional.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The hostname of the server, optionally followed by a port, as given
+    /// with `--host`.
+    pub host: Option<String>,
+    /// The port of the server, as given with `--port`.
+    pub port: Option<u16>,
+    /// Seconds to wait for a reply, as given with `--timeout`.
+    pub timeout: Option<u64>,
+    /// Channels to subscribe to when listening or receiving without any
+    /// `--sub`.
+    pub channels: Vec<String>,
+}
+
+impl ConfigFile {
+    /// Loads a config file.
+    /// # Arguments
+    /// * `path` - The file to load.
+    /// # Returns
+    /// The defaults in the file, or an error if the file cannot be read or is
+    /// not a valid config file.
+    pub fn load(path: &Path) -> io::Result<ConfigFile> {
+        toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Gets the config file read when no `--config` is given, in the user's
+    /// home directory.
+    /// # Returns
+    /// The path of the file, or `None` if there is no home directory.
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(CONFIG_FILE_NAME))
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "client")]
 pub struct Options {
-    /// The hostname of the server
-    #[structopt(short = "H", long, default_value = "localhost")]
-    pub host: String,
+    /// The hostname of the server, optionally followed by a port as
+    /// `host:port` or `[ipv6]:port` [default: localhost]
+    #[structopt(short = "H", long)]
+    pub host: Option<String>,
+
+    /// The port of the server, overriding any port given with the host
+    /// [default: 7878]
+    #[structopt(short = "P", long)]
+    pub port: Option<u16>,
+
+    /// How the server should write messages to the client (text or binary)
+    #[structopt(long, default_value = "text")]
+    pub encoding: Encoding,
+
+    /// The character separating the channel from the body of published
+    /// messages, matching the server's `--channel-separator`
+    #[structopt(long)]
+    pub channel_separator: Option<char>,
 
-    /// The port of the server
-    #[structopt(short = "P", long, default_value = "7878")]
-    pub port: u16,
+    /// Seconds to wait for the server to reply or for a message to be
+    /// received before giving up. Listening carries on waiting. Waits forever
+    /// when not set
+    #[structopt(long)]
+    pub timeout: Option<u64>,
+
+    /// Connects to the server over TLS
+    #[structopt(long)]
+    pub tls: bool,
+
+    /// PEM file holding the certificate authorities trusted to sign the
+    /// server's TLS certificate, instead of the Mozilla root certificates
+    #[structopt(long, parse(from_os_str), requires = "tls")]
+    pub tls_ca: Option<PathBuf>,
 
     /// Interactive mode
     #[structopt(short, long)]
@@ -25,6 +125,37 @@ pub struct Options {
     #[structopt(short, long)]
     pub ping: bool,
 
+    /// Checks that the server is up, printing its latency and version, and
+    /// exits with an error if it is not
+    #[structopt(long)]
+    pub health: bool,
+
+    /// The config file to read defaults from [default:
+    /// ~/.rusty-pub-sub.toml]
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Milliseconds to wait for the server to accept the connection, and then
+    /// to reply, when checking its health
+    #[structopt(long, default_value = "5000")]
+    pub health_timeout: u64,
+
+    /// Token to authenticate with
+    #[structopt(short, long = "auth")]
+    pub auth: Option<String>,
+
+    /// Subscribe to every channel (requires admin rights)
+    #[structopt(long = "sub-all")]
+    pub subscribe_all: bool,
+
+    /// Lists every connected client (requires admin rights)
+    #[structopt(long)]
+    pub clients: bool,
+
+    /// Lists every channel with at least one subscriber
+    #[structopt(long)]
+    pub list: bool,
+
     /// Channel to subscribe to
     #[structopt(short, long = "sub")]
     pub subscribe: Vec<String>,
@@ -38,10 +169,23 @@ pub struct Options {
     #[structopt(short, long)]
     pub channel: Option<String>,
 
-    /// Sends a message to the server
+    /// The priority of the message sent to a channel (low, normal or high)
+    #[structopt(long)]
+    pub priority: Option<Priority>,
+
+    /// Sends a message to the server, or the message read from stdin when
+    /// given as `-`
     #[structopt(short, long = "msg")]
     pub message: Option<String>,
 
+    /// A file holding the message to send, which is sent exactly as it is
+    #[structopt(long, parse(from_os_str), conflicts_with = "message")]
+    pub msg_file: Option<PathBuf>,
+
+    /// Publishes messages exactly as given, without adding a trailing newline
+    #[structopt(long)]
+    pub no_newline: bool,
+
     /// Waits to receive a message from the server
     #[structopt(short, long = "recv")]
     pub recv: bool,
@@ -49,37 +193,180 @@ pub struct Options {
     /// Listens continuously for messages from the server
     #[structopt(short, long)]
     pub listen: bool,
+
+    /// Prints each message received as a line of JSON with its channel,
+    /// message and timestamp
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Stops listening after this many messages, or never when 0. Exits with
+    /// an error if the connection is lost first. Any `--recv` waits for a
+    /// further message
+    #[structopt(long, requires = "listen")]
+    pub count: Option<usize>,
 }
 
 impl Options {
-    /// Parses and validates the command line arguments and returns a new
-    /// `Options` instance.
+    /// Parses and validates the command line arguments, filling in the
+    /// options they do not give from the config file, and returns a new
+    /// `Options` instance. A missing config file is only an error when it was
+    /// given with `--config`.
     /// # Returns
     /// A new `Options` instance.
     /// # Panics
-    /// An error if the command line arguments are invalid.
+    /// An error if the command line arguments or the config file are invalid.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Options {
         let mut opts = Options::from_args();
+        let file = match (&opts.config, ConfigFile::default_path()) {
+            (Some(path), _) => ConfigFile::load(path),
+            (None, Some(path)) => match ConfigFile::load(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+                result => result,
+            },
+            (None, None) => Ok(ConfigFile::default()),
+        };
+        let file = file.unwrap_or_else(|e| panic!("Could not read the config file: {}", e));
 
-        // If there is a channel defined but no message, then panic.
-        if opts.channel.is_some() && opts.message.is_none() {
-            panic!("You must specify a message to send when using a channel.");
+        if let Err(e) = opts.check_mode() {
+            panic!("{}", e);
         }
+        opts.merge(file);
 
-        // If no options are set, then set the interactive flag to true.
-        if !opts.ping
-            && opts.subscribe.is_empty()
-            && opts.unsubscribe.is_empty()
-            && !opts.channel.is_some()
-            && !opts.message.is_some()
-            && !opts.recv
-            && !opts.listen
-        {
-            opts.interactive = true;
+        // Fail early on an address which cannot be connected to.
+        if let Err(e) = Client::parse_endpoint(opts.host()) {
+            panic!("{}", e);
         }
 
         opts
     }
+
+    /// Checks that a channel is only given along with a message, from
+    /// `--msg` or `--msg-file`, and switches to interactive mode when no
+    /// option asks for anything to be done.
+    /// # Returns
+    /// An error if a channel is given without a message.
+    fn check_mode(&mut self) -> Result<(), &'static str> {
+        let has_message = self.message.is_some() || self.msg_file.is_some();
+        if self.channel.is_some() && !has_message {
+            return Err("You must specify a message to send when using a channel.");
+        }
+
+        if !self.ping
+            && !self.health
+            && !self.subscribe_all
+            && !self.clients
+            && !self.list
+            && self.subscribe.is_empty()
+            && self.unsubscribe.is_empty()
+            && self.channel.is_none()
+            && !has_message
+            && !self.recv
+            && !self.listen
+        {
+            self.interactive = true;
+        }
+        Ok(())
+    }
+
+    /// Fills in the options not given on the command line from a config
+    /// file. A port given as part of `--host` counts as given, so the file's
+    /// port does not replace it.
+    /// # Arguments
+    /// * `file` - The defaults read from the config file.
+    fn merge(&mut self, file: ConfigFile) {
+        let host_has_port = self
+            .host
+            .as_deref()
+            .is_some_and(|host| matches!(Client::split_endpoint(host), Ok((_, Some(_)))));
+        if self.port.is_none() && !host_has_port {
+            self.port = file.port;
+        }
+        if self.host.is_none() {
+            self.host = file.host;
+        }
+        if self.timeout.is_none() {
+            self.timeout = file.timeout;
+        }
+        if self.subscribe.is_empty() && (self.listen || self.recv) {
+            self.subscribe = file.channels;
+        }
+    }
+
+    /// Gets the host option, which may include a port.
+    fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or(DEFAULT_HOST)
+    }
+
+    /// Gets the address of the server from the host and port options.
+    /// # Returns
+    /// The host and port of the server.
+    pub fn endpoint(&self) -> (String, u16) {
+        let (host, port) = Client::parse_endpoint(self.host()).unwrap();
+        (host, self.port.unwrap_or(port))
+    }
+}
+
+/// A command entered in interactive mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Help,
+    Exit,
+    Ping,
+    Receive,
+    Listen,
+    Subscribe(String),
+    Unsubscribe(String),
+    SubscribeAll,
+    UnsubscribeAll,
+    Authenticate(String),
+    Publish {
+        channel: String,
+        message: String,
+    },
+    /// A message sent to the server as it was given, with `SEND`.
+    Send(String),
+    /// Any other command, sent to the server as it was entered.
+    Raw(String),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    /// Parses a line entered in interactive mode into its command and
+    /// arguments. Commands are not case sensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let line = s.trim();
+        let (verb, args) = line
+            .split_once(char::is_whitespace)
+            .map(|(verb, args)| (verb, args.trim_start()))
+            .unwrap_or((line, ""));
+        let required = |usage: &str| match args.is_empty() {
+            true => Err(format!("Usage: {}", usage)),
+            false => Ok(args.to_string()),
+        };
+        match verb.to_uppercase().as_str() {
+            "HELP" => Ok(Command::Help),
+            "DISCONNECT" | "EXIT" | "CLOSE" | "QUIT" => Ok(Command::Exit),
+            "PING" => Ok(Command::Ping),
+            "RECV" | "RECEIVE" => Ok(Command::Receive),
+            "LISTEN" => Ok(Command::Listen),
+            "SUBSCRIBE" => required("SUBSCRIBE <channel>").map(Command::Subscribe),
+            "UNSUBSCRIBE" => required("UNSUBSCRIBE <channel>").map(Command::Unsubscribe),
+            "SUBSCRIBE_ALL" => Ok(Command::SubscribeAll),
+            "UNSUBSCRIBE_ALL" => Ok(Command::UnsubscribeAll),
+            "AUTH" => required("AUTH <token>").map(Command::Authenticate),
+            "PUBLISH" => match args.split_once(char::is_whitespace) {
+                Some((channel, message)) => Ok(Command::Publish {
+                    channel: channel.to_string(),
+                    message: message.to_string(),
+                }),
+                None => Err("Usage: PUBLISH <channel> <message>".to_string()),
+            },
+            "SEND" => required("SEND <message>").map(Command::Send),
+            _ => Ok(Command::Raw(line.to_string())),
+        }
+    }
 }
 
 /// This struct holds the user's options and the client object. It is
@@ -104,8 +391,12 @@ impl Parser<'_> {
     /// Parses the user's input and calls the appropriate methods on the
     /// client.
     pub fn parse_args(&mut self) {
+        self.handle_auth();
+        self.handle_json();
         self.handle_interactive();
         self.handle_ping();
+        self.handle_clients();
+        self.handle_list();
         self.handle_subscriptions();
         self.handle_messages();
         self.handle_listening(false);
@@ -121,7 +412,22 @@ impl Parser<'_> {
             let mut line = String::new();
             print!("rusty-pub-sub> {}", line);
             std::io::stdout().flush().unwrap();
-            std::io::stdin().read_line(&mut line).unwrap();
+            match std::io::stdin().read_line(&mut line) {
+                // End of input (Ctrl-D) disconnects, as `EXIT` does.
+                Ok(0) => {
+                    println!();
+                    // Leaving anyway, so a connection which is already gone
+                    // does not matter.
+                    let _ = self.client.disconnect();
+                    break;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("Failed to read input: {}", e);
+                    let _ = self.client.disconnect();
+                    break;
+                }
+            }
             let line = line.trim().to_string();
 
             // if empty line, continue
@@ -130,33 +436,41 @@ impl Parser<'_> {
             }
             println!("{}", line);
 
-            match line.to_uppercase().as_str() {
-                "DISCONNECT" | "EXIT" | "CLOSE" | "QUIT" => {
-                    self.client.disconnect();
-                    break;
+            let command = match line.parse::<Command>() {
+                Ok(command) => command,
+                Err(usage) => {
+                    eprintln!("{}", usage);
+                    continue;
                 }
-                "RECV" | "RECEIVE" => {
-                    self.handle_receiving(true);
+            };
+            match command {
+                Command::Exit => {
+                    let _ = self.client.disconnect();
+                    break;
                 }
-                "HELP" => {
-                    println!("\nUSAGE:\n\t[COMMAND] [OPTIONS]\n");
-                    println!("HELP\t\t\t\t Shows this message");
-                    println!("EXIT\t\t\t\t Exits program",);
-                    println!("PING\t\t\t\t Pings the server");
-                    println!("SUBSCRIBE [channel]\t\t Subscribes to a channel");
-                    println!("UNSUBSCRIBE [channel]\t\t Unsubscribes from a channel");
-                    println!("PUBLISH [channel] [message]\t Publishes a message to a channel");
-                    println!("SEND [message]\t\t\t Publishes a message to the server");
-                    println!("RECV\t\t\t\t Receives a message from the server");
-                    println!("LISTEN\t\t\t\t Listens for messages from the server");
+                Command::Help => print_interactive_help(),
+                Command::Ping => {
+                    or_exit(self.client.ping());
+                    println!("PONG");
                 }
-                "LISTEN" => self.handle_listening(true),
-                _ => {
-                    let mut msg = line;
-                    if msg.to_uppercase().starts_with("PUBLISH") {
-                        msg.push_str("\n");
+                Command::Receive => self.handle_receiving(true),
+                Command::Listen => self.handle_listening(true),
+                Command::Subscribe(channel) => or_exit(self.client.subscribe(channel)),
+                Command::Unsubscribe(channel) => or_exit(self.client.unsubscribe(channel)),
+                Command::SubscribeAll => or_exit(self.client.subscribe_all()),
+                Command::UnsubscribeAll => or_exit(self.client.unsubscribe_all()),
+                // A wrong token is worth another try rather than exiting.
+                Command::Authenticate(token) => {
+                    if let Err(e) = self.client.authenticate(token) {
+                        eprintln!("Error: {}", e);
                     }
-                    self.client.send(msg);
+                }
+                Command::Publish { channel, message } => {
+                    let message = self.terminate(message);
+                    or_exit(self.client.publish(channel, message));
+                }
+                Command::Send(message) | Command::Raw(message) => {
+                    or_exit(self.client.send(message))
                 }
             };
         }
@@ -165,21 +479,69 @@ impl Parser<'_> {
     /// Pings the server.
     fn handle_ping(&mut self) {
         if self.options.ping {
-            self.client.ping().unwrap();
+            or_exit(self.client.ping());
         };
     }
 
-    // Handles subscribing and unsubscribing to channels.
+    /// Authenticates with the server.
+    fn handle_auth(&mut self) {
+        if let Some(token) = &self.options.auth {
+            or_exit(self.client.authenticate(token.to_string()));
+        }
+    }
+
+    /// Lists the clients connected to the server.
+    fn handle_clients(&mut self) {
+        if !self.options.clients {
+            return;
+        }
+        for info in or_exit(self.client.list_clients()) {
+            println!(
+                "{}\t{}\t{}\t{}",
+                info.id,
+                info.addr,
+                info.subscriptions,
+                info.name.unwrap_or_default()
+            );
+        }
+    }
+
+    /// Lists the channels with subscribers.
+    fn handle_list(&mut self) {
+        if !self.options.list {
+            return;
+        }
+        for channel in or_exit(self.client.list_channels()) {
+            println!("{}", channel);
+        }
+    }
+
+    // Handles subscribing and unsubscribing to channels. When listening, the
+    // server confirms every subscription before listening starts, so that no
+    // message published in the meantime is missed, and messages are received
+    // with their channel.
     fn handle_subscriptions(&mut self) {
-        if self.options.subscribe.len() > 0 {
+        if self.options.subscribe_all {
+            or_exit(self.client.subscribe_all());
+        }
+
+        if self.options.listen && !self.options.subscribe.is_empty() {
+            let timeout = self.options.timeout.unwrap_or(SUBSCRIBE_TIMEOUT);
+            or_exit(
+                self.client.subscribe_to_listen(
+                    self.options.subscribe.clone(),
+                    Duration::from_secs(timeout),
+                ),
+            );
+        } else if !self.options.subscribe.is_empty() {
             for channel in &self.options.subscribe {
-                self.client.subscribe(channel.to_string());
+                or_exit(self.client.subscribe(channel.to_string()));
             }
         }
 
-        if self.options.unsubscribe.len() > 0 {
+        if !self.options.unsubscribe.is_empty() {
             for channel in &self.options.unsubscribe {
-                self.client.unsubscribe(channel.to_string());
+                or_exit(self.client.unsubscribe(channel.to_string()));
             }
         }
     }
@@ -187,25 +549,89 @@ impl Parser<'_> {
     // Handles sending messages. Depending on whether a channel was specified,
     // the message will be sent to the server as a raw message, or sent with
     // the intent for it to be sent to published to a specific channel.
+    // Messages read from a file or stdin are sent exactly as read, and are
+    // published as bytes so that binary content survives.
     fn handle_messages(&mut self) {
-        if let Some(message) = &self.options.message {
-            if let Some(channel) = &self.options.channel {
-                let mut msg = message.to_string();
-                // "\n" is added to the end of the message to make it easier to
-                // allow the clients know that the message is complete.
-                msg.push_str("\n");
-                self.client.publish(channel.to_string(), msg.to_string());
-            } else {
-                self.client.send(message.to_string());
+        let message = match or_exit(read_payload(self.options, io::stdin().lock())) {
+            Some(payload) => payload,
+            None => match (&self.options.message, &self.options.channel) {
+                (Some(message), Some(_)) => self.terminate(message.to_string()).into_bytes(),
+                (Some(message), None) => message.clone().into_bytes(),
+                (None, _) => return,
+            },
+        };
+        let Some(channel) = &self.options.channel else {
+            let message = or_exit(
+                String::from_utf8(message)
+                    .map_err(|_| "A message sent without a channel must be UTF-8"),
+            );
+            return or_exit(self.client.send(message));
+        };
+        match self.options.priority {
+            Some(priority) => {
+                let message = or_exit(
+                    String::from_utf8(message)
+                        .map_err(|_| "A message published with a priority must be UTF-8"),
+                );
+                or_exit(
+                    self.client
+                        .publish_priority(channel.to_string(), message, priority),
+                );
             }
+            None => or_exit(self.client.publish_bytes(channel, &message)),
+        }
+    }
+
+    // Adds a trailing newline to a message being published, unless the user
+    // asked for messages to be published exactly as given.
+    fn terminate(&self, mut message: String) -> String {
+        if !self.options.no_newline {
+            message.push('\n');
+        }
+        message
+    }
+
+    // Asks the server for sequence numbers when printing messages as JSON, as
+    // they carry the channel each message was published on.
+    fn handle_json(&mut self) {
+        if self.options.json {
+            or_exit(self.client.enable_sequence_numbers());
         }
     }
 
     // Handles continuous listening for messages. All messages will be printed
-    // to the stdout.
+    // to the stdout. If the user specified a count, listening stops once that
+    // many messages have been printed. Listening also stops when the server
+    // closes the connection, which is only an error if the count was not
+    // reached.
     fn handle_listening(&mut self, force_true: bool) {
-        if self.options.listen || force_true {
-            self.client.listen(|message| println!("{}", message));
+        if !self.options.listen && !force_true {
+            return;
+        }
+        let (options, count) = (self.options, self.options.count);
+        let mut received = 0;
+        let result = self.client.listen_until(|sequence, message| {
+            print_received(options, sequence, message);
+            received += 1;
+            match count {
+                Some(count) if count > 0 && received >= count => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        if let Err(e) = result {
+            eprintln!("Lost connection to the server: {}", e);
+            std::process::exit(1);
+        }
+        match count {
+            Some(count) if count > 0 && received >= count => (),
+            Some(count) if count > 0 => {
+                eprintln!("disconnected from server");
+                std::process::exit(1);
+            }
+            _ => {
+                eprintln!("disconnected from server");
+                std::process::exit(0);
+            }
         }
     }
 
@@ -213,7 +639,475 @@ impl Parser<'_> {
     // then the client will listen for messages and print them to the console.
     fn handle_receiving(&mut self, force_true: bool) {
         if self.options.recv || force_true {
-            self.client.receive(|message| println!("{}", message));
+            let options = self.options;
+            match self
+                .client
+                .receive_sequenced(|sequence, message| print_received(options, sequence, message))
+            {
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    eprintln!("No message received before the timeout.")
+                }
+                result => or_exit(result),
+            }
+        }
+    }
+}
+
+/// Prints the commands accepted in interactive mode.
+fn print_interactive_help() {
+    println!("\nUSAGE:\n\t[COMMAND] [OPTIONS]\n");
+    println!("HELP\t\t\t\t Shows this message");
+    println!("EXIT\t\t\t\t Exits program",);
+    println!("PING\t\t\t\t Pings the server");
+    println!("SUBSCRIBE [channel]\t\t Subscribes to a channel");
+    println!("UNSUBSCRIBE [channel]\t\t Unsubscribes from a channel");
+    println!("SUBSCRIBE_ALL\t\t\t Subscribes to every channel (admin)");
+    println!("UNSUBSCRIBE_ALL\t\t\t Unsubscribes from every channel");
+    println!("AUTH [token]\t\t\t Authenticates with the server");
+    println!("PUBLISH [channel] [message]\t Publishes a message to a channel");
+    println!("SEND [message]\t\t\t Publishes a message to the server");
+    println!("RECV\t\t\t\t Receives a message from the server");
+    println!("LISTEN\t\t\t\t Listens for messages from the server");
+}
+
+/// Reads the message given with `--msg-file`, or with `--msg -` from stdin.
+/// # Arguments
+/// * `options` - The user's options.
+/// * `stdin` - Where a message given as `-` is read from.
+/// # Returns
+/// The bytes read, `None` if the message was not given as a file or as `-`,
+/// or an error if it could not be read.
+fn read_payload(options: &Options, mut stdin: impl Read) -> io::Result<Option<Vec<u8>>> {
+    if let Some(path) = &options.msg_file {
+        return fs::read(path)
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+    }
+    if options.message.as_deref() != Some("-") {
+        return Ok(None);
+    }
+    let mut payload = Vec::new();
+    stdin.read_to_end(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Gets the result of a command, or prints why it failed and exits, as
+/// nothing more can be done once the connection to the server is broken.
+/// # Arguments
+/// * `result` - The result of the command.
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Prints a message received from the server, as JSON when asked to with
+/// `--json`. Otherwise a message with a sequence number, as received when
+/// listening to subscribed channels, is prefixed with its channel.
+/// # Arguments
+/// * `options` - The user's options.
+/// * `sequence` - The sequence number of the message, if it has one.
+/// * `message` - The message.
+fn print_received(options: &Options, sequence: Option<&Sequence>, message: &Message) {
+    // Messages of a catch-all subscription start with their channel and the
+    // channel separator, which are taken off so that the channel is printed
+    // like that of any other message.
+    let (channel, message) = match options.subscribe_all {
+        true => {
+            let separator = options.channel_separator.unwrap_or(CHANNEL_SEPARATOR);
+            split_catch_all(message, separator)
         }
+        false => (
+            sequence.map(|sequence| sequence.channel.clone()),
+            message.clone(),
+        ),
+    };
+    match options.json {
+        true => println!(
+            "{}",
+            json_line(channel.as_deref(), &message, SystemTime::now())
+        ),
+        false => {
+            if let Some(channel) = channel {
+                print!("[{}] ", channel);
+            }
+            print_message(&message)
+        }
+    }
+}
+
+/// Splits the channel off a message received through a catch-all
+/// subscription, which starts with its channel and the channel separator.
+/// # Arguments
+/// * `message` - The message.
+/// * `separator` - The character separating the channel from the message.
+/// # Returns
+/// The channel and the message without it, or no channel and the message
+/// unchanged when it has none, such as a direct message.
+fn split_catch_all(message: &Message, separator: char) -> (Option<String>, Message) {
+    let (Message::Regular(text) | Message::System(text)) = message else {
+        return (None, message.clone());
+    };
+    let Some((channel, text)) = text.split_once(separator) else {
+        return (None, message.clone());
+    };
+    let text = text.to_string();
+    let message = match message {
+        Message::System(_) => Message::System(text),
+        _ => Message::Regular(text),
+    };
+    (Some(channel.to_string()), message)
+}
+
+/// A message received from the server, as printed with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonMessage<'a> {
+    /// The channel the message was published on, or `None` for messages which
+    /// were not published on a channel, such as direct messages.
+    channel: Option<&'a str>,
+    /// The text of the message.
+    message: &'a str,
+    /// When the message was received, see `format_timestamp`.
+    ts: String,
+}
+
+/// Formats a message received from the server as a line of JSON, such as
+/// `{"channel":"news","message":"hello","ts":"2024-05-01T12:00:00.000Z"}`.
+/// # Arguments
+/// * `channel` - The channel the message was published on, if known.
+/// * `message` - The message, without the channel a catch-all subscription
+///   receives it with, see `split_catch_all`.
+/// * `received` - When the message was received.
+fn json_line(channel: Option<&str>, message: &Message, received: SystemTime) -> String {
+    let message = JsonMessage {
+        channel,
+        message: message.text(),
+        ts: format_timestamp(received),
+    };
+    // Serialising strings cannot fail.
+    serde_json::to_string(&message).unwrap()
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC, to the millisecond, such
+/// as `2024-05-01T12:00:00.000Z`.
+/// # Arguments
+/// * `time` - The time to format.
+fn format_timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = (elapsed.as_secs() / 86400, elapsed.as_secs() % 86400);
+    // The civil date of a number of days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+/// Prints a message received from the server. System and direct messages
+/// are prefixed so that they stand out from regular messages.
+fn print_message(message: &Message) {
+    match message {
+        Message::Regular(text) => println!("{}", text),
+        Message::System(text) => println!("[SYSTEM] {}", text),
+        Message::Direct { from, text } => println!("[FROM {}] {}", from, text),
+        Message::Request { id, text } => println!("[REQUEST {}] {}", id, text),
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a config file to a temporary path.
+    /// # Arguments
+    /// * `name` - A name for the file, unique to the test.
+    /// * `contents` - The contents of the file.
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Test that a config file fills in the options not given on the command
+    /// line.
+    #[test]
+    fn test_merge_config_file() {
+        let path = write_config(
+            "test_merge_config_file",
+            "host = \"pubsub.example.com\"\nport = 9000\ntimeout = 10\nchannels = [\"news\"]\n",
+        );
+        let file = ConfigFile::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut options = Options::from_iter(["client", "--listen"]);
+        options.merge(file);
+        assert_eq!(options.endpoint(), ("pubsub.example.com".to_string(), 9000));
+        assert_eq!(options.timeout, Some(10));
+        assert_eq!(options.subscribe, vec!["news"]);
+    }
+
+    /// Test that options given on the command line take precedence over the
+    /// config file, including a port given as part of the host.
+    #[test]
+    fn test_merge_flags_take_precedence() {
+        let file = ConfigFile {
+            host: Some("pubsub.example.com".to_string()),
+            port: Some(9000),
+            timeout: Some(10),
+            channels: vec!["news".to_string()],
+        };
+        let mut options = Options::from_iter([
+            "client",
+            "--listen",
+            "--host",
+            "other:8000",
+            "--timeout",
+            "1",
+            "--sub",
+            "sport",
+        ]);
+        options.merge(file);
+        assert_eq!(options.endpoint(), ("other".to_string(), 8000));
+        assert_eq!(options.timeout, Some(1));
+        assert_eq!(options.subscribe, vec!["sport"]);
+
+        let mut options = Options::from_iter(["client", "--host", "other", "--ping"]);
+        options.merge(ConfigFile {
+            port: Some(9000),
+            channels: vec!["news".to_string()],
+            ..ConfigFile::default()
+        });
+        assert_eq!(options.endpoint(), ("other".to_string(), 9000));
+        // Nothing is listened to, so there is nothing to subscribe to.
+        assert!(options.subscribe.is_empty());
+    }
+
+    /// Test that the channel a catch-all subscription receives messages with
+    /// is split off at the channel separator, and that messages without one
+    /// are unchanged.
+    #[test]
+    fn test_split_catch_all() {
+        assert_eq!(
+            split_catch_all(
+                &Message::Regular("news\u{1f}hello world".to_string()),
+                '\u{1f}'
+            ),
+            (
+                Some("news".to_string()),
+                Message::Regular("hello world".to_string())
+            )
+        );
+        assert_eq!(
+            split_catch_all(&Message::System("news|closing".to_string()), '|'),
+            (
+                Some("news".to_string()),
+                Message::System("closing".to_string())
+            )
+        );
+        let direct = Message::Direct {
+            from: "1".to_string(),
+            text: "news\u{1f}hi".to_string(),
+        };
+        assert_eq!(split_catch_all(&direct, '\u{1f}'), (None, direct.clone()));
+    }
+
+    /// Test that a message printed as JSON parses back into its channel,
+    /// message and timestamp, and that messages without a channel have a
+    /// null channel.
+    #[test]
+    fn test_json_line() {
+        let received = UNIX_EPOCH + std::time::Duration::from_millis(951_782_400_500);
+        let line = json_line(
+            Some("news"),
+            &Message::Regular("hello \"world\"\n".to_string()),
+            received,
+        );
+        assert!(!line.contains('\n'));
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "channel": "news",
+                "message": "hello \"world\"\n",
+                "ts": "2000-02-29T00:00:00.500Z"
+            })
+        );
+
+        let line = json_line(
+            None,
+            &Message::Direct {
+                from: "1".to_string(),
+                text: "hi".to_string(),
+            },
+            received,
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["channel"], serde_json::Value::Null);
+        assert_eq!(parsed["message"], "hi");
+    }
+
+    /// Test that timestamps are formatted in UTC, across the start of a year.
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let new_year = UNIX_EPOCH + std::time::Duration::from_secs(1_704_067_199);
+        assert_eq!(format_timestamp(new_year), "2023-12-31T23:59:59.000Z");
+        let new_year = new_year + std::time::Duration::from_secs(1);
+        assert_eq!(format_timestamp(new_year), "2024-01-01T00:00:00.000Z");
+    }
+
+    /// Test that interactive commands are parsed whatever their case, with
+    /// the message of a publish kept whole.
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("help".parse(), Ok(Command::Help));
+        assert_eq!("QUIT".parse(), Ok(Command::Exit));
+        assert_eq!("ping".parse(), Ok(Command::Ping));
+        assert_eq!("recv".parse(), Ok(Command::Receive));
+        assert_eq!(
+            "subscribe news".parse(),
+            Ok(Command::Subscribe("news".to_string()))
+        );
+        assert_eq!(
+            "UNSUBSCRIBE  news ".parse(),
+            Ok(Command::Unsubscribe("news".to_string()))
+        );
+        assert_eq!("subscribe_all".parse(), Ok(Command::SubscribeAll));
+        assert_eq!(
+            "AUTH letmein".parse(),
+            Ok(Command::Authenticate("letmein".to_string()))
+        );
+        assert_eq!(
+            "PUBLISH news hello  world".parse(),
+            Ok(Command::Publish {
+                channel: "news".to_string(),
+                message: "hello  world".to_string()
+            })
+        );
+        assert_eq!("SEND hello".parse(), Ok(Command::Send("hello".to_string())));
+    }
+
+    /// Test that commands missing their arguments are refused with their
+    /// usage, and that other commands are passed on as entered.
+    #[test]
+    fn test_parse_command_errors() {
+        assert_eq!(
+            "SUBSCRIBE".parse::<Command>(),
+            Err("Usage: SUBSCRIBE <channel>".to_string())
+        );
+        assert_eq!(
+            "PUBLISH news".parse::<Command>(),
+            Err("Usage: PUBLISH <channel> <message>".to_string())
+        );
+        assert_eq!(
+            "AUTH ".parse::<Command>(),
+            Err("Usage: AUTH <token>".to_string())
+        );
+        assert_eq!(
+            "COUNT news".parse(),
+            Ok(Command::Raw("COUNT news".to_string()))
+        );
+    }
+
+    /// Test that a message given as a file or as `-` is read as bytes, and
+    /// that other messages are left to be sent as given.
+    #[test]
+    fn test_read_payload() {
+        let path = env::temp_dir().join(format!("payload-{}", std::process::id()));
+        fs::write(&path, [0xff, b'\n', 0x00]).unwrap();
+        let options = Options::from_iter(["client", "--msg-file", path.to_str().unwrap()]);
+        let payload = read_payload(&options, io::empty()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(payload, Some(vec![0xff, b'\n', 0x00]));
+
+        let options = Options::from_iter(["client", "-c", "news", "-m", "-"]);
+        let payload = read_payload(&options, &b"from stdin\n"[..]).unwrap();
+        assert_eq!(payload, Some(b"from stdin\n".to_vec()));
+
+        let options = Options::from_iter(["client", "-c", "news", "-m", "hello"]);
+        assert_eq!(read_payload(&options, io::empty()).unwrap(), None);
+
+        let options = Options::from_iter(["client", "--msg-file", "/nonexistent/payload"]);
+        let error = read_payload(&options, io::empty()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with("/nonexistent/payload: "));
+    }
+
+    /// Test that a message cannot be given both as a file and on the command
+    /// line.
+    #[test]
+    fn test_msg_file_conflicts() {
+        let result = Options::from_iter_safe(["client", "-m", "hello", "--msg-file", "payload"]);
+        assert!(result.is_err());
+    }
+
+    /// Test that a message file counts as the message sent to a channel, and
+    /// that a channel without a message, or no options at all, are caught
+    /// before connecting.
+    #[test]
+    fn test_check_mode() {
+        let mut options =
+            Options::from_iter(["client", "--channel", "news", "--msg-file", "payload"]);
+        assert!(options.check_mode().is_ok());
+        assert!(!options.interactive);
+
+        let mut options = Options::from_iter(["client", "--msg-file", "payload"]);
+        assert!(options.check_mode().is_ok());
+        assert!(!options.interactive);
+
+        let mut options = Options::from_iter(["client", "--channel", "news"]);
+        assert!(options.check_mode().is_err());
+
+        let mut options = Options::from_iter(["client"]);
+        assert!(options.check_mode().is_ok());
+        assert!(options.interactive);
+    }
+
+    /// Test that the built-in defaults are used when neither the command line
+    /// nor the config file gives an option.
+    #[test]
+    fn test_merge_defaults() {
+        let mut options = Options::from_iter(["client", "--listen"]);
+        options.merge(ConfigFile::default());
+        assert_eq!(options.endpoint(), ("localhost".to_string(), 7878));
+        assert_eq!(options.timeout, None);
+    }
+
+    /// Test that a missing file is reported as not found, so that it can be
+    /// skipped, and that an unknown key is an error.
+    #[test]
+    fn test_load_errors() {
+        let path = env::temp_dir().join("test_load_errors-missing.toml");
+        assert_eq!(
+            ConfigFile::load(&path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let path = write_config("test_load_errors", "hots = \"localhost\"\n");
+        let error = ConfigFile::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("hots"));
     }
 }
@@ -2,41 +2,732 @@
 //! This library provides a way for a client to connect and communicate with
 //! the server.
 
+use crate::stream::Stream;
+use crate::{protocol, tls};
+use log::error;
+use serde::Deserialize;
 use std::{
+    collections::{hash_map::RandomState, HashSet, VecDeque},
     error::Error,
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
+    fmt,
+    hash::BuildHasher,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    ops::ControlFlow,
     str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
+/// The priority of a published message. Higher priority messages are
+/// delivered ahead of lower priority messages still waiting to be written to
+/// a subscriber. This is best-effort: messages that have already been written
+/// to a subscriber are never reordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "LOW"),
+            Priority::Normal => write!(f, "NORMAL"),
+            Priority::High => write!(f, "HIGH"),
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "LOW" => Ok(Priority::Low),
+            "NORMAL" => Ok(Priority::Normal),
+            "HIGH" => Ok(Priority::High),
+            _ => Err(format!("Unknown priority: {}", s)),
+        }
+    }
+}
+
+/// The port the server listens on by default.
+pub const DEFAULT_PORT: u16 = 7878;
+
+/// The character separating the channel from the body of a published message
+/// by default. Servers started with `--channel-separator` expect another
+/// character, see `ClientBuilder::channel_separator`.
+pub const CHANNEL_SEPARATOR: char = '\u{1f}';
+
+/// Marks the start of a system message sent by the server.
+const SYSTEM_MESSAGE_MARKER: char = '\u{1}';
+
+/// Marks the start of a message sent directly to this client.
+const DIRECT_MESSAGE_MARKER: char = '\u{2}';
+
+/// Marks the start of a message sent with its sequence number.
+const SEQUENCE_MARKER: char = '\u{3}';
+
+/// Marks the start of a request published on a channel.
+const REQUEST_MARKER: char = '\u{4}';
+
+/// How long `Client::request` waits for a reply when the client has no
+/// timeout set.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `Client::disconnect` waits for the server to confirm the
+/// disconnection.
+pub const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A message received from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A message published on a channel.
+    Regular(String),
+    /// An operational notice, such as a warning that a channel will close.
+    System(String),
+    /// A message sent directly to this client, see `Client::send_to`.
+    Direct {
+        /// The id of the client which sent the message.
+        from: String,
+        /// The message.
+        text: String,
+    },
+    /// A request published on a channel, to be answered with `Client::reply`.
+    Request {
+        /// The correlation id to reply with.
+        id: String,
+        /// The request.
+        text: String,
+    },
+}
+
+impl Message {
+    /// Parses a line received from the server.
+    /// # Arguments
+    /// * `line` - The line received, without the trailing newline.
+    pub fn from_line(line: String) -> Message {
+        if let Some(text) = line.strip_prefix(SYSTEM_MESSAGE_MARKER) {
+            return Message::System(text.to_string());
+        }
+        if let Some(direct) = line.strip_prefix(DIRECT_MESSAGE_MARKER) {
+            let (from, text) = direct.split_once(' ').unwrap_or((direct, ""));
+            return Message::Direct {
+                from: from.to_string(),
+                text: text.to_string(),
+            };
+        }
+        if let Some(request) = line.strip_prefix(REQUEST_MARKER) {
+            let (id, text) = request.split_once(' ').unwrap_or((request, ""));
+            return Message::Request {
+                id: id.to_string(),
+                text: text.to_string(),
+            };
+        }
+        Message::Regular(line)
+    }
+
+    /// The text of the message.
+    pub fn text(&self) -> &String {
+        match self {
+            Message::Regular(text)
+            | Message::System(text)
+            | Message::Direct { text, .. }
+            | Message::Request { text, .. } => text,
+        }
+    }
+}
+
+/// The position of a message among the messages published on its channel,
+/// see `Client::enable_sequence_numbers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequence {
+    /// The channel the message was published on.
+    pub channel: String,
+    /// The number of the message on its channel. Each channel is numbered
+    /// separately, starting from 1.
+    pub number: u64,
+}
+
+/// Splits the sequence number from a message sent with one.
+/// # Arguments
+/// * `line` - The line received, without the trailing newline.
+/// * `separator` - The character separating the channel from the message.
+/// # Returns
+/// The sequence number and the rest of the line, or `None` if the line does
+/// not carry a sequence number.
+fn split_sequence(line: &str, separator: char) -> Option<(Sequence, &str)> {
+    let (number, rest) = line.strip_prefix(SEQUENCE_MARKER)?.split_once(' ')?;
+    let (channel, text) = rest.split_once(separator)?;
+    let sequence = Sequence {
+        channel: channel.to_string(),
+        number: number.parse().ok()?,
+    };
+    Some((sequence, text))
+}
+
+/// An event passed to the callback of `Client::listen_resumable`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenEvent {
+    /// A message received from the server.
+    Message(Message),
+    /// A message received from the server with its sequence number, once
+    /// sequence numbers are enabled.
+    Sequenced {
+        /// The position of the message on its channel.
+        sequence: Sequence,
+        /// The message.
+        message: Message,
+    },
+    /// The connection was lost and has been restored. Messages published
+    /// while the client was disconnected were not delivered.
+    Gap,
+}
+
+impl ListenEvent {
+    /// The message received, if the event is a message.
+    pub fn message(&self) -> Option<&Message> {
+        match self {
+            ListenEvent::Message(message) | ListenEvent::Sequenced { message, .. } => Some(message),
+            ListenEvent::Gap => None,
+        }
+    }
+}
+
+/// What `Client::try_listen` does when its callback returns an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallbackErrorPolicy {
+    /// Stop listening and return the error.
+    #[default]
+    Stop,
+    /// Log the error at `error` level and carry on with the next message.
+    Log,
+}
+
+/// The reason `Client::try_listen` stopped listening.
+#[derive(Debug)]
+pub enum ListenError<E> {
+    /// The connection was lost and could not be restored.
+    Io(io::Error),
+    /// The callback failed to process a message.
+    Callback(E),
+}
+
+impl<E: fmt::Display> fmt::Display for ListenError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenError::Io(e) => write!(f, "Connection lost: {}", e),
+            ListenError::Callback(e) => write!(f, "Failed to process message: {}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for ListenError<E> {}
+
+impl<E> From<io::Error> for ListenError<E> {
+    fn from(e: io::Error) -> Self {
+        ListenError::Io(e)
+    }
+}
+
+/// The reason a request which the server replies to failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The connection to the server failed.
+    Io(io::Error),
+    /// The server did not reply in time.
+    Timeout,
+    /// The server refused the request, with the reason it gave.
+    Rejected(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "Connection failed: {}", e),
+            ClientError::Timeout => write!(f, "The server did not reply in time"),
+            ClientError::Rejected(reason) => write!(f, "The server refused: {}", reason),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+/// The counters of a server, see `Client::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct ServerStats {
+    /// The number of clients which have connected since the server started.
+    pub total_connections: u64,
+    /// The number of clients connected now.
+    pub current_connections: u64,
+    /// The number of channels with at least one subscriber.
+    pub channels: u64,
+    /// The number of messages published since the server started.
+    pub messages_published: u64,
+    /// The total size of the bodies of the messages published.
+    pub bytes_published: u64,
+}
+
+/// The state of a server which passed a health check, see
+/// `Client::health_check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// How long the server took to reply to a ping.
+    pub latency: Duration,
+    /// The version of the server, unless it is too old to report it.
+    pub server_version: Option<String>,
+}
+
+/// The reason a server failed a health check.
+#[derive(Debug)]
+pub enum HealthError {
+    /// Nothing is listening at the address.
+    Refused,
+    /// The server did not accept the connection or reply in time.
+    Timeout,
+    /// The server replied to the ping with something other than a pong, with
+    /// the reply.
+    BadPong(String),
+    /// The connection failed in another way.
+    Io(io::Error),
+}
+
+impl fmt::Display for HealthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthError::Refused => write!(f, "Connection refused"),
+            HealthError::Timeout => write!(f, "The server did not reply in time"),
+            HealthError::BadPong(reply) => write!(f, "Unexpected reply to PING: {}", reply),
+            HealthError::Io(e) => write!(f, "Connection failed: {}", e),
+        }
+    }
+}
+
+impl Error for HealthError {}
+
+impl From<io::Error> for HealthError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => HealthError::Refused,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => HealthError::Timeout,
+            _ => HealthError::Io(e),
+        }
+    }
+}
+
+/// A summary of a client connected to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The server assigned id of the client.
+    pub id: String,
+    /// The name the client has given itself.
+    pub name: Option<String>,
+    /// The remote address of the client.
+    pub addr: String,
+    /// The number of channels the client is subscribed to.
+    pub subscriptions: usize,
+}
+
+impl FromStr for ClientInfo {
+    type Err = String;
+
+    /// Parses a `CLIENT <id> <addr> <subscriptions> [name]` record.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(5, ' ');
+        if parts.next() != Some("CLIENT") {
+            return Err(format!("Not a client record: {}", s));
+        }
+        let mut next_part = || {
+            parts
+                .next()
+                .ok_or_else(|| format!("Incomplete client record: {}", s))
+        };
+        let id = next_part()?.to_string();
+        let addr = next_part()?.to_string();
+        let subscriptions = next_part()?.parse::<usize>().map_err(|e| e.to_string())?;
+        let name = parts.next().map(|name| name.to_string());
+        Ok(ClientInfo {
+            id,
+            name,
+            addr,
+            subscriptions,
+        })
+    }
+}
+
+/// Checks that a channel name is usable. The server rejects the same channel
+/// names.
+/// # Arguments
+/// * `channel` - The channel name to check.
+/// * `separator` - The character separating the channel from the body of a
+///   published message, which channel names cannot contain.
+/// # Returns
+/// The reason the channel name is invalid, if it is.
+pub fn validate_channel(channel: &str, separator: char) -> Result<(), String> {
+    if channel.trim().is_empty() {
+        return Err("channel name cannot be empty".to_string());
+    }
+    if channel.contains(separator) {
+        return Err(format!(
+            "channel name cannot contain the separator {:?}",
+            separator
+        ));
+    }
+    Ok(())
+}
+
+/// Opens a connection to a server, trying each of its addresses in turn.
+/// # Arguments
+/// * `host` - The hostname of the server.
+/// * `port` - The port of the server.
+/// * `timeout` - How long to wait for each address to accept the connection.
+/// # Returns
+/// The connection, or the error from the last address tried.
+fn connect_timeout(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "The host has no addresses");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// How the server writes messages to the client, negotiated when connecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Each message is terminated by a newline. Messages cannot contain
+    /// newlines of their own, and a trailing newline is indistinguishable from
+    /// the terminator.
+    #[default]
+    Text,
+    /// Each message is preceded by its length, see `protocol::read_frame`, so
+    /// messages are received byte for byte as they were published.
+    Binary,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encoding::Text => write!(f, "TEXT"),
+            Encoding::Binary => write!(f, "BINARY"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "TEXT" => Ok(Encoding::Text),
+            "BINARY" => Ok(Encoding::Binary),
+            _ => Err(format!("Unknown encoding: {}", s)),
+        }
+    }
+}
+
+/// Reads a single message written by the server. Whatever the encoding, the
+/// message is appended to the buffer followed by a newline, as it is when
+/// using the text encoding.
+/// # Arguments
+/// * `reader` - The reader to read from.
+/// * `encoding` - The encoding the server writes messages with.
+/// * `buffer` - The buffer to append the message to.
+/// # Returns
+/// The number of bytes read, which is 0 once the server has closed the
+/// connection.
+fn read_line(
+    reader: &mut impl BufRead,
+    encoding: Encoding,
+    buffer: &mut String,
+) -> io::Result<usize> {
+    if encoding == Encoding::Text {
+        return reader.read_line(buffer);
+    }
+
+    let message = match protocol::read_frame(reader) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+        result => result?,
+    };
+    let length = message.len();
+    let message =
+        String::from_utf8(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    buffer.push_str(&message);
+    buffer.push('\n');
+    Ok(protocol::HEADER_LEN + length)
+}
+
+/// Checks whether an error comes from a read or write which timed out, which
+/// depending on the platform is reported as either `WouldBlock` or
+/// `TimedOut`.
+/// # Arguments
+/// * `e` - The error.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Reports a read or write which timed out as `TimedOut`, whichever kind the
+/// platform reported it as. Other errors are passed on unchanged.
+/// # Arguments
+/// * `e` - The error.
+fn timeout_error(e: io::Error) -> io::Error {
+    match is_timeout(&e) {
+        true => io::ErrorKind::TimedOut.into(),
+        false => e,
+    }
+}
+
+/// How the client reconnects after losing its connection to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// The maximum number of attempts to reconnect before giving up.
+    pub max_attempts: u32,
+    /// How long to wait before the first attempt. The wait doubles after each
+    /// failed attempt, up to `max_delay`.
+    pub delay: Duration,
+    /// The longest to wait before an attempt. When this is no longer than
+    /// `delay`, the client waits `delay` before every attempt.
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Gets how long to wait before an attempt to reconnect.
+    /// # Arguments
+    /// * `attempt` - The number of attempts already made.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay.max(self.delay))
+    }
+}
+
+/// Holds a callback so that the types holding it can still be debugged.
+struct Callback<F: ?Sized>(Box<F>);
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// Called when the connection to the server is lost, with the error which
+/// revealed the loss.
+type DisconnectCallback = Callback<dyn FnMut(&io::Error) + Send>;
+
+/// Called once the connection to the server has been restored.
+type ReconnectCallback = Callback<dyn FnMut() + Send>;
+
 /// Represents a client connected to the server.
 /// # Arguments
 /// * `host` - The hostname of the client.
 /// * `port` - The port of the client.
-/// * `connection` - The TCP connection to the client.
+/// * `connection` - The connection to the server, plain or encrypted.
+/// * `reader` - Reads messages from the connection.
+/// * `subscriptions` - The channels subscribed to, restored on reconnect.
+/// * `pattern_subscriptions` - The patterns subscribed to, restored on
+///   reconnect.
+/// * `subscribed_all` - Whether subscribed to every channel.
+/// * `auth_token` - The token authenticated with, restored on reconnect.
+/// * `auto_reconnect` - How to reconnect when the connection is lost while
+///   listening.
+/// * `preferred_encoding` - The encoding to ask the server for on connecting.
+/// * `encoding` - The encoding the server agreed to.
+/// * `session` - The session to resume on reconnect.
+/// * `pings` - The number of pings sent, used to match replies to pings.
+/// * `message_ids` - The number of messages published with an id, used to
+///   give each message a new id.
+/// * `requests` - The number of requests sent, used to give each request a
+///   new correlation id.
+/// * `pending` - Messages which arrived while waiting for a reply, held until
+///   they are received.
+/// * `on_disconnect` - Called when the connection is lost.
+/// * `on_reconnect` - Called when the connection is restored.
+/// * `channel_separator` - Separates the channel from the body of published
+///   messages.
+/// * `sequence_numbers` - Whether messages are received with their sequence
+///   number.
+/// * `check_errors` - Whether commands wait for the server to handle them,
+///   surfacing error replies.
+/// * `timeout` - How long reads from and writes to the connection may block,
+///   restored on reconnect.
+/// * `tls` - The TLS configuration, if the connection is encrypted.
 #[derive(Debug)]
 pub struct Client {
     host: String,
     port: u16,
-    connection: Option<TcpStream>,
+    connection: Option<Box<dyn Stream>>,
+    reader: Option<BufReader<Box<dyn Stream>>>,
+    subscriptions: HashSet<String>,
+    pattern_subscriptions: HashSet<String>,
+    subscribed_all: bool,
+    auth_token: Option<String>,
+    auto_reconnect: Option<ReconnectPolicy>,
+    preferred_encoding: Encoding,
+    encoding: Encoding,
+    session: Option<String>,
+    pings: u64,
+    message_ids: u64,
+    requests: u64,
+    pending: VecDeque<String>,
+    on_disconnect: Option<DisconnectCallback>,
+    on_reconnect: Option<ReconnectCallback>,
+    channel_separator: char,
+    sequence_numbers: bool,
+    check_errors: bool,
+    timeout: Option<Duration>,
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+/// Configures a client before connecting to the server.
+#[derive(Debug)]
+pub struct ClientBuilder {
+    host: String,
+    port: u16,
+    auto_reconnect: Option<ReconnectPolicy>,
+    encoding: Encoding,
+    on_disconnect: Option<DisconnectCallback>,
+    on_reconnect: Option<ReconnectCallback>,
+    channel_separator: char,
+    check_errors: bool,
+    timeout: Option<Duration>,
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl ClientBuilder {
+    /// Creates a builder for a client of a server.
+    /// # Arguments
+    /// * `host` - The hostname of the server.
+    /// * `port` - The port of the server.
+    pub fn new(host: String, port: u16) -> ClientBuilder {
+        ClientBuilder {
+            host,
+            port,
+            auto_reconnect: None,
+            encoding: Encoding::default(),
+            on_disconnect: None,
+            on_reconnect: None,
+            channel_separator: CHANNEL_SEPARATOR,
+            check_errors: false,
+            timeout: None,
+            tls: None,
+        }
+    }
+
+    /// Sets the character separating the channel from the body of published
+    /// messages, which must match the server's `--channel-separator`.
+    /// # Arguments
+    /// * `separator` - The separator.
+    pub fn channel_separator(mut self, separator: char) -> ClientBuilder {
+        self.channel_separator = separator;
+        self
+    }
+
+    /// Sets how long reads and writes may block, see `Client::set_timeout`.
+    /// # Arguments
+    /// * `timeout` - The longest a read or write may block.
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Connects to the server over TLS, as required by servers started with
+    /// `--tls-cert`. Reconnecting uses TLS too.
+    /// # Arguments
+    /// * `config` - The TLS configuration, see `tls::client_config`.
+    pub fn tls(mut self, config: Arc<rustls::ClientConfig>) -> ClientBuilder {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Sets whether commands wait for the server to handle them, see
+    /// `Client::set_check_errors`.
+    /// # Arguments
+    /// * `check_errors` - Whether to wait.
+    pub fn check_errors(mut self, check_errors: bool) -> ClientBuilder {
+        self.check_errors = check_errors;
+        self
+    }
+
+    /// Sets the encoding to ask the server to write messages with. The server
+    /// may downgrade the binary encoding to text, see `Client::encoding`.
+    /// # Arguments
+    /// * `encoding` - The preferred encoding.
+    pub fn encoding(mut self, encoding: Encoding) -> ClientBuilder {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets how the client reconnects when the connection is lost while
+    /// listening.
+    /// # Arguments
+    /// * `policy` - How to reconnect.
+    pub fn auto_reconnect(mut self, policy: ReconnectPolicy) -> ClientBuilder {
+        self.auto_reconnect = Some(policy);
+        self
+    }
+
+    /// Sets the callback run when the connection is lost, see
+    /// `Client::on_disconnect`.
+    /// # Arguments
+    /// * `callback` - The callback.
+    pub fn on_disconnect(
+        mut self,
+        callback: impl FnMut(&io::Error) + Send + 'static,
+    ) -> ClientBuilder {
+        self.on_disconnect = Some(Callback(Box::new(callback)));
+        self
+    }
+
+    /// Sets the callback run when the connection is restored, see
+    /// `Client::on_reconnect`.
+    /// # Arguments
+    /// * `callback` - The callback.
+    pub fn on_reconnect(mut self, callback: impl FnMut() + Send + 'static) -> ClientBuilder {
+        self.on_reconnect = Some(Callback(Box::new(callback)));
+        self
+    }
+
+    /// Connects to the server and negotiates the encoding.
+    /// # Returns
+    /// The connected client.
+    pub fn build(self) -> io::Result<Client> {
+        let mut client = Client::disconnected(self.host, self.port);
+        client.auto_reconnect = self.auto_reconnect;
+        client.preferred_encoding = self.encoding;
+        client.on_disconnect = self.on_disconnect;
+        client.on_reconnect = self.on_reconnect;
+        client.channel_separator = self.channel_separator;
+        client.check_errors = self.check_errors;
+        client.timeout = self.timeout;
+        client.tls = self.tls;
+        client.reconnect()?;
+        Ok(client)
+    }
 }
 
 impl FromStr for Client {
     type Err = String;
 
+    /// Parses an address into a client which is not yet connected, see
+    /// `connect`. The address is parsed by `Client::parse_endpoint`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split(":");
-        let host = parts.next().unwrap().to_string();
-        let port = parts
-            .next()
-            .ok_or_else(|| "Could not parse host:port".to_string())?
-            .parse::<u16>()
-            .map_err(|e| e.to_string())?;
-        Ok(Client {
-            host,
-            port,
-            connection: None,
-        })
+        let (host, port) = Client::parse_endpoint(s)?;
+        Ok(Client::disconnected(host, port))
     }
 }
 
@@ -47,136 +738,2840 @@ impl Client {
     /// * `port` - The port of the client.
     /// # Returns
     /// A new client instance.
+    /// # Panics
+    /// If the server cannot be reached, see `try_new`.
     pub fn new(host: String, port: u16) -> Client {
-        let mut client = Client {
+        let mut client = Client::disconnected(host, port);
+        client.connect().expect("Failed to connect to server.");
+        client
+    }
+
+    /// Parses the address of a server. The address is a host, optionally
+    /// followed by a port as `host:port`. IPv6 addresses with a port are
+    /// bracketed, as in `[::1]:7878`. The default port is used when no port
+    /// is given.
+    /// # Arguments
+    /// * `s` - The address to parse.
+    /// # Returns
+    /// The host and port of the server.
+    pub fn parse_endpoint(s: &str) -> Result<(String, u16), String> {
+        let (host, port) = Client::split_endpoint(s)?;
+        Ok((host, port.unwrap_or(DEFAULT_PORT)))
+    }
+
+    /// Parses the address of a server as `parse_endpoint` does, but without
+    /// filling in the default port.
+    /// # Arguments
+    /// * `s` - The address to parse.
+    /// # Returns
+    /// The host of the server, and its port if the address has one.
+    pub(crate) fn split_endpoint(s: &str) -> Result<(String, Option<u16>), String> {
+        let s = s.trim();
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .ok_or_else(|| format!("Missing ] in address: {}", s))?;
+                match rest {
+                    "" => (host, None),
+                    _ => match rest.strip_prefix(':') {
+                        Some(port) => (host, Some(port)),
+                        None => return Err(format!("Could not parse address: {}", s)),
+                    },
+                }
+            }
+            None => match s.split_once(':') {
+                // An IPv6 address without brackets has no port.
+                Some((_, port)) if port.contains(':') => (s, None),
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            },
+        };
+
+        if host.is_empty() {
+            return Err(format!("Missing host in address: {}", s));
+        }
+        let port = port
+            .map(|port| {
+                port.parse::<u16>()
+                    .map_err(|e| format!("Invalid port {}: {}", port, e))
+            })
+            .transpose()?;
+        Ok((host.to_string(), port))
+    }
+
+    /// Creates a new client instance without connecting to the server.
+    fn disconnected(host: String, port: u16) -> Client {
+        Client {
             host,
             port,
             connection: None,
-        };
-        client.connect();
-        client
+            reader: None,
+            subscriptions: HashSet::new(),
+            pattern_subscriptions: HashSet::new(),
+            subscribed_all: false,
+            auth_token: None,
+            auto_reconnect: None,
+            preferred_encoding: Encoding::default(),
+            encoding: Encoding::default(),
+            session: None,
+            pings: 0,
+            message_ids: 0,
+            requests: 0,
+            pending: VecDeque::new(),
+            on_disconnect: None,
+            on_reconnect: None,
+            channel_separator: CHANNEL_SEPARATOR,
+            sequence_numbers: false,
+            check_errors: false,
+            timeout: None,
+            tls: None,
+        }
+    }
+
+    /// Creates a new client instance and connects to the server, returning
+    /// the connection error rather than panicking when the server cannot be
+    /// reached.
+    /// # Arguments
+    /// * `host` - The hostname of the client.
+    /// * `port` - The port of the client.
+    /// # Returns
+    /// A new client instance.
+    pub fn try_new(host: String, port: u16) -> io::Result<Client> {
+        let mut client = Client::disconnected(host, port);
+        client.connect()?;
+        Ok(client)
     }
 
     /// Connects to the server.
-    pub fn connect(&mut self) {
-        let stream = TcpStream::connect(format!("{}:{}", self.host, self.port).as_str()).unwrap();
+    /// # Returns
+    /// The connection error when the server cannot be reached.
+    pub fn connect(&mut self) -> io::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        self.set_connection(stream)?;
+        Ok(())
+    }
+
+    /// Connects to the server, returning the connection error rather than
+    /// panicking when the server cannot be reached.
+    #[deprecated(note = "use `connect`, which now returns the connection error")]
+    pub fn try_connect(&mut self) -> io::Result<()> {
+        self.connect()
+    }
+
+    /// Replaces the connection to the server, along with the reader messages
+    /// are read through. The connection is encrypted first when the client
+    /// uses TLS.
+    /// # Arguments
+    /// * `stream` - The new connection.
+    fn set_connection(&mut self, stream: TcpStream) -> io::Result<()> {
+        // Shared by the reader, which reads from a clone of the stream, and
+        // bounds the handshake when the connection is encrypted.
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        let stream: Box<dyn Stream> = match &self.tls {
+            Some(config) => Box::new(tls::connect(config.clone(), &self.host, stream)?),
+            None => Box::new(stream),
+        };
+        self.reader = Some(BufReader::new(stream.try_clone()?));
         self.connection = Some(stream);
-        if self.connection.is_none() {
-            panic!("Failed to connect to server.");
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Reads a single message from the server. Every message is read through
+    /// the same reader so that bytes read ahead of one message are kept for
+    /// the next, rather than lost with a short-lived reader.
+    /// Heartbeats sent by the server are answered with `PONG` and skipped, so
+    /// they are never returned.
+    /// # Arguments
+    /// * `buffer` - The buffer to append the message to, followed by a
+    ///   newline.
+    /// # Returns
+    /// The number of bytes read, which is 0 once the server has closed the
+    /// connection.
+    fn read_message(&mut self, buffer: &mut String) -> io::Result<usize> {
+        let encoding = self.encoding;
+        loop {
+            let reader = self.reader.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+            let read = read_line(reader, encoding, buffer)?;
+            if buffer != "PING\n" {
+                return Ok(read);
+            }
+            buffer.clear();
+            self.write_frame(&protocol::encode_frame(b"PONG"))?;
         }
     }
 
-    /// Disconnects from the server.
-    pub fn disconnect(&mut self) {
-        self.send(format!("DISCONNECT").as_str().to_string());
+    /// Reconnects to the server, restoring the authentication and
+    /// subscriptions of the previous connection. When a session has been
+    /// started, see `start_session`, the subscriptions are restored by
+    /// resuming the session, falling back to subscribing again if the session
+    /// has expired.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        self.set_connection(stream)?;
+        self.encoding = Encoding::Text;
+
+        // Servers requiring authentication refuse anything else until then.
+        if let Some(token) = self.auth_token.clone() {
+            self.authenticate(token)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        if self.preferred_encoding != Encoding::Text {
+            self.negotiate_encoding(self.preferred_encoding)?;
+        }
+        if self.sequence_numbers {
+            self.enable_sequence_numbers()?;
+        }
+        if let Some(session_id) = self.session.take() {
+            if self.resume_session(&session_id)? {
+                return Ok(());
+            }
+            // The session has expired, so a new one is started for the next
+            // reconnect. Sessions are best-effort, so failing to start one
+            // does not fail the reconnect.
+            let _ = self.start_session();
+        }
+        if self.subscribed_all {
+            self.subscribe_all()?;
+        }
+        for channel in self.subscriptions.clone() {
+            self.subscribe(channel)?;
+        }
+        for pattern in self.pattern_subscriptions.clone() {
+            self.psubscribe(pattern)?;
+        }
+        Ok(())
     }
 
-    /// Pings the server
-    pub fn ping(&mut self) -> Result<(), Box<dyn Error>> {
-        self.send(format!("PING").as_str().to_string());
+    /// Starts a session, so that the subscriptions of this connection are kept
+    /// by the server for a grace period after the connection is lost. They
+    /// are then restored on reconnect without subscribing again. Messages
+    /// published while disconnected are not delivered.
+    /// # Returns
+    /// The id of the session, or an error if the server does not allow
+    /// sessions.
+    pub fn start_session(&mut self) -> io::Result<String> {
+        self.send("SESSION".to_string())?;
         let mut buffer = String::new();
-        let mut reader = BufReader::new(self.connection.as_mut().unwrap());
-        match reader.read_line(&mut buffer) {
-            Ok(_) => {
-                if buffer.trim() == "PONG" {
-                    Ok(())
-                } else {
-                    Err("Got a back response back from the server".into())
-                }
+        self.read_message(&mut buffer)?;
+        match buffer.trim().strip_prefix("SESSION ") {
+            Some(session_id) => {
+                self.session = Some(session_id.to_string());
+                Ok(session_id.to_string())
             }
-            Err(e) => Err(Box::new(e)),
+            None => Err(io::Error::other(format!(
+                "Failed to start a session: {}",
+                buffer.trim()
+            ))),
         }
     }
 
-    /// Sends a message to the server.
+    /// Resumes a session started by an earlier connection, restoring its
+    /// subscriptions to this connection.
     /// # Arguments
-    /// * `message` - The message to send.
+    /// * `session_id` - The id of the session.
     /// # Returns
-    /// * `bool` - Whether the message was sent successfully.
-    pub fn send(&mut self, message: String) -> bool {
-        let connection = self.connection.as_mut().unwrap();
-
-        // The server expects an initial message with the length of the message.
-        let message_length = message.len().to_string();
-        let mut buffer = [0; 64];
-
-        // Add the message to the buffer and pad it with spaces.
-        buffer[0..message_length.len()].copy_from_slice(message_length.as_bytes());
-        buffer[message_length.len()..]
-            .copy_from_slice(" ".repeat(64 - message_length.len()).as_bytes());
-
-        // Send the message length.
-        connection.write(&buffer).unwrap();
+    /// True if the session was resumed, false if the session has expired or
+    /// the server does not allow sessions.
+    pub fn resume_session(&mut self, session_id: &str) -> io::Result<bool> {
+        self.send(format!("RESUME {}", session_id))?;
+        let mut buffer = String::new();
+        self.read_message(&mut buffer)?;
+        if buffer.trim() != format!("RESUMED {}", session_id) {
+            return Ok(false);
+        }
+        self.session = Some(session_id.to_string());
+        Ok(true)
+    }
 
-        // Send the message.
-        connection.write(message.as_bytes()).is_ok()
+    /// Gets the id of the session resumed on reconnect, if any.
+    pub fn session_id(&self) -> Option<&str> {
+        self.session.as_deref()
     }
 
-    /// Receives a message from the server.
+    /// Asks the server to write messages with an encoding. The server replies
+    /// using the previous encoding, then switches.
     /// # Arguments
-    /// * `callback` - The callback to call when a message is received.
+    /// * `encoding` - The encoding to ask for.
     /// # Returns
-    /// * `String` - The message received from the server.
-    pub fn receive(&mut self, callback: fn(&String)) {
+    /// The encoding the server agreed to, which is text if the server does
+    /// not allow the binary encoding.
+    pub fn negotiate_encoding(&mut self, encoding: Encoding) -> io::Result<Encoding> {
+        self.send(format!("ENCODING {}", encoding))?;
         let mut buffer = String::new();
-        let conn = self.connection.as_mut().unwrap();
-        let mut reader = BufReader::new(conn);
-        reader.read_line(&mut buffer).unwrap();
-        buffer.pop();
-        callback(&buffer);
-        buffer.clear();
+        self.read_message(&mut buffer)?;
+        self.encoding = match buffer.trim().split_once(' ') {
+            Some(("ENCODING", encoding)) => encoding.parse().map_err(io::Error::other)?,
+            _ => {
+                return Err(io::Error::other(format!(
+                    "Unexpected reply to ENCODING: {}",
+                    buffer.trim()
+                )))
+            }
+        };
+        Ok(self.encoding)
     }
 
-    /// Subscribes to a channel.
-    /// # Arguments
-    /// * `channel` - The channel to subscribe to.
+    /// Asks the server to send every message published on a channel with its
+    /// sequence number on that channel, so that missed messages can be
+    /// detected, see `listen_sequenced`. Sequence numbers are asked for again
+    /// whenever the client reconnects.
     /// # Returns
-    /// * `bool` - Whether the subscription was successful.
-    pub fn subscribe(&mut self, channel: String) -> bool {
-        self.send(format!("SUBSCRIBE {}", channel).as_str().to_string())
+    /// An error if the server did not agree.
+    pub fn enable_sequence_numbers(&mut self) -> io::Result<()> {
+        self.send("SEQUENCE".to_string())?;
+        self.read_reply(|line| line == "SEQUENCE ON")?;
+        self.sequence_numbers = true;
+        Ok(())
     }
 
-    /// Unsubscribes from a channel.
+    /// Parses a line received from the server into an event, splitting off
+    /// the sequence number when sequence numbers are enabled.
     /// # Arguments
-    /// * `channel` - The channel to unsubscribe from.
+    /// * `line` - The line received, without the trailing newline.
+    fn parse_event(&self, line: String) -> ListenEvent {
+        if self.sequence_numbers {
+            if let Some((sequence, text)) = split_sequence(&line, self.channel_separator) {
+                return ListenEvent::Sequenced {
+                    sequence,
+                    message: Message::from_line(text.to_string()),
+                };
+            }
+        }
+        ListenEvent::Message(Message::from_line(line))
+    }
+
+    /// Gets the encoding the server writes messages to this client with.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Sets the character separating the channel from the body of published
+    /// messages, see `ClientBuilder::channel_separator`.
+    /// # Arguments
+    /// * `separator` - The separator.
+    pub fn set_channel_separator(&mut self, separator: char) {
+        self.channel_separator = separator;
+    }
+
+    /// Sets how long reads from and writes to the connection may block, so
+    /// that a server which has stopped responding cannot hang the client.
+    /// Commands waiting for a reply, such as `ping`, and `receive` then fail
+    /// with a `TimedOut` error once the timeout passes. Listening carries on
+    /// waiting after a timeout, until the connection is lost. The timeout is
+    /// kept when reconnecting.
+    ///
+    /// With the binary encoding, a message which times out part way through
+    /// arriving is lost and the messages after it cannot be read, so the
+    /// timeout should be well above the time a message takes to arrive.
+    /// # Arguments
+    /// * `timeout` - The longest a read or write may block, or `None` to block
+    ///   for as long as it takes.
+    /// # Returns
+    /// An error if the timeout is zero.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if let Some(connection) = self.connection.as_ref() {
+            connection.socket().set_read_timeout(timeout)?;
+            connection.socket().set_write_timeout(timeout)?;
+        }
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Sets whether commands which the server does not otherwise reply to,
+    /// such as `publish` and `subscribe`, wait for the server to handle them.
+    /// When waiting, an error replied by the server, such as for an
+    /// unauthorized channel, is returned as an error wrapping
+    /// `ClientError::Rejected`. Each such command then takes a round trip to
+    /// the server, so this is off by default.
+    /// # Arguments
+    /// * `check_errors` - Whether to wait.
+    pub fn set_check_errors(&mut self, check_errors: bool) {
+        self.check_errors = check_errors;
+    }
+
+    /// Sets how the client reconnects when the connection is lost while
+    /// listening. `None` disables reconnecting.
+    /// # Arguments
+    /// * `policy` - How to reconnect.
+    pub fn set_auto_reconnect(&mut self, policy: Option<ReconnectPolicy>) {
+        self.auto_reconnect = policy;
+    }
+
+    /// Sets a callback run when the connection to the server is found to be
+    /// lost while listening, whether or not the client then reconnects.
+    /// # Arguments
+    /// * `callback` - The callback, which is passed the error that revealed
+    ///   the loss.
+    /// # Remarks
+    /// The callback runs on the thread using the client, so listening waits
+    /// for it to return.
+    pub fn on_disconnect(&mut self, callback: impl FnMut(&io::Error) + Send + 'static) {
+        self.on_disconnect = Some(Callback(Box::new(callback)));
+    }
+
+    /// Sets a callback run when the connection to the server has been
+    /// restored by auto-reconnect, see `set_auto_reconnect`.
+    /// # Arguments
+    /// * `callback` - The callback.
+    /// # Remarks
+    /// The callback runs on the thread using the client, so listening waits
+    /// for it to return.
+    pub fn on_reconnect(&mut self, callback: impl FnMut() + Send + 'static) {
+        self.on_reconnect = Some(Callback(Box::new(callback)));
+    }
+
+    /// Reconnects to the server, retrying according to the policy. The
+    /// reconnect callback is run once reconnected, see `on_reconnect`.
+    /// # Arguments
+    /// * `policy` - How to reconnect.
+    /// # Returns
+    /// The error from the last attempt if every attempt failed.
+    pub(crate) fn reconnect_with_policy(&mut self, policy: ReconnectPolicy) -> io::Result<()> {
+        let mut last_error = io::Error::other("No attempts were made to reconnect");
+        for attempt in 0..policy.max_attempts {
+            thread::sleep(policy.backoff(attempt));
+            match self.reconnect() {
+                Ok(()) => {
+                    if let Some(Callback(callback)) = self.on_reconnect.as_mut() {
+                        callback();
+                    }
+                    return Ok(());
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Gets how the client reconnects when the connection is lost.
+    pub fn auto_reconnect(&self) -> Option<ReconnectPolicy> {
+        self.auto_reconnect
+    }
+
+    /// Disconnects from the server, waiting up to `DISCONNECT_TIMEOUT` for
+    /// the server to reply `BYE`, which it does once it has forgotten the
+    /// client. Once this returns, the client is no longer subscribed to
+    /// anything nor listed by the server. Messages arriving before the reply
+    /// are kept and passed on by the next call receiving messages.
+    /// # Returns
+    /// An error if the disconnection could not be sent, or a `TimedOut` error
+    /// if the server did not reply in time. The server closing the connection
+    /// without replying, as servers which predate `BYE` do, is not an error.
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.disconnect_nowait()?;
+        match self.await_reply(|line| line == "BYE", DISCONNECT_TIMEOUT) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(io::ErrorKind::TimedOut.into()),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Disconnects from the server without waiting for the server to reply,
+    /// see `disconnect`.
+    /// # Returns
+    /// An error if the disconnection could not be sent.
+    pub fn disconnect_nowait(&mut self) -> io::Result<()> {
+        self.send("DISCONNECT".to_string())
+    }
+
+    /// Pings the server. Each ping carries a token which the server echoes
+    /// back, so channel messages arriving before the reply are not mistaken
+    /// for it. Those messages are kept and passed on by the next call
+    /// receiving messages.
+    pub fn ping(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pings += 1;
+        let pong = format!("PONG {}", self.pings);
+        self.send(format!("PING {}", self.pings))?;
+
+        self.read_reply(|line| line == pong)?;
+        Ok(())
+    }
+
+    /// Sends a command the server does not otherwise reply to. When checking
+    /// for errors, see `set_check_errors`, waits for the server to handle the
+    /// command.
+    /// # Arguments
+    /// * `message` - The command to send.
+    /// # Returns
+    /// An error if the command could not be sent, or was refused by the
+    /// server.
+    fn send_command(&mut self, message: impl AsRef<[u8]>) -> io::Result<()> {
+        self.send_bytes(message.as_ref())?;
+        self.check_sent()
+    }
+
+    /// When checking for errors, see `set_check_errors`, waits for the server
+    /// to handle the commands sent so far.
+    /// # Returns
+    /// An error if the server refused one of the commands, with the first
+    /// refusal.
+    fn check_sent(&mut self) -> io::Result<()> {
+        if !self.check_errors {
+            return Ok(());
+        }
+        // The server handles commands in order, so an error reply to the
+        // command arrives before the reply to the ping.
+        self.pings += 1;
+        let pong = format!("PONG {}", self.pings);
+        self.send(format!("PING {}", self.pings))?;
+        let reply =
+            self.read_reply(|line| line == pong || line.starts_with(protocol::ERROR_PREFIX))?;
+        if reply == pong {
+            return Ok(());
+        }
+        self.read_reply(|line| line == pong)?;
+        Err(io::Error::other(ClientError::Rejected(reply)))
+    }
+
+    /// Reads messages from the server until a reply arrives, keeping the
+    /// messages which arrive first.
+    /// # Arguments
+    /// * `is_reply` - Whether a message is the reply.
+    /// # Returns
+    /// The reply without its newline.
+    fn read_reply(&mut self, is_reply: impl Fn(&str) -> bool) -> io::Result<String> {
+        loop {
+            let mut buffer = String::new();
+            if self.read_message(&mut buffer).map_err(timeout_error)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buffer.pop();
+            if is_reply(&buffer) {
+                return Ok(buffer);
+            }
+            self.pending.push_back(buffer);
+        }
+    }
+
+    /// Checks that a server is up and responding, for liveness probes. A new
+    /// connection is opened, asks for the server's version, pings the server
+    /// and is closed again.
+    /// # Arguments
+    /// * `host` - The hostname of the server.
+    /// * `port` - The port of the server.
+    /// * `timeout` - How long to wait for the connection, and then for the
+    ///   replies.
+    /// # Returns
+    /// The state of the server, or why it failed the check.
+    pub fn health_check(
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<HealthReport, HealthError> {
+        let stream = connect_timeout(host, port, timeout)?;
+        // Small writes would otherwise be held back, inflating the latency.
+        stream.set_nodelay(true)?;
+        let mut client = Client::disconnected(host.to_string(), port);
+        client.set_connection(stream)?;
+
+        // Servers which predate `VERSION` ignore it, so the ping is sent
+        // without waiting for the version.
+        let started = Instant::now();
+        client.send("VERSION".to_string())?;
+        client.send("PING health".to_string())?;
+        let pong = client
+            .await_reply(|line| !line.starts_with("VERSION "), timeout)?
+            .ok_or(HealthError::Timeout)?;
+        let latency = started.elapsed();
+        if pong != "PONG health" {
+            return Err(HealthError::BadPong(pong));
+        }
+        let server_version = client
+            .pending
+            .iter()
+            .find_map(|line| line.strip_prefix("VERSION "))
+            .map(str::to_string);
+        // The server is healthy whether or not it sees the disconnect.
+        let _ = client.disconnect_nowait();
+        Ok(HealthReport {
+            latency,
+            server_version,
+        })
+    }
+
+    /// Authenticates with the server. Servers started with auth tokens refuse
+    /// to subscribe or publish for clients which have not authenticated.
+    /// Providing the server's admin token grants this connection admin
+    /// rights. The token is authenticated with again on reconnect.
+    /// # Arguments
+    /// * `token` - The token to authenticate with.
+    pub fn authenticate(&mut self, token: String) -> Result<(), Box<dyn Error>> {
+        self.send(format!("AUTH {}", token))?;
+        let mut buffer = String::new();
+        match self.read_message(&mut buffer) {
+            Ok(_) => {
+                if buffer.trim() == "OK" {
+                    self.auth_token = Some(token);
+                    Ok(())
+                } else {
+                    Err(format!("Failed to authenticate: {}", buffer.trim()).into())
+                }
+            }
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Shuts down the server once it has delivered the messages queued for
+    /// subscribers. Requires admin rights, see `authenticate`.
+    pub fn shutdown_server(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send("SHUTDOWN".to_string())?;
+        let mut buffer = String::new();
+        self.read_message(&mut buffer)?;
+        match buffer.trim() {
+            "OK" => Ok(()),
+            reply => Err(format!("Failed to shut down the server: {}", reply).into()),
+        }
+    }
+
+    /// Sets the name this client is listed under.
+    /// # Arguments
+    /// * `name` - The name of the client.
+    /// # Returns
+    /// An error if the name could not be sent, or was refused by the server.
+    pub fn set_name(&mut self, name: String) -> io::Result<()> {
+        self.send_command(format!("NAME {}", name))
+    }
+
+    /// Lists every client connected to the server. Requires admin rights, see
+    /// `authenticate`.
+    /// # Returns
+    /// A summary of each connected client.
+    pub fn list_clients(&mut self) -> io::Result<Vec<ClientInfo>> {
+        self.send("CLIENTS".to_string())?;
+
+        let mut clients = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.read_message(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            if line == "END" {
+                return Ok(clients);
+            }
+            clients.push(line.parse::<ClientInfo>().map_err(io::Error::other)?);
+        }
+    }
+
+    /// Lists every channel with at least one subscriber on the server.
+    /// Messages arriving before the list are kept and passed on by the next
+    /// call receiving messages.
+    /// # Returns
+    /// The channel names, sorted.
+    pub fn list_channels(&mut self) -> io::Result<Vec<String>> {
+        self.send("LIST".to_string())?;
+
+        let mut channels = Vec::new();
+        loop {
+            let line = self.read_reply(|line| line == "END" || line.starts_with("CHANNEL "))?;
+            match line.strip_prefix("CHANNEL ") {
+                Some(channel) => channels.push(channel.to_string()),
+                None => return Ok(channels),
+            }
+        }
+    }
+
+    /// Asks the server for the id it knows this connection by, as listed by
+    /// `list_clients` and used by `send_to`. Messages arriving before the
+    /// reply are kept and passed on by the next call receiving messages.
+    /// # Returns
+    /// The id, which stays the same for as long as the connection is open.
+    pub fn whoami(&mut self) -> io::Result<String> {
+        self.send("WHOAMI".to_string())?;
+        let reply = self.read_reply(|line| line.starts_with("WHOAMI "))?;
+        Ok(reply["WHOAMI ".len()..].to_string())
+    }
+
+    /// Counts the clients subscribed to a channel, without subscribing to it.
+    /// Messages arriving before the count are kept and passed on by the next
+    /// call receiving messages.
+    /// # Arguments
+    /// * `channel` - The channel to count the subscribers of.
+    /// # Returns
+    /// The number of subscribers, which is 0 for a channel nobody has
+    /// subscribed to.
+    pub fn count(&mut self, channel: String) -> io::Result<usize> {
+        self.send(format!("COUNT {}", channel))?;
+        let prefix = format!("COUNT {} ", channel);
+        let reply = self.read_reply(|line| line.starts_with(&prefix))?;
+        reply[prefix.len()..]
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads the message retained on a channel, see `publish_retained`,
+    /// without subscribing to it. Messages arriving before the reply are kept
+    /// and passed on by the next call receiving messages.
+    /// # Arguments
+    /// * `channel` - The channel to read the retained message of.
+    /// # Returns
+    /// The retained message, or `None` when nothing is retained on the
+    /// channel. Messages are read as text, so one which is not UTF-8 is an
+    /// `InvalidData` error.
+    pub fn get_retained(&mut self, channel: String) -> io::Result<Option<Vec<u8>>> {
+        self.send(format!("GET {}", channel))?;
+        let prefix = format!("RETAINED {}", channel);
+        let separator = self.channel_separator;
+        let reply = self.read_reply(|line| {
+            line.strip_prefix(&prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(separator))
+        })?;
+        Ok(reply[prefix.len()..]
+            .strip_prefix(separator)
+            .map(|message| message.as_bytes().to_vec()))
+    }
+
+    /// Asks the server for its counters, such as how many clients are
+    /// connected and how many messages have been published, for monitoring.
+    /// Messages arriving before the reply are kept and passed on by the next
+    /// call receiving messages.
+    /// # Returns
+    /// The counters, or an `InvalidData` error if the reply could not be
+    /// parsed.
+    pub fn stats(&mut self) -> io::Result<ServerStats> {
+        self.send("STATS".to_string())?;
+        let reply = self.read_reply(|line| line.starts_with("STATS "))?;
+        serde_json::from_str(&reply["STATS ".len()..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Sends a message to the server. When auto-reconnect is enabled, see
+    /// `set_auto_reconnect`, a message which cannot be written is sent again
+    /// once the connection and its subscriptions have been restored.
+    /// # Arguments
+    /// * `message` - The message to send.
+    /// # Returns
+    /// An error if the client is not connected or the message could not be
+    /// written, such as when the server has closed the connection, and the
+    /// connection could not be restored.
+    /// # Remarks
+    /// A write to a connection the server has closed may still succeed, so
+    /// the loss is often only found by the next message sent.
+    pub fn send(&mut self, message: String) -> io::Result<()> {
+        self.send_bytes(message.as_bytes())
+    }
+
+    /// Sends a message which may not be UTF-8 to the server, see `send`.
+    /// # Arguments
+    /// * `message` - The message to send.
+    fn send_bytes(&mut self, message: &[u8]) -> io::Result<()> {
+        // The server expects the message to be preceded by its length.
+        let frame = protocol::encode_frame(message);
+        let error = match self.write_frame(&frame) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        // The policy is put aside while reconnecting, so that the commands
+        // restoring the subscriptions are not retried by reconnecting again.
+        let Some(policy) = self.auto_reconnect.take() else {
+            return Err(error);
+        };
+        if let Some(Callback(callback)) = self.on_disconnect.as_mut() {
+            callback(&error);
+        }
+        let result = self
+            .reconnect_with_policy(policy)
+            .and_then(|_| self.write_frame(&frame));
+        self.auto_reconnect = Some(policy);
+        result
+    }
+
+    /// Writes a framed message to the connection.
+    /// # Arguments
+    /// * `frame` - The framed message.
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?
+            .write_all(frame)
+    }
+
+    /// Sends a message to the server, discarding why it failed.
+    /// # Arguments
+    /// * `message` - The message to send.
+    /// # Returns
+    /// * `bool` - Whether the message was sent successfully.
+    #[deprecated(note = "use `send`, which reports why the message was not sent")]
+    pub fn send_lossy(&mut self, message: String) -> bool {
+        self.send(message).is_ok()
+    }
+
+    /// Receives a message from the server.
+    /// # Arguments
+    /// * `callback` - The callback to call when a message is received.
+    /// # Returns
+    /// An error if the connection has been lost, or a `TimedOut` error if no
+    /// message arrived within the timeout, see `set_timeout`.
+    /// # Remarks
+    /// System messages are passed to the callback like any other message, use
+    /// `receive_message` to tell them apart.
+    pub fn receive(&mut self, mut callback: impl FnMut(&String)) -> io::Result<()> {
+        self.receive_with(|message| callback(message.text()))
+    }
+
+    /// Receives a message from the server.
+    /// # Arguments
+    /// * `callback` - The callback to call when a message is received.
+    /// # Returns
+    /// An error if the connection has been lost, or a `TimedOut` error if no
+    /// message arrived within the timeout, see `set_timeout`.
+    pub fn receive_message(&mut self, callback: impl FnMut(&Message)) -> io::Result<()> {
+        self.receive_with(callback)
+    }
+
+    /// Receives a message from the server with its sequence number, see
+    /// `enable_sequence_numbers`.
+    /// # Arguments
+    /// * `callback` - The callback to call when a message is received, with
+    ///   its sequence number if it has one. Direct messages have none.
+    /// # Returns
+    /// An error if the connection has been lost, or a `TimedOut` error if no
+    /// message arrived within the timeout, see `set_timeout`.
+    pub fn receive_sequenced(
+        &mut self,
+        mut callback: impl FnMut(Option<&Sequence>, &Message),
+    ) -> io::Result<()> {
+        self.receive_event(|event| match event {
+            ListenEvent::Message(message) => callback(None, message),
+            ListenEvent::Sequenced { sequence, message } => callback(Some(sequence), message),
+            ListenEvent::Gap => {}
+        })
+    }
+
+    /// Receives a single message from the server and passes it to a callback.
+    fn receive_with(&mut self, mut callback: impl FnMut(&Message)) -> io::Result<()> {
+        self.receive_event(|event| {
+            if let Some(message) = event.message() {
+                callback(message);
+            }
+        })
+    }
+
+    /// Receives a single message from the server and passes it to a callback
+    /// as an event.
+    fn receive_event(&mut self, callback: impl FnOnce(&ListenEvent)) -> io::Result<()> {
+        let line = match self.pending.pop_front() {
+            Some(line) => line,
+            None => {
+                let mut buffer = String::new();
+                if self.read_message(&mut buffer).map_err(timeout_error)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                buffer.pop();
+                buffer
+            }
+        };
+        callback(&self.parse_event(line));
+        Ok(())
+    }
+
+    /// Receives a single line from the server, waiting at most `timeout` for
+    /// it to arrive.
+    /// # Arguments
+    /// * `timeout` - How long to wait for the line.
+    /// # Returns
+    /// The line without its newline, or `None` if the timeout passed first.
+    fn receive_line_timeout(&mut self, timeout: Duration) -> io::Result<Option<String>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(Some(line));
+        }
+        let conn = self
+            .connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let previous_timeout = conn.socket().read_timeout()?;
+        conn.socket().set_read_timeout(Some(timeout))?;
+
+        let mut buffer = String::new();
+        let result = self.read_message(&mut buffer);
+        self.connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?
+            .socket()
+            .set_read_timeout(previous_timeout)?;
+        match result {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {
+                buffer.pop();
+                Ok(Some(buffer))
+            }
+            Err(e) if is_timeout(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Discards the messages that have already arrived from the server,
+    /// without waiting for new ones.
+    /// # Returns
+    /// The number of messages discarded.
+    pub fn drain(&mut self) -> io::Result<usize> {
+        let conn = self
+            .connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let previous_timeout = conn.socket().read_timeout()?;
+        // Messages which have already arrived are read well within this.
+        conn.socket()
+            .set_read_timeout(Some(Duration::from_millis(10)))?;
+
+        let mut drained = self.pending.len();
+        self.pending.clear();
+        let result = loop {
+            let mut buffer = String::new();
+            match self.read_message(&mut buffer) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => drained += 1,
+                Err(e) if is_timeout(&e) => break Ok(drained),
+                Err(e) => break Err(e),
+            }
+        };
+        self.connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?
+            .socket()
+            .set_read_timeout(previous_timeout)?;
+        result
+    }
+
+    /// Subscribes to a channel, waits for the next message and unsubscribes
+    /// again. The client stays subscribed if it already was before the call.
+    /// # Arguments
+    /// * `channel` - The channel to wait on.
+    /// * `timeout` - How long to wait for a message.
+    /// # Returns
+    /// The message, or `None` if no message arrived before the timeout.
+    /// # Remarks
+    /// Messages are not labelled with their channel, so the first message
+    /// received on any of the client's subscriptions is returned.
+    pub fn subscribe_once(
+        &mut self,
+        channel: String,
+        timeout: Duration,
+    ) -> io::Result<Option<String>> {
+        let was_subscribed = self.subscriptions.contains(&channel);
+        if !was_subscribed {
+            self.subscribe(channel.clone())?;
+        }
+
+        let message = self.receive_line_timeout(timeout);
+        if !was_subscribed {
+            // An error receiving the message is the more useful to report.
+            let unsubscribed = self.unsubscribe(channel);
+            return message.and_then(|message| unsubscribed.map(|_| message));
+        }
+        message
+    }
+
+    /// Subscribes to a channel, gathers every message which arrives until a
+    /// deadline and unsubscribes again. The client stays subscribed if it
+    /// already was before the call.
+    /// # Arguments
+    /// * `channel` - The channel to collect messages from.
+    /// * `deadline` - When to stop collecting.
+    /// # Returns
+    /// The messages in the order they arrived, or the error which stopped the
+    /// collection. The channel is unsubscribed from either way.
+    /// # Remarks
+    /// Every message is held in memory until the deadline, so collecting from
+    /// a busy channel for long can use a lot of memory. Use `listen` to
+    /// process messages as they arrive instead. As with `subscribe_once`,
+    /// messages arriving on the client's other subscriptions are collected
+    /// too.
+    pub fn collect(&mut self, channel: String, deadline: Instant) -> io::Result<Vec<String>> {
+        let was_subscribed = self.subscriptions.contains(&channel);
+        if !was_subscribed {
+            self.subscribe(channel.clone())?;
+        }
+
+        let mut messages = Vec::new();
+        let mut result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Ok(());
+            }
+            match self.receive_line_timeout(remaining) {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        if !was_subscribed {
+            result = result.and(self.unsubscribe(channel));
+        }
+        result.map(|_| messages)
+    }
+
+    /// Subscribes to a channel.
+    /// # Arguments
+    /// * `channel` - The channel to subscribe to.
+    /// # Returns
+    /// An error if the channel name is invalid, in which case nothing is sent
+    /// to the server, or the subscription could not be sent.
+    pub fn subscribe(&mut self, channel: String) -> io::Result<()> {
+        validate_channel(&channel, self.channel_separator)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command(format!("SUBSCRIBE {}", channel))?;
+        self.subscriptions.insert(channel);
+        Ok(())
+    }
+
+    /// Subscribes to a channel and waits for the server to confirm the
+    /// subscription is in place, so that messages published afterwards are
+    /// not missed. Messages arriving while waiting are kept and passed on by
+    /// the next call receiving messages.
+    /// # Arguments
+    /// * `channel` - The channel to subscribe to.
+    /// * `timeout` - How long to wait for the confirmation.
+    /// # Returns
+    /// An error if the channel is invalid, the server refused the
+    /// subscription or did not confirm it in time, or the connection failed.
+    pub fn subscribe_with_ack(
+        &mut self,
+        channel: String,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        validate_channel(&channel, self.channel_separator).map_err(ClientError::Rejected)?;
+        self.send(format!("SUBSCRIBE_ACK {}", channel))?;
+        let ack = format!("ACK SUBSCRIBE {}", channel);
+        match self.await_reply(
+            |line| line == ack || line.starts_with(protocol::ERROR_PREFIX),
+            timeout,
+        )? {
+            Some(reply) if reply == ack => {
+                self.subscriptions.insert(channel);
+                Ok(())
+            }
+            Some(reply) => Err(ClientError::Rejected(reply)),
+            None => Err(ClientError::Timeout),
+        }
+    }
+
+    /// Subscribes to channels and listens for messages, passing each to a
+    /// callback with the channel it was published on. Every subscription is
+    /// confirmed by the server before listening starts, so that no message
+    /// published afterwards is missed.
+    /// # Arguments
+    /// * `channels` - The channels to subscribe to.
+    /// * `timeout` - How long to wait for each subscription to be confirmed.
+    /// * `callback` - The function to call for each message, with its
+    ///   channel. Messages not published on a channel, such as direct
+    ///   messages, have none.
+    /// # Returns
+    /// An error if a subscription failed, see `subscribe_with_ack`. Otherwise
+    /// `Ok` once the server closes the connection, or an error if reading
+    /// from it fails, see `listen`.
+    /// # Remarks
+    /// The channel of each message is learnt from its sequence number, so
+    /// sequence numbers are enabled, see `enable_sequence_numbers`.
+    pub fn subscribe_and_listen(
+        &mut self,
+        channels: Vec<String>,
+        timeout: Duration,
+        mut callback: impl FnMut(Option<&str>, &Message),
+    ) -> Result<(), ClientError> {
+        self.subscribe_to_listen(channels, timeout)?;
+        self.listen_sequenced(|sequence, message| {
+            callback(sequence.map(|sequence| sequence.channel.as_str()), message)
+        })?;
+        Ok(())
+    }
+
+    /// Subscribes to channels, waiting for each subscription to be confirmed,
+    /// with sequence numbers enabled so that the channel of each message
+    /// received is known, see `subscribe_and_listen`.
+    /// # Arguments
+    /// * `channels` - The channels to subscribe to.
+    /// * `timeout` - How long to wait for each subscription to be confirmed.
+    pub(crate) fn subscribe_to_listen(
+        &mut self,
+        channels: Vec<String>,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        // Enabled first, so that no message arrives without its channel.
+        if !self.sequence_numbers {
+            self.enable_sequence_numbers()?;
+        }
+        for channel in channels {
+            self.subscribe_with_ack(channel, timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribes from a channel, and from the pattern of the same name,
+    /// see `psubscribe`. Subscriptions to channels the pattern matches are
+    /// left in place.
+    /// # Arguments
+    /// * `channel` - The channel or pattern to unsubscribe from.
+    /// # Returns
+    /// An error if the unsubscription could not be sent.
+    pub fn unsubscribe(&mut self, channel: String) -> io::Result<()> {
+        self.subscriptions.remove(&channel);
+        self.pattern_subscriptions.remove(&channel);
+        self.send_command(format!("UNSUBSCRIBE {}", channel))
+    }
+
+    /// Subscribes to every channel matching a pattern. Channels are split into
+    /// segments at each `.`, and a `*` segment matches any single segment
+    /// while a `#` as the last segment matches the rest of the channel, so
+    /// `sensors.*.temp` matches `sensors.kitchen.temp` and `sensors.#`
+    /// matches every channel starting with `sensors.`. Messages are received
+    /// prefixed with the channel they were published on, like those of
+    /// `subscribe_all`.
+    /// # Arguments
+    /// * `pattern` - The pattern to subscribe to.
+    /// # Returns
+    /// An error if the pattern is empty or contains the channel separator, in
+    /// which case nothing is sent to the server, or the subscription could not
+    /// be sent.
+    pub fn psubscribe(&mut self, pattern: String) -> io::Result<()> {
+        validate_channel(&pattern, self.channel_separator)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command(format!("PSUBSCRIBE {}", pattern))?;
+        self.pattern_subscriptions.insert(pattern);
+        Ok(())
+    }
+
+    /// Unsubscribes from a pattern.
+    /// # Arguments
+    /// * `pattern` - The pattern to unsubscribe from.
+    /// # Returns
+    /// An error if the unsubscription could not be sent.
+    pub fn punsubscribe(&mut self, pattern: String) -> io::Result<()> {
+        self.pattern_subscriptions.remove(&pattern);
+        self.send_command(format!("PUNSUBSCRIBE {}", pattern))
+    }
+
+    /// Moves the subscription from one channel to another. The new channel is
+    /// subscribed to before the old one is unsubscribed from, so no message
+    /// published on the new channel during the switch is missed. Messages
+    /// published on the old channel may still be received until the server
+    /// has handled the unsubscription.
+    /// # Arguments
+    /// * `from` - The channel to unsubscribe from.
+    /// * `to` - The channel to subscribe to.
+    /// # Returns
+    /// An error if either step failed. The client remains subscribed to
+    /// `from` if subscribing to `to` failed.
+    pub fn switch_channel(&mut self, from: String, to: String) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = self.subscribe(to.clone()) {
+            return Err(format!("Failed to subscribe to {}: {}", to, e).into());
+        }
+        if let Err(e) = self.unsubscribe(from.clone()) {
+            return Err(format!(
+                "Subscribed to {} but failed to unsubscribe from {}: {}",
+                to, from, e
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Subscribes to every message published on every channel. Messages are
+    /// received prefixed with the channel they were published on. Requires
+    /// admin rights, see `authenticate`.
+    /// # Returns
+    /// An error if the subscription could not be sent, or was refused by the
+    /// server.
+    pub fn subscribe_all(&mut self) -> io::Result<()> {
+        self.subscribed_all = false;
+        self.send_command("SUBSCRIBE_ALL")?;
+        self.subscribed_all = true;
+        Ok(())
+    }
+
+    /// Removes the subscription to every channel.
+    /// # Returns
+    /// An error if the unsubscription could not be sent, or was refused by
+    /// the server.
+    pub fn unsubscribe_all(&mut self) -> io::Result<()> {
+        self.subscribed_all = false;
+        self.send_command("UNSUBSCRIBE_ALL")
+    }
+
+    /// Sends a heartbeat to the server, keeping this client on the server's
+    /// presence channel.
+    /// # Returns
+    /// An error if the heartbeat could not be sent, or was refused by the
+    /// server.
+    pub fn heartbeat(&mut self) -> io::Result<()> {
+        self.send_command("HEARTBEAT")
+    }
+
+    /// Publishes a message to a channel.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish.
+    /// # Returns
+    /// An error if the channel name is invalid, in which case nothing is sent
+    /// to the server, or the message could not be sent.
+    pub fn publish(&mut self, channel: String, message: String) -> io::Result<()> {
+        let args = self
+            .publish_args(&channel, &message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command(format!("PUBLISH {}", args))
+    }
+
+    /// Publishes a message which may not be UTF-8, such as an image or a
+    /// serialised struct, to a channel. Subscribers using the binary encoding
+    /// receive it byte for byte, see `listen_bytes`.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The bytes to publish.
+    /// # Returns
+    /// An error if the channel name is invalid, in which case nothing is sent
+    /// to the server, or the message could not be sent.
+    pub fn publish_bytes(&mut self, channel: &str, message: &[u8]) -> io::Result<()> {
+        let args = self
+            .publish_args(channel, "")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command([format!("PUBLISH {}", args).as_bytes(), message].concat())
+    }
+
+    /// Publishes a message to a channel and has the server retain it, so that
+    /// clients subscribing to the channel later receive it straight away.
+    /// Only the latest message is retained on each channel.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish. An empty message clears the
+    ///   retained message without publishing anything.
+    /// # Returns
+    /// An error if the channel name is invalid, in which case nothing is sent
+    /// to the server, or the message could not be sent.
+    pub fn publish_retained(&mut self, channel: String, message: String) -> io::Result<()> {
+        let args = self
+            .publish_args(&channel, &message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command(format!("RETAIN {}", args))
+    }
+
+    /// Publishes a request on a channel and waits for a subscriber to reply
+    /// to it, see `reply`. Subscribers receive the request as a
+    /// `Message::Request`. Messages arriving while waiting are kept and passed
+    /// on by the next call receiving messages.
+    /// # Arguments
+    /// * `channel` - The channel to publish the request to.
+    /// * `payload` - The request.
+    /// # Returns
+    /// The first reply, or a `TimedOut` error if no reply arrived within the
+    /// client's timeout, see `set_timeout`, or `DEFAULT_REQUEST_TIMEOUT` when
+    /// none is set.
+    pub fn request(&mut self, channel: String, payload: String) -> io::Result<String> {
+        let args = self
+            .publish_args(&channel, &payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // Correlation ids are shared by every client of the server, so they
+        // are random rather than counted.
+        self.requests += 1;
+        let id = format!("{:016x}", RandomState::new().hash_one(self.requests));
+        self.send_command(format!("REQUEST {} {}", id, args))?;
+
+        let prefix = format!("REPLY {} ", id);
+        let timeout = self.timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        match self.await_reply(|line| line.starts_with(&prefix), timeout)? {
+            Some(reply) => Ok(reply[prefix.len()..].to_string()),
+            None => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No reply to request {}", id),
+            )),
+        }
+    }
+
+    /// Replies to a request received as a `Message::Request`. Only the first
+    /// reply to a request is passed on to the requester.
+    /// # Arguments
+    /// * `id` - The correlation id of the request.
+    /// * `payload` - The reply.
+    /// # Returns
+    /// An error if the reply could not be sent.
+    pub fn reply(&mut self, id: &str, payload: &str) -> io::Result<()> {
+        self.send_command(format!("REPLY {} {}", id, payload))
+    }
+
+    /// Joins a channel and a message with the channel separator, as the
+    /// arguments of the publish commands.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish.
+    /// # Returns
+    /// The arguments, or the reason the channel name is invalid.
+    fn publish_args(&self, channel: &str, message: &str) -> Result<String, String> {
+        validate_channel(channel, self.channel_separator)?;
+        Ok(format!("{}{}{}", channel, self.channel_separator, message))
+    }
+
+    /// Sends a message to every client connected to the server, including this
+    /// one, whatever they are subscribed to. Clients receive it as a
+    /// `Message::Regular`. Requires admin rights, see `authenticate`, when the
+    /// server has an admin token.
+    /// # Arguments
+    /// * `message` - The message to broadcast.
+    /// # Returns
+    /// An error if the message could not be sent, or was refused by the
+    /// server when checking for errors, see `set_check_errors`.
+    pub fn broadcast(&mut self, message: String) -> io::Result<()> {
+        self.send_command(format!("BROADCAST {}", message))
+    }
+
+    /// Sends a message directly to another client, rather than publishing it
+    /// on a channel. The recipient receives it as a `Message::Direct`.
+    /// # Arguments
+    /// * `id` - The id of the recipient, as listed by `list_clients` or given
+    ///   as the sender of a direct message.
+    /// * `message` - The message to send.
+    /// # Returns
+    /// Whether the recipient was found, or an error if the message could not
+    /// be sent or the server refused it.
+    pub fn send_to(&mut self, id: &str, message: &str) -> Result<bool, Box<dyn Error>> {
+        self.send(format!("SENDTO {} {}", id, message))?;
+        let delivered = format!("DELIVERED {}", id);
+        let not_found = format!("NOT_FOUND {}", id);
+        let reply = self.read_reply(|line| {
+            line == delivered || line == not_found || line.starts_with(protocol::ERROR_PREFIX)
+        })?;
+        match reply {
+            reply if reply == delivered => Ok(true),
+            reply if reply == not_found => Ok(false),
+            reply => Err(reply.into()),
+        }
+    }
+
+    /// Publishes a message to a channel and waits for the server to
+    /// acknowledge it, publishing it again if no acknowledgement arrives in
+    /// time. The message carries an id so that a server started with
+    /// `--dedup-window` delivers it only once, however many times it is sent.
+    /// Messages arriving while waiting are kept and passed on by the next call
+    /// receiving messages.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish.
+    /// * `retries` - How many times to publish the message again.
+    /// * `timeout` - How long to wait for each acknowledgement.
+    /// # Remarks
+    /// The server remembers message ids per connection, so a message retried
+    /// after reconnecting may be delivered twice.
+    pub fn publish_qos(
+        &mut self,
+        channel: String,
+        message: String,
+        retries: u32,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let args = self.publish_args(&channel, &message)?;
+        self.message_ids += 1;
+        let id = self.message_ids;
+        let command = format!("PUBLISH_ID {} {}", id, args);
+        let ack = format!("ACK {}", id);
+        for _ in 0..=retries {
+            self.send(command.clone())?;
+            if self.await_reply(|line| line == ack, timeout)?.is_some() {
+                return Ok(());
+            }
+        }
+        Err(format!("Message {} was not acknowledged", id).into())
+    }
+
+    /// Waits for a reply from the server, keeping any other messages which
+    /// arrive first.
+    /// # Arguments
+    /// * `is_reply` - Whether a message is the reply.
+    /// * `timeout` - How long to wait.
+    /// # Returns
+    /// The reply without its newline, or `None` if the timeout passed first.
+    fn await_reply(
+        &mut self,
+        is_reply: impl Fn(&str) -> bool,
+        timeout: Duration,
+    ) -> io::Result<Option<String>> {
+        let deadline = Instant::now() + timeout;
+        let conn = self
+            .connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let previous_timeout = conn.socket().read_timeout()?;
+
+        let result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Ok(None);
+            }
+            if let Err(e) = self
+                .connection
+                .as_mut()
+                .ok_or(io::ErrorKind::NotConnected.into())
+                .and_then(|conn| conn.socket().set_read_timeout(Some(remaining)))
+            {
+                break Err(e);
+            }
+            let mut buffer = String::new();
+            match self.read_message(&mut buffer) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {
+                    buffer.pop();
+                    if is_reply(&buffer) {
+                        break Ok(Some(buffer));
+                    }
+                    self.pending.push_back(buffer);
+                }
+                Err(e) if is_timeout(&e) => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        };
+        self.connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?
+            .socket()
+            .set_read_timeout(previous_timeout)?;
+        result
+    }
+
+    /// Publishes a sequence of messages to a channel. The commands are
+    /// encoded into a single reused buffer and written back-to-back, without
+    /// waiting on the server between messages, which is cheaper than calling
+    /// `publish` for each message.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `messages` - The messages to publish, in order.
     /// # Returns
-    /// * `bool` - Whether the unsubscription was successful.
-    pub fn unsubscribe(&mut self, channel: String) -> bool {
-        self.send(format!("UNSUBSCRIBE {}", channel).as_str().to_string())
+    /// The number of messages sent, or the first error writing to the server.
+    /// Nothing is sent to invalid channel names. When checking for errors,
+    /// see `set_check_errors`, the first message the server refused is an
+    /// error too, once every message has been sent.
+    /// # Remarks
+    /// Unlike `publish`, a failed write is not retried by reconnecting, even
+    /// with auto-reconnect enabled, as the messages written before it may
+    /// already have been delivered.
+    pub fn publish_iter<I: IntoIterator<Item = String>>(
+        &mut self,
+        channel: &str,
+        messages: I,
+    ) -> io::Result<usize> {
+        let separator = self.channel_separator;
+        validate_channel(channel, separator)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let mut writer = io::BufWriter::new(connection);
+        let mut command = Vec::new();
+        let mut sent = 0;
+        for message in messages {
+            command.clear();
+            write!(command, "PUBLISH {}{}{}", channel, separator, message)?;
+            writer.write_all(&protocol::encode_frame(&command))?;
+            sent += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.check_sent()?;
+        Ok(sent)
     }
 
-    /// Publishes a message to a channel.
+    /// Publishes a system message to a channel. Subscribers are able to tell
+    /// system messages apart from regular messages, see `Message`. Requires
+    /// admin rights, see `authenticate`.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish.
+    /// # Returns
+    /// An error if the channel name is invalid, in which case nothing is sent
+    /// to the server, or the message could not be sent.
+    pub fn publish_system(&mut self, channel: String, message: String) -> io::Result<()> {
+        let args = self
+            .publish_args(&channel, &message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command(format!("PUBLISH_SYSTEM {}", args))
+    }
+
+    /// Publishes a message to a channel with a priority.
     /// # Arguments
     /// * `channel` - The channel to publish to.
     /// * `message` - The message to publish.
+    /// * `priority` - The priority of the message.
     /// # Returns
-    /// * `bool` - Whether the publish was successful.
-    pub fn publish(&mut self, channel: String, message: String) -> bool {
-        self.send(
-            format!("PUBLISH {} {}", channel, message)
-                .as_str()
-                .to_string(),
-        )
+    /// An error if the channel name is invalid, in which case nothing is sent
+    /// to the server, or the message could not be sent.
+    pub fn publish_priority(
+        &mut self,
+        channel: String,
+        message: String,
+        priority: Priority,
+    ) -> io::Result<()> {
+        let args = self
+            .publish_args(&channel, &message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_command(format!("PUBLISH_PRIORITY {} {}", priority, args))
     }
 
     /// Listens for messages from the server. Any messages received are passed
     /// to a callback function.
     /// # Arguments
     /// * `callback` - The function to call when a message is received.
+    /// # Returns
+    /// `Ok` once the server closes the connection, or an error if reading
+    /// from it fails. When auto-reconnect is enabled, see
+    /// `set_auto_reconnect`, either is an error only once the connection
+    /// could not be restored.
     /// # Remarks
     /// This function will listen forever until the client is disconnected.
-    pub fn listen(&mut self, callback: fn(&String)) {
-        let mut buffer = String::new();
-        let conn = self.connection.as_mut().unwrap();
-        let mut reader = BufReader::new(conn);
+    /// System messages are passed to the callback like any other message, use
+    /// `listen_messages` to tell them apart.
+    pub fn listen(&mut self, mut callback: impl FnMut(&String)) -> io::Result<()> {
+        self.listen_with(|message| callback(message.text()))
+    }
+
+    /// Listens for messages from the server until a number of them have been
+    /// received, passing each to a callback, so that scripts waiting for a
+    /// few messages can carry on afterwards.
+    /// # Arguments
+    /// * `n` - How many messages to receive, or 0 to listen forever as
+    ///   `listen` does.
+    /// * `callback` - The function to call when a message is received.
+    /// # Returns
+    /// `Ok` once `n` messages have been received, or an `UnexpectedEof` error
+    /// if the server closes the connection first. An error if reading from
+    /// the connection fails, see `listen`.
+    /// # Remarks
+    /// Messages arriving after the last one are left for the next call
+    /// receiving messages.
+    pub fn listen_n(&mut self, n: usize, mut callback: impl FnMut(&String)) -> io::Result<()> {
+        let mut received = 0;
+        self.listen_until(|_, message| {
+            callback(message.text());
+            received += 1;
+            match n > 0 && received >= n {
+                true => ControlFlow::Break(()),
+                false => ControlFlow::Continue(()),
+            }
+        })?;
+        if n > 0 && received < n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "The server closed the connection after {} of {} messages",
+                    received, n
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Listens for messages from the server. Any messages received are passed
+    /// to a callback function.
+    /// # Arguments
+    /// * `callback` - The function to call when a message is received.
+    /// # Returns
+    /// `Ok` once the server closes the connection, or an error if reading
+    /// from it fails. When auto-reconnect is enabled, see
+    /// `set_auto_reconnect`, either is an error only once the connection
+    /// could not be restored.
+    /// # Remarks
+    /// This function will listen forever until the client is disconnected.
+    pub fn listen_messages(&mut self, callback: impl FnMut(&Message)) -> io::Result<()> {
+        self.listen_with(callback)
+    }
+
+    /// Listens for messages from the server, passing the bytes of each to a
+    /// callback, so that messages which are not UTF-8 are received intact.
+    /// The client must use the binary encoding, see
+    /// `ClientBuilder::encoding`, as with the text encoding the end of a
+    /// message cannot be told apart from a newline within it.
+    /// # Arguments
+    /// * `callback` - The function to call with the bytes of each message.
+    /// # Returns
+    /// An `InvalidInput` error if the client uses the text encoding. Otherwise
+    /// `Ok` once the server closes the connection, or an error if reading
+    /// from it fails.
+    /// # Remarks
+    /// Messages are passed on as the server sent them, so system messages,
+    /// for example, keep their markers. A lost connection is not restored.
+    pub fn listen_bytes(&mut self, mut callback: impl FnMut(&[u8])) -> io::Result<()> {
+        if self.encoding != Encoding::Binary {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Listening for bytes requires the binary encoding",
+            ));
+        }
+        while let Some(line) = self.pending.pop_front() {
+            callback(line.as_bytes());
+        }
         loop {
-            reader.read_line(&mut buffer).unwrap();
-            if buffer.len() > 0 {
-                // Remove the newline character.
-                buffer.pop();
-                callback(&buffer);
+            let reader = self.reader.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+            let message = match protocol::read_frame(reader) {
+                Ok(message) => message,
+                // The server closed the connection.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                // Only a read which timed out, see `set_timeout`.
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            // Heartbeats are answered rather than passed on, as they are by
+            // every other way of reading messages.
+            if message == b"PING" {
+                self.write_frame(&protocol::encode_frame(b"PONG"))?;
+            } else {
+                callback(&message);
             }
-            buffer.clear();
         }
     }
+
+    /// Listens for messages from the server, passing each to a callback which
+    /// may fail, for example on a message it cannot parse.
+    /// # Arguments
+    /// * `policy` - Whether a failed callback stops listening or is logged.
+    /// * `callback` - The function to call with the text of each message.
+    /// # Returns
+    /// The callback's error when the policy is to stop. Otherwise `Ok` once
+    /// the server closes the connection, or an error if reading from it
+    /// fails, see `listen`.
+    /// # Remarks
+    /// `listen` is the same for callbacks which cannot fail.
+    pub fn try_listen<E: fmt::Display>(
+        &mut self,
+        policy: CallbackErrorPolicy,
+        mut callback: impl FnMut(&str) -> Result<(), E>,
+    ) -> Result<(), ListenError<E>> {
+        let mut failure = None;
+        self.listen_events(self.auto_reconnect, |event| {
+            let Some(message) = event.message() else {
+                return ControlFlow::Continue(());
+            };
+            match (callback(message.text()), policy) {
+                (Ok(()), _) => ControlFlow::Continue(()),
+                (Err(e), CallbackErrorPolicy::Log) => {
+                    error!("Failed to process message: {}", e);
+                    ControlFlow::Continue(())
+                }
+                (Err(e), CallbackErrorPolicy::Stop) => {
+                    failure = Some(e);
+                    ControlFlow::Break(())
+                }
+            }
+        })?;
+        match failure {
+            Some(e) => Err(ListenError::Callback(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Listens for messages from the server, reconnecting whenever the
+    /// connection is lost. The client starts a session, see `start_session`,
+    /// and resumes it on reconnect. Its subscriptions stay in place on the
+    /// server while it is disconnected. A `ListenEvent::Gap` is passed to the
+    /// callback after each reconnect.
+    /// # Arguments
+    /// * `policy` - How to reconnect.
+    /// * `callback` - The function to call for each message or gap.
+    /// # Returns
+    /// An error if the server does not allow sessions, or once the connection
+    /// is lost and could not be restored.
+    /// # Remarks
+    /// Delivery is at-most-once. The server never sends a message twice, so
+    /// nothing needs deduplicating. Messages published while the client is
+    /// disconnected are not replayed, so they are lost. A gap marks where
+    /// that may have happened.
+    pub fn listen_resumable(
+        &mut self,
+        policy: ReconnectPolicy,
+        mut callback: impl FnMut(&ListenEvent),
+    ) -> io::Result<()> {
+        if self.session.is_none() {
+            self.start_session()?;
+        }
+        self.listen_events(Some(policy), |event| {
+            callback(event);
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// Listens for messages from the server until the callback asks to stop.
+    /// When auto-reconnect is enabled, a lost connection is restored and
+    /// messages continue to be passed to the same callback.
+    /// # Arguments
+    /// * `callback` - The function to call for each message, with its
+    ///   sequence number if it has one, see `listen_sequenced`, returning
+    ///   whether to carry on listening.
+    /// # Returns
+    /// `Ok` once the callback asks to stop or the server closes the
+    /// connection, or an error if reading from it fails, see `listen`.
+    pub(crate) fn listen_until(
+        &mut self,
+        mut callback: impl FnMut(Option<&Sequence>, &Message) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        self.listen_events(self.auto_reconnect, |event| match event {
+            ListenEvent::Message(message) => callback(None, message),
+            ListenEvent::Sequenced { sequence, message } => callback(Some(sequence), message),
+            ListenEvent::Gap => ControlFlow::Continue(()),
+        })
+    }
+
+    /// Listens for messages from the server, passing each to a callback. When
+    /// auto-reconnect is enabled, a lost connection is restored and messages
+    /// continue to be passed to the same callback.
+    fn listen_with(&mut self, mut callback: impl FnMut(&Message)) -> io::Result<()> {
+        self.listen_events(self.auto_reconnect, |event| {
+            if let Some(message) = event.message() {
+                callback(message);
+            }
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// Listens for messages from the server, passing each to a callback with
+    /// its sequence number, see `enable_sequence_numbers`. A message whose
+    /// number is more than one past the last number seen on its channel
+    /// follows messages which were missed, for example while reconnecting.
+    /// When auto-reconnect is enabled, a lost connection is restored and
+    /// messages continue to be passed to the same callback.
+    /// # Arguments
+    /// * `callback` - The function to call for each message, with its
+    ///   sequence number if it has one. Direct messages have none.
+    /// # Returns
+    /// `Ok` once the server closes the connection, or an error if reading
+    /// from it fails, see `listen`.
+    pub fn listen_sequenced(
+        &mut self,
+        mut callback: impl FnMut(Option<&Sequence>, &Message),
+    ) -> io::Result<()> {
+        self.listen_events(self.auto_reconnect, |event| {
+            match event {
+                ListenEvent::Message(message) => callback(None, message),
+                ListenEvent::Sequenced { sequence, message } => callback(Some(sequence), message),
+                ListenEvent::Gap => {}
+            }
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// Listens for messages from the server, passing each to a callback.
+    /// Given a policy, a lost connection is restored, a gap is passed to the
+    /// callback and listening continues. Without one, listening stops, and
+    /// only a connection which failed rather than being closed by the server
+    /// is an error.
+    /// # Arguments
+    /// * `policy` - How to reconnect, if at all.
+    /// * `callback` - The function to call for each message or gap.
+    fn listen_events(
+        &mut self,
+        policy: Option<ReconnectPolicy>,
+        mut callback: impl FnMut(&ListenEvent) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        loop {
+            while let Some(line) = self.pending.pop_front() {
+                if callback(&self.parse_event(line)).is_break() {
+                    return Ok(());
+                }
+            }
+            let mut buffer = String::new();
+            let error = loop {
+                match self.read_message(&mut buffer) {
+                    // The server closed the connection.
+                    Ok(0) => break io::Error::from(io::ErrorKind::UnexpectedEof),
+                    Ok(_) => {
+                        if !buffer.is_empty() {
+                            // Remove the newline character.
+                            buffer.pop();
+                            let event = self.parse_event(buffer.clone());
+                            if callback(&event).is_break() {
+                                return Ok(());
+                            }
+                        }
+                        buffer.clear();
+                    }
+                    // Only a read which timed out, the connection may still
+                    // be fine. Part of a line read before the timeout is kept
+                    // in the buffer, see `set_timeout` for frames.
+                    Err(e) if is_timeout(&e) => (),
+                    Err(e) => break e,
+                }
+            };
+
+            if let Some(Callback(callback)) = self.on_disconnect.as_mut() {
+                callback(&error);
+            }
+            match policy {
+                Some(policy) => {
+                    self.reconnect_with_policy(policy)?;
+                    if callback(&ListenEvent::Gap).is_break() {
+                        return Ok(());
+                    }
+                }
+                // The server closing the connection is how listening ends.
+                None if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                None => return Err(error),
+            }
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    /// Helper function to read a command sent by the client.
+    fn read_command(stream: &mut TcpStream) -> String {
+        String::from_utf8(protocol::read_frame(stream).unwrap()).unwrap()
+    }
+
+    /// Test that listening reconnects when the connection is lost, restoring
+    /// the subscriptions and passing messages to the same callback, until the
+    /// reconnection attempts are exhausted.
+    #[test]
+    fn test_listen_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        client.set_auto_reconnect(Some(ReconnectPolicy {
+            max_attempts: 3,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+        }));
+        client.subscribe("test".to_string()).unwrap();
+
+        let server = thread::spawn(move || {
+            for message in ["first\n", "second\n"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                assert_eq!(read_command(&mut stream), "SUBSCRIBE test");
+                stream.write_all(message.as_bytes()).unwrap();
+            }
+        });
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            client.on_disconnect(move |_| events.lock().unwrap().push("disconnect"));
+        }
+        {
+            let events = events.clone();
+            client.on_reconnect(move || events.lock().unwrap().push("reconnect"));
+        }
+
+        let mut received = Vec::new();
+        let result = client.listen_with(|message| received.push(message.text().to_string()));
+        server.join().unwrap();
+        assert!(result.is_err());
+        assert_eq!(received, vec!["first", "second"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["disconnect", "reconnect", "disconnect"]
+        );
+    }
+
+    /// Test that a resumable listener starts a session, resumes it when the
+    /// connection is lost and marks the gap between connections.
+    #[test]
+    fn test_listen_resumable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "SESSION");
+            stream.write_all(b"SESSION abc\n").unwrap();
+            stream.write_all(b"first\n").unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "RESUME abc");
+            stream.write_all(b"RESUMED abc\n").unwrap();
+            stream.write_all(b"second\n").unwrap();
+        });
+
+        assert_eq!(client.start_session().unwrap(), "abc");
+        let mut events = Vec::new();
+        let policy = ReconnectPolicy {
+            max_attempts: 1,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+        };
+        let result = client.listen_events(Some(policy), |event| {
+            events.push(event.clone());
+            ControlFlow::Continue(())
+        });
+        server.join().unwrap();
+        assert!(result.is_err());
+        assert_eq!(
+            events,
+            vec![
+                ListenEvent::Message(Message::Regular("first".to_string())),
+                ListenEvent::Gap,
+                ListenEvent::Message(Message::Regular("second".to_string())),
+            ]
+        );
+    }
+
+    /// Test that a message is published again, with the same id, until it is
+    /// acknowledged, keeping messages which arrive in the meantime.
+    #[test]
+    fn test_publish_qos() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            assert_eq!(read_command(&mut stream), "PUBLISH_ID 1 news\u{1f}hello");
+            assert_eq!(read_command(&mut stream), "PUBLISH_ID 1 news\u{1f}hello");
+            stream.write_all(b"update\nACK 1\n").unwrap();
+            assert_eq!(read_command(&mut stream), "PUBLISH_ID 2 news\u{1f}again");
+            stream
+        });
+
+        client
+            .publish_qos(
+                "news".to_string(),
+                "hello".to_string(),
+                1,
+                Duration::from_millis(100),
+            )
+            .unwrap();
+        assert_eq!(client.pending, VecDeque::from(["update".to_string()]));
+        assert!(client
+            .publish_qos(
+                "news".to_string(),
+                "again".to_string(),
+                0,
+                Duration::from_millis(10),
+            )
+            .is_err());
+        server.join().unwrap();
+    }
+
+    /// Test that a subscription is only recorded once the server confirms it.
+    #[test]
+    fn test_subscribe_with_ack() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .write_all(b"update\nACK SUBSCRIBE news\nERR invalid channel\n")
+            .unwrap();
+
+        client
+            .subscribe_with_ack("news".to_string(), Duration::from_secs(1))
+            .unwrap();
+        assert!(matches!(
+            client.subscribe_with_ack("sport".to_string(), Duration::from_secs(1)),
+            Err(ClientError::Rejected(_))
+        ));
+        assert!(matches!(
+            client.subscribe_with_ack("sport".to_string(), Duration::from_millis(10)),
+            Err(ClientError::Timeout)
+        ));
+        assert!(matches!(
+            client.subscribe_with_ack(" ".to_string(), Duration::from_millis(10)),
+            Err(ClientError::Rejected(_))
+        ));
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE_ACK news");
+        assert_eq!(client.subscriptions, HashSet::from(["news".to_string()]));
+        assert_eq!(client.pending, VecDeque::from(["update".to_string()]));
+    }
+
+    /// Test that listening stops once the callback asks, or once the
+    /// connection is closed.
+    #[test]
+    fn test_listen_until() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"1\n2\n3\n").unwrap();
+        drop(stream);
+
+        let mut received = Vec::new();
+        let result = client.listen_until(|_, message| {
+            received.push(message.text().clone());
+            match received.len() {
+                2 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(received, vec!["1", "2"]);
+
+        let result = client.listen_until(|_, message| {
+            received.push(message.text().clone());
+            ControlFlow::Continue(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(received, vec!["1", "2", "3"]);
+    }
+
+    /// Test that listening for a number of messages returns once exactly that
+    /// many have been passed on, leaving the rest, and fails if the
+    /// connection is closed first.
+    #[test]
+    fn test_listen_n() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"1\n2\n3\n4\n5\n").unwrap();
+
+        let mut received = Vec::new();
+        client
+            .listen_n(3, |message| received.push(message.clone()))
+            .unwrap();
+        assert_eq!(received, vec!["1", "2", "3"]);
+        client
+            .listen_n(1, |message| received.push(message.clone()))
+            .unwrap();
+        assert_eq!(received, vec!["1", "2", "3", "4"]);
+
+        drop(stream);
+        let error = client
+            .listen_n(2, |message| received.push(message.clone()))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(received, vec!["1", "2", "3", "4", "5"]);
+    }
+
+    /// Test that a failing callback stops listening, or is skipped, depending
+    /// on the policy.
+    #[test]
+    fn test_try_listen() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"1\nx\n2\nx\n3\n").unwrap();
+        drop(stream);
+        drop(listener);
+        client.set_auto_reconnect(Some(ReconnectPolicy {
+            max_attempts: 1,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+        }));
+
+        let mut received = Vec::new();
+        let result = client.try_listen(CallbackErrorPolicy::Stop, |text| {
+            received.push(text.parse::<u32>()?);
+            Ok::<_, std::num::ParseIntError>(())
+        });
+        assert!(matches!(result, Err(ListenError::Callback(_))));
+        assert_eq!(received, vec![1]);
+
+        let result = client.try_listen(CallbackErrorPolicy::Log, |text| {
+            received.push(text.parse::<u32>()?);
+            Ok::<_, std::num::ParseIntError>(())
+        });
+        assert!(matches!(result, Err(ListenError::Io(_))));
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+
+    /// Test that the callbacks of `listen` and `receive` may capture and
+    /// change state, such as counting or collecting messages.
+    #[test]
+    fn test_listen_capturing_closure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"first\nsecond\nthird\n").unwrap();
+        drop(stream);
+
+        let mut received = Vec::new();
+        client
+            .receive(|message| received.push(message.clone()))
+            .unwrap();
+        assert_eq!(received, vec!["first"]);
+
+        let mut count = 0;
+        assert!(client.listen(|_| count += 1).is_ok());
+        assert_eq!(count, 2);
+    }
+
+    /// Test that the wait before each attempt to reconnect doubles, up to the
+    /// longest wait.
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectPolicy {
+            max_attempts: 10,
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        let waits: Vec<u128> = (0..5).map(|i| policy.backoff(i).as_millis()).collect();
+        assert_eq!(waits, vec![100, 200, 400, 500, 500]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(500));
+
+        let fixed = ReconnectPolicy {
+            max_delay: Duration::ZERO,
+            ..policy
+        };
+        assert_eq!(fixed.backoff(3), Duration::from_millis(100));
+    }
+
+    /// Test that a message which cannot be sent as the server has closed the
+    /// connection is sent again once the client has reconnected and
+    /// subscribed again.
+    #[test]
+    fn test_send_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        client.set_auto_reconnect(Some(ReconnectPolicy {
+            max_attempts: 3,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+        }));
+        let (stream, _) = listener.accept().unwrap();
+        drop(stream);
+
+        // The first write after the server has closed the connection
+        // succeeds, and the server resets the connection in return.
+        client.subscribe("news".to_string()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client
+            .publish("news".to_string(), "hello".to_string())
+            .unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE news");
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f}hello");
+    }
+
+    /// Test that pinging or receiving from a server which never replies fails
+    /// once the timeout passes, rather than blocking forever.
+    #[test]
+    fn test_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (_stream, _) = listener.accept().unwrap();
+        client
+            .set_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let start = Instant::now();
+        let error = client.ping().unwrap_err();
+        let error = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let error = client.receive(|_| ()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    /// Test that listening carries on after a read times out, and only stops
+    /// once the connection is lost.
+    #[test]
+    fn test_listen_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = ClientBuilder::new("127.0.0.1".to_string(), port)
+            .timeout(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stream.write_all(b"late\n").unwrap();
+        });
+
+        let mut received = Vec::new();
+        client
+            .listen(|message| received.push(message.clone()))
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(received, vec!["late"]);
+    }
+
+    /// Test that listening returns once the server closes the connection,
+    /// after passing on the messages sent before it did, and that the
+    /// disconnect is still reported.
+    #[test]
+    fn test_listen_server_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (sender, disconnects) = mpsc::channel();
+        client.on_disconnect(move |e| sender.send(e.kind()).unwrap());
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"last\n").unwrap();
+        drop(stream);
+
+        let mut received = Vec::new();
+        client
+            .listen(|message| received.push(message.clone()))
+            .unwrap();
+        assert_eq!(received, vec!["last"]);
+        assert_eq!(disconnects.try_recv(), Ok(io::ErrorKind::UnexpectedEof));
+    }
+
+    /// Test that switching channels subscribes to the new channel before
+    /// unsubscribing from the old one.
+    #[test]
+    fn test_switch_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.subscribe("old".to_string()).unwrap();
+        client
+            .switch_channel("old".to_string(), "new".to_string())
+            .unwrap();
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE old");
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE new");
+        assert_eq!(read_command(&mut stream), "UNSUBSCRIBE old");
+        assert_eq!(client.subscriptions, HashSet::from(["new".to_string()]));
+
+        // An invalid channel is never subscribed to, leaving the old
+        // subscription in place.
+        assert!(client
+            .switch_channel("new".to_string(), " ".to_string())
+            .is_err());
+        assert_eq!(client.subscriptions, HashSet::from(["new".to_string()]));
+    }
+
+    /// Test that patterns are subscribed to, remembered for reconnecting and
+    /// forgotten once unsubscribed from, and that empty patterns are never
+    /// sent.
+    #[test]
+    fn test_psubscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.psubscribe("sensors.#".to_string()).unwrap();
+        assert_eq!(
+            client.psubscribe(" ".to_string()).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            client.pattern_subscriptions,
+            HashSet::from(["sensors.#".to_string()])
+        );
+        client.punsubscribe("sensors.#".to_string()).unwrap();
+        assert!(client.pattern_subscriptions.is_empty());
+        client.psubscribe("sensors.*".to_string()).unwrap();
+        client.unsubscribe("sensors.*".to_string()).unwrap();
+        assert!(client.pattern_subscriptions.is_empty());
+
+        assert_eq!(read_command(&mut stream), "PSUBSCRIBE sensors.#");
+        assert_eq!(read_command(&mut stream), "PUNSUBSCRIBE sensors.#");
+        assert_eq!(read_command(&mut stream), "PSUBSCRIBE sensors.*");
+        assert_eq!(read_command(&mut stream), "UNSUBSCRIBE sensors.*");
+    }
+
+    /// Test that waiting for a single message returns the message, or `None`
+    /// once the timeout passes, unsubscribing in both cases.
+    #[test]
+    fn test_subscribe_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        stream.write_all(b"hello\n").unwrap();
+        let message = client
+            .subscribe_once("test".to_string(), Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(message, Some("hello".to_string()));
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE test");
+        assert_eq!(read_command(&mut stream), "UNSUBSCRIBE test");
+
+        let message = client
+            .subscribe_once("test".to_string(), Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(message, None);
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE test");
+        assert_eq!(read_command(&mut stream), "UNSUBSCRIBE test");
+        assert!(client.subscriptions.is_empty());
+    }
+
+    /// Test that every message arriving before the deadline is collected,
+    /// and that the channel is unsubscribed from when the connection fails
+    /// part way.
+    #[test]
+    fn test_collect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        stream.write_all(b"first\nsecond\n").unwrap();
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let messages = client.collect("test".to_string(), deadline).unwrap();
+        assert!(Instant::now() >= deadline);
+        assert_eq!(messages, vec!["first", "second"]);
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE test");
+        assert_eq!(read_command(&mut stream), "UNSUBSCRIBE test");
+
+        stream.write_all(b"third\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(client.collect("test".to_string(), deadline).is_err());
+        assert_eq!(read_command(&mut stream), "SUBSCRIBE test");
+        assert_eq!(read_command(&mut stream), "UNSUBSCRIBE test");
+        assert!(client.subscriptions.is_empty());
+    }
+
+    /// Test that the builder negotiates the binary encoding, after which
+    /// messages are read from frames and may contain newlines.
+    #[test]
+    fn test_builder_encoding() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (negotiated, wait_for_negotiation) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "ENCODING BINARY");
+            stream.write_all(b"ENCODING BINARY\n").unwrap();
+            wait_for_negotiation.recv().unwrap();
+            stream
+                .write_all(&protocol::encode_frame(b"two\nlines"))
+                .unwrap();
+        });
+
+        let mut client = ClientBuilder::new("127.0.0.1".to_string(), port)
+            .encoding(Encoding::Binary)
+            .build()
+            .unwrap();
+        assert_eq!(client.encoding(), Encoding::Binary);
+        negotiated.send(()).unwrap();
+        let mut received = Vec::new();
+        client
+            .receive_with(|message| received.push(message.text().to_string()))
+            .unwrap();
+        assert_eq!(received, vec!["two\nlines"]);
+        server.join().unwrap();
+    }
+
+    /// Test that binary messages are read byte for byte, keeping any trailing
+    /// newline of their own, while messages are published unchanged.
+    #[test]
+    fn test_binary_messages_byte_exact() {
+        let mut reader = io::Cursor::new(
+            [protocol::encode_frame(b"a"), protocol::encode_frame(b"b\n")].concat(),
+        );
+        for expected in ["a\n", "b\n\n"] {
+            let mut buffer = String::new();
+            read_line(&mut reader, Encoding::Binary, &mut buffer).unwrap();
+            assert_eq!(buffer, expected);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        client
+            .publish("news".to_string(), "no newline".to_string())
+            .unwrap();
+        client
+            .publish("news".to_string(), " spaced \n".to_string())
+            .unwrap();
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f}no newline");
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f} spaced \n");
+    }
+
+    /// Test that failing to connect is reported as an error, and that a
+    /// parsed client only connects once asked to.
+    #[test]
+    fn test_try_new() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = format!("127.0.0.1:{}", port).parse::<Client>().unwrap();
+        assert!(client.connection.is_none());
+        client.connect().unwrap();
+        assert!(Client::try_new("127.0.0.1".to_string(), port).is_ok());
+
+        drop(listener);
+        assert!(Client::try_new("127.0.0.1".to_string(), port).is_err());
+    }
+
+    /// Test that connecting to a port nothing listens on returns an error
+    /// rather than panicking.
+    #[test]
+    fn test_connect_closed_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let mut client = format!("127.0.0.1:{}", port).parse::<Client>().unwrap();
+        let error = client.connect().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(client.connection.is_none());
+    }
+
+    /// Test that reconnecting resumes the session rather than subscribing
+    /// again, and subscribes again once the session has expired.
+    #[test]
+    fn test_reconnect_resumes_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "SESSION");
+            stream.write_all(b"SESSION abc\n").unwrap();
+            assert_eq!(read_command(&mut stream), "SUBSCRIBE news");
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "RESUME abc");
+            stream.write_all(b"RESUMED abc\n").unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "RESUME abc");
+            stream.write_all(b"ERR unknown session\n").unwrap();
+            assert_eq!(read_command(&mut stream), "SESSION");
+            stream.write_all(b"SESSION def\n").unwrap();
+            assert_eq!(read_command(&mut stream), "SUBSCRIBE news");
+        });
+
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        assert_eq!(client.start_session().unwrap(), "abc");
+        client.subscribe("news".to_string()).unwrap();
+        client.reconnect().unwrap();
+        assert_eq!(client.session_id(), Some("abc"));
+        client.reconnect().unwrap();
+        assert_eq!(client.session_id(), Some("def"));
+        server.join().unwrap();
+    }
+
+    /// Test that every message is published, in order, as its own command.
+    #[test]
+    fn test_publish_iter() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let messages = ["first", "", "third message"].map(String::from);
+        assert_eq!(client.publish_iter("news", messages).unwrap(), 3);
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f}first");
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f}");
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f}third message");
+    }
+
+    /// Test that a channel message arriving before the reply to a ping is
+    /// kept and received afterwards, rather than mistaken for the reply.
+    #[test]
+    fn test_ping_interleaved_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (pinged, wait_for_ping) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "PING 1");
+            stream.write_all(b"PONG\nPONG 1\n").unwrap();
+            assert_eq!(read_command(&mut stream), "PING 2");
+            stream.write_all(b"first\nPONG 2\n").unwrap();
+            wait_for_ping.recv().unwrap();
+            stream.write_all(b"second\n").unwrap();
+        });
+
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        client.ping().unwrap();
+        client.ping().unwrap();
+        pinged.send(()).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            client
+                .receive_with(|message| received.push(message.text().to_string()))
+                .unwrap();
+        }
+        assert_eq!(received, vec!["PONG", "first", "second"]);
+        server.join().unwrap();
+    }
+
+    /// Test that heartbeats from the server are answered without being
+    /// received as messages.
+    #[test]
+    fn test_answers_heartbeat() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"PING\nhello\n").unwrap();
+            assert_eq!(read_command(&mut stream), "PONG");
+        });
+
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let mut received = Vec::new();
+        client
+            .receive(|message| received.push(message.clone()))
+            .unwrap();
+        assert_eq!(received, vec!["hello"]);
+        server.join().unwrap();
+    }
+
+    /// Test that sequence numbers are split from messages once enabled, and
+    /// that messages without one are passed on unchanged.
+    #[test]
+    fn test_listen_sequenced() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"\x031 news\x1fearly\n").unwrap();
+            assert_eq!(read_command(&mut stream), "SEQUENCE");
+            stream
+                .write_all(b"SEQUENCE ON\n\x033 news\x1f\x01closing\n\x021 hi\n")
+                .unwrap();
+        });
+
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let mut received = Vec::new();
+        client
+            .receive_with(|message| received.push((None, message.clone())))
+            .unwrap();
+        client.enable_sequence_numbers().unwrap();
+        let result = client.listen_sequenced(|sequence, message| {
+            received.push((sequence.map(|s| s.number), message.clone()));
+        });
+        server.join().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(
+            received,
+            vec![
+                (None, Message::Regular("\u{3}1 news\u{1f}early".to_string())),
+                (Some(3), Message::System("closing".to_string())),
+                (
+                    None,
+                    Message::Direct {
+                        from: "1".to_string(),
+                        text: "hi".to_string()
+                    }
+                ),
+            ]
+        );
+    }
+
+    /// Test that a health check reports the version and latency of a server,
+    /// and tells apart the ways a server can fail it.
+    #[test]
+    fn test_health_check() {
+        let timeout = Duration::from_millis(500);
+        let check = |replies: &'static [u8]| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                assert_eq!(read_command(&mut stream), "VERSION");
+                assert_eq!(read_command(&mut stream), "PING health");
+                stream.write_all(replies).unwrap();
+                // Hold the connection open until the client is done.
+                let _ = stream.read(&mut [0; 1]);
+            });
+            let result = Client::health_check("127.0.0.1", port, timeout);
+            server.join().unwrap();
+            result
+        };
+
+        let report = check(b"VERSION 1.2.3\nPONG health\n").unwrap();
+        assert_eq!(report.server_version.as_deref(), Some("1.2.3"));
+        assert!(report.latency < timeout);
+        let report = check(b"PONG health\n").unwrap();
+        assert_eq!(report.server_version, None);
+        assert!(matches!(
+            check(b"HTTP/1.1 400 Bad Request\n"),
+            Err(HealthError::BadPong(reply)) if reply == "HTTP/1.1 400 Bad Request"
+        ));
+        assert!(matches!(check(b""), Err(HealthError::Timeout)));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        assert!(matches!(
+            Client::health_check("127.0.0.1", port, timeout),
+            Err(HealthError::Refused)
+        ));
+    }
+
+    /// Test that channels are listed until the end marker, keeping messages
+    /// which arrive first.
+    #[test]
+    fn test_list_channels() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .write_all(b"hello\nCHANNEL news\nCHANNEL sport\nEND\n")
+            .unwrap();
+
+        assert_eq!(client.list_channels().unwrap(), vec!["news", "sport"]);
+        assert_eq!(read_command(&mut stream), "LIST");
+        assert_eq!(client.pending, vec!["hello"]);
+    }
+
+    /// Test that sending reports an error, rather than panicking, when the
+    /// client is not connected or the server has closed the connection.
+    #[test]
+    fn test_send_errors() {
+        let mut client = Client::disconnected("127.0.0.1".to_string(), 0);
+        assert_eq!(
+            client.send("PING".to_string()).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        drop(listener.accept().unwrap());
+        // The first writes may be buffered before the reset arrives.
+        let failed = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(1));
+            client
+                .publish("news".to_string(), "hello".to_string())
+                .is_err()
+        });
+        assert!(failed);
+        #[allow(deprecated)]
+        let sent = client.send_lossy("PING".to_string());
+        assert!(!sent);
+    }
+
+    /// Test that the length in each header covers exactly the bytes of the
+    /// command, for commands either side of common buffer sizes.
+    #[test]
+    fn test_send_length_boundaries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut commands = Vec::new();
+        for size in [0, 1, 63, 64, 65, 127, 128] {
+            commands.push("x".repeat(size));
+            commands.push(format!("{}{}", "é".repeat(size / 2), "x".repeat(size % 2)));
+        }
+        for command in &commands {
+            client.send(command.to_string()).unwrap();
+        }
+        client.publish("news".to_string(), "x".repeat(64)).unwrap();
+        for command in &commands {
+            assert_eq!(&read_command(&mut stream), command);
+        }
+        assert_eq!(
+            read_command(&mut stream),
+            format!("PUBLISH news\u{1f}{}", "x".repeat(64))
+        );
+    }
+
+    /// Test that addresses are parsed with or without a port, including
+    /// IPv6 addresses, and that malformed addresses are rejected.
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint = |host: &str, port| Ok((host.to_string(), port));
+        assert_eq!(
+            Client::parse_endpoint("localhost"),
+            endpoint("localhost", 7878)
+        );
+        assert_eq!(
+            Client::parse_endpoint("example.com:80"),
+            endpoint("example.com", 80)
+        );
+        assert_eq!(Client::parse_endpoint("[::1]:8000"), endpoint("::1", 8000));
+        assert_eq!(Client::parse_endpoint("[::1]"), endpoint("::1", 7878));
+        assert_eq!(Client::parse_endpoint("::1"), endpoint("::1", 7878));
+        for address in ["", ":80", "host:port", "host:99999", "[::1", "[::1]80"] {
+            assert!(Client::parse_endpoint(address).is_err(), "{}", address);
+        }
+    }
+
+    /// Test that a client connects to an IPv6 server given its address.
+    #[test]
+    fn test_connect_ipv6() {
+        let listener = match TcpListener::bind("[::1]:0") {
+            Ok(listener) => listener,
+            // IPv6 is not available everywhere.
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+        let mut client = format!("[::1]:{}", port).parse::<Client>().unwrap();
+        client.connect().unwrap();
+        listener.accept().unwrap();
+    }
+
+    /// Test that the client keeps reading text when the server downgrades
+    /// the binary encoding.
+    #[test]
+    fn test_negotiate_encoding_downgraded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"ENCODING TEXT\n").unwrap();
+
+        let encoding = client.negotiate_encoding(Encoding::Binary).unwrap();
+        assert_eq!(encoding, Encoding::Text);
+        assert_eq!(client.encoding(), Encoding::Text);
+    }
+
+    /// Test that draining discards every message which has already arrived.
+    #[test]
+    fn test_drain() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        stream.write_all(b"first\nsecond\nthird\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.drain().unwrap(), 3);
+        assert_eq!(client.drain().unwrap(), 0);
+    }
+
+    /// Test that reading from a client which is not connected fails rather
+    /// than panicking.
+    #[test]
+    fn test_read_not_connected() {
+        let mut client = "127.0.0.1:7878".parse::<Client>().unwrap();
+        assert_eq!(
+            client.drain().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(
+            client
+                .receive_line_timeout(Duration::from_millis(10))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+
+    /// Test that a client record is parsed with and without a name.
+    #[test]
+    fn test_client_info_from_str() {
+        let info = "CLIENT 1 127.0.0.1:5000 2 my client"
+            .parse::<ClientInfo>()
+            .unwrap();
+        assert_eq!(
+            info,
+            ClientInfo {
+                id: "1".to_string(),
+                name: Some("my client".to_string()),
+                addr: "127.0.0.1:5000".to_string(),
+                subscriptions: 2,
+            }
+        );
+
+        let info = "CLIENT 1 127.0.0.1:5000 0".parse::<ClientInfo>().unwrap();
+        assert_eq!(info.name, None);
+    }
+
+    /// Test that empty and whitespace only channel names are invalid.
+    #[test]
+    fn test_validate_channel() {
+        assert!(validate_channel("", CHANNEL_SEPARATOR).is_err());
+        assert!(validate_channel("   ", CHANNEL_SEPARATOR).is_err());
+        assert!(validate_channel("te\u{1f}st", CHANNEL_SEPARATOR).is_err());
+        assert!(validate_channel("te|st", '|').is_err());
+        assert!(validate_channel("my test", CHANNEL_SEPARATOR).is_ok());
+    }
+
+    /// Test that the channel and message are joined with the configured
+    /// separator, and that channels containing it are not published to.
+    #[test]
+    fn test_publish_channel_separator() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        client
+            .publish("my news".to_string(), "\u{1f}hello".to_string())
+            .unwrap();
+        assert_eq!(
+            client
+                .publish("a\u{1f}b".to_string(), "hello".to_string())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        client.set_channel_separator('|');
+        client
+            .publish("news".to_string(), "|hello".to_string())
+            .unwrap();
+        assert_eq!(
+            client
+                .publish_system("a|b".to_string(), "hello".to_string())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            read_command(&mut stream),
+            "PUBLISH my news\u{1f}\u{1f}hello"
+        );
+        assert_eq!(read_command(&mut stream), "PUBLISH news||hello");
+    }
+
+    /// Test that system messages are told apart from regular messages.
+    #[test]
+    fn test_message_from_line() {
+        assert_eq!(
+            Message::from_line("hello".to_string()),
+            Message::Regular("hello".to_string())
+        );
+        assert_eq!(
+            Message::from_line("\u{1}closing".to_string()),
+            Message::System("closing".to_string())
+        );
+        assert_eq!(
+            Message::from_line("\u{1}closing".to_string()).text(),
+            "closing"
+        );
+        assert_eq!(
+            Message::from_line("\u{2}42 hello there".to_string()),
+            Message::Direct {
+                from: "42".to_string(),
+                text: "hello there".to_string()
+            }
+        );
+        assert_eq!(
+            Message::from_line("\u{4}7 ping".to_string()),
+            Message::Request {
+                id: "7".to_string(),
+                text: "ping".to_string()
+            }
+        );
+    }
+
+    /// Test that direct messages report whether the recipient was found,
+    /// keeping messages which arrive before the reply.
+    #[test]
+    fn test_send_to() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new("127.0.0.1".to_string(), port);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .write_all(b"update\nDELIVERED 42\nNOT_FOUND 7\nERR unauthorized\n")
+            .unwrap();
+
+        assert!(client.send_to("42", "hello").unwrap());
+        assert!(!client.send_to("7", "hello").unwrap());
+        assert!(client.send_to("42", "hello").is_err());
+        assert_eq!(read_command(&mut stream), "SENDTO 42 hello");
+        assert_eq!(client.pending, VecDeque::from(["update".to_string()]));
+    }
+
+    /// Test that a priority survives being formatted and parsed.
+    #[test]
+    fn test_priority_round_trip() {
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            assert_eq!(priority.to_string().parse::<Priority>(), Ok(priority));
+        }
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+
+    /// Test that an error reply is not parsed as a client record.
+    #[test]
+    fn test_client_info_from_str_error() {
+        assert!("ERR unauthorized".parse::<ClientInfo>().is_err());
+        assert!("CLIENT 1".parse::<ClientInfo>().is_err());
+    }
 }
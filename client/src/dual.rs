@@ -0,0 +1,161 @@
+//! # Dual Connection Client
+//! This library contains a client which talks to a server over two
+//! connections, one for publishing and commands and one dedicated to
+//! delivering the messages of its subscriptions.
+//! On a single connection, replies to commands arrive interleaved with channel
+//! messages and have to be told apart. With a dedicated connection the
+//! messages being listened to never contain a reply.
+
+use crate::client::Client;
+use std::io;
+
+/// A client holding separate connections for publishing and subscribing to
+/// the same server.
+///
+/// Prefer it over a single `Client` when an application both publishes, or
+/// sends commands which are replied to, and listens for messages, especially
+/// from different threads, see `split`. A single `Client` is enough, and uses
+/// half the connections, for applications which only publish or only listen.
+#[derive(Debug)]
+pub struct DualClient {
+    publisher: Client,
+    subscriber: Client,
+}
+
+impl DualClient {
+    /// Opens both connections to a server.
+    /// # Arguments
+    /// * `host` - The hostname of the server.
+    /// * `port` - The port of the server.
+    /// # Returns
+    /// The connected client, or the error from the connection which could not
+    /// be opened.
+    pub fn connect(host: String, port: u16) -> io::Result<DualClient> {
+        let subscriber = Client::try_new(host.clone(), port)?;
+        let publisher = Client::try_new(host, port)?;
+        Ok(DualClient {
+            publisher,
+            subscriber,
+        })
+    }
+
+    /// Gets the connection used for publishing and commands, for commands
+    /// which `DualClient` does not wrap, such as `Client::authenticate`.
+    pub fn publisher(&mut self) -> &mut Client {
+        &mut self.publisher
+    }
+
+    /// Gets the connection messages are delivered on.
+    pub fn subscriber(&mut self) -> &mut Client {
+        &mut self.subscriber
+    }
+
+    /// Separates the two connections, so that messages can be published on
+    /// one thread while another listens.
+    /// # Returns
+    /// The publishing connection followed by the subscribing connection.
+    pub fn split(self) -> (Client, Client) {
+        (self.publisher, self.subscriber)
+    }
+
+    /// Publishes a message to a channel over the publishing connection.
+    /// # Arguments
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish.
+    /// # Returns
+    /// An error if the message could not be published, see `Client::publish`.
+    pub fn publish(&mut self, channel: String, message: String) -> io::Result<()> {
+        self.publisher.publish(channel, message)
+    }
+
+    /// Subscribes the subscribing connection to a channel.
+    /// # Arguments
+    /// * `channel` - The channel to subscribe to.
+    /// # Returns
+    /// An error if the subscription could not be sent, see
+    /// `Client::subscribe`.
+    pub fn subscribe(&mut self, channel: String) -> io::Result<()> {
+        self.subscriber.subscribe(channel)
+    }
+
+    /// Unsubscribes the subscribing connection from a channel.
+    /// # Arguments
+    /// * `channel` - The channel to unsubscribe from.
+    /// # Returns
+    /// An error if the unsubscription could not be sent.
+    pub fn unsubscribe(&mut self, channel: String) -> io::Result<()> {
+        self.subscriber.unsubscribe(channel)
+    }
+
+    /// Listens for messages on the subscribing connection, see
+    /// `Client::listen`.
+    /// # Arguments
+    /// * `callback` - The function to call when a message is received.
+    pub fn listen(&mut self, callback: impl FnMut(&String)) -> io::Result<()> {
+        self.subscriber.listen(callback)
+    }
+
+    /// Disconnects both connections from the server.
+    /// # Returns
+    /// The first error disconnecting. Both connections are disconnected
+    /// either way.
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        let publisher = self.publisher.disconnect();
+        let subscriber = self.subscriber.disconnect();
+        publisher.and(subscriber)
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Reads a single framed command sent by a client.
+    fn read_command(stream: &mut TcpStream) -> String {
+        String::from_utf8(protocol::read_frame(stream).unwrap()).unwrap()
+    }
+
+    /// Test that subscriptions and publishes are sent over separate
+    /// connections.
+    #[test]
+    fn test_separate_connections() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut client = DualClient::connect("127.0.0.1".to_string(), port).unwrap();
+        let (mut subscriber, _) = server.accept().unwrap();
+        let (mut publisher, _) = server.accept().unwrap();
+
+        client.subscribe("news".to_string()).unwrap();
+        client
+            .publish("news".to_string(), "hello".to_string())
+            .unwrap();
+        client.unsubscribe("news".to_string()).unwrap();
+        // Each connection waits for its disconnection to be confirmed.
+        let server = thread::spawn(move || {
+            let mut commands = Vec::new();
+            for stream in [&mut publisher, &mut subscriber] {
+                let mut received = Vec::new();
+                while received.last().map(String::as_str) != Some("DISCONNECT") {
+                    received.push(read_command(stream));
+                }
+                stream.write_all(b"BYE\n").unwrap();
+                commands.push(received);
+            }
+            commands
+        });
+        client.disconnect().unwrap();
+
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                vec!["PUBLISH news\u{1f}hello", "DISCONNECT"],
+                vec!["SUBSCRIBE news", "UNSUBSCRIBE news", "DISCONNECT"]
+            ]
+        );
+    }
+}
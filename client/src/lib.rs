@@ -1,2 +1,7 @@
 pub mod client;
 pub mod cli;
+pub mod dual;
+pub mod pool;
+pub use common::protocol;
+pub use common::stream;
+pub mod tls;
@@ -4,11 +4,46 @@
 //! messages to and from the server.
 
 use client::cli::{Options, Parser};
-use client::client::Client;
+use client::client::{Client, ClientBuilder};
+use client::tls;
+use std::{process, time::Duration};
 
 fn main() {
     let options = Options::new();
-    let mut client = Client::new(options.host.clone(), options.port.clone());
+    let (host, port) = options.endpoint();
+    if options.health {
+        let timeout = Duration::from_millis(options.health_timeout);
+        match Client::health_check(&host, port, timeout) {
+            Ok(report) => println!(
+                "OK latency={:?} version={}",
+                report.latency,
+                report.server_version.as_deref().unwrap_or("unknown")
+            ),
+            Err(e) => {
+                eprintln!("UNHEALTHY {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let mut builder = ClientBuilder::new(host.clone(), port).encoding(options.encoding);
+    if let Some(separator) = options.channel_separator {
+        builder = builder.channel_separator(separator);
+    }
+    if let Some(timeout) = options.timeout {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+    if options.tls {
+        let config = tls::client_config(options.tls_ca.as_deref()).unwrap_or_else(|e| {
+            eprintln!("could not load the TLS configuration: {}", e);
+            process::exit(1);
+        });
+        builder = builder.tls(config);
+    }
+    let mut client = builder.build().unwrap_or_else(|e| {
+        eprintln!("could not connect to {}:{}: {}", host, port, e);
+        process::exit(1);
+    });
     let mut parser = Parser::new(&options, &mut client);
     parser.parse_args();
 }
@@ -0,0 +1,146 @@
+//! # Client Pool
+//! This library contains a pool of named clients, for applications which talk
+//! to several servers, for example when channels are sharded across servers.
+
+use crate::client::Client;
+use std::{collections::HashMap, error::Error};
+
+/// A set of clients, each connected to a different server and addressed by
+/// name.
+#[derive(Default)]
+pub struct ClientPool {
+    clients: HashMap<String, Client>,
+}
+
+impl ClientPool {
+    /// Creates an empty pool.
+    pub fn new() -> ClientPool {
+        ClientPool::default()
+    }
+
+    /// Connects to a server and adds the connection to the pool.
+    /// # Arguments
+    /// * `name` - The name to address the server by.
+    /// * `address` - The address of the server, see `Client::parse_endpoint`.
+    pub fn connect(&mut self, name: &str, address: &str) -> Result<(), Box<dyn Error>> {
+        let mut client = address.parse::<Client>()?;
+        client.reconnect()?;
+        self.add(name, client);
+        Ok(())
+    }
+
+    /// Adds a client to the pool, replacing any client with the same name.
+    /// # Arguments
+    /// * `name` - The name to address the client's server by.
+    /// * `client` - The client.
+    pub fn add(&mut self, name: &str, client: Client) {
+        self.clients.insert(name.to_string(), client);
+    }
+
+    /// Removes a client from the pool.
+    /// # Arguments
+    /// * `name` - The name of the client's server.
+    /// # Returns
+    /// The client, if it was in the pool.
+    pub fn remove(&mut self, name: &str) -> Option<Client> {
+        self.clients.remove(name)
+    }
+
+    /// Gets a client from the pool.
+    /// # Arguments
+    /// * `name` - The name of the client's server.
+    pub fn get(&mut self, name: &str) -> Option<&mut Client> {
+        self.clients.get_mut(name)
+    }
+
+    /// Gets the names of the servers in the pool.
+    pub fn names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.clients.keys().collect();
+        names.sort();
+        names
+    }
+
+    /// Publishes a message to a channel on one of the servers. If the message
+    /// cannot be sent and the client has a reconnect policy, see
+    /// `Client::set_auto_reconnect`, the client reconnects and sends it again
+    /// as `Client::publish` does. The other clients in the pool are
+    /// unaffected.
+    /// # Arguments
+    /// * `name` - The name of the server to publish to.
+    /// * `channel` - The channel to publish to.
+    /// * `message` - The message to publish.
+    pub fn publish(
+        &mut self,
+        name: &str,
+        channel: &str,
+        message: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let client = self
+            .clients
+            .get_mut(name)
+            .ok_or_else(|| format!("Unknown server: {}", name))?;
+
+        client.publish(channel.to_string(), message.to_string())?;
+        Ok(())
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use std::io;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Reads a single framed command sent by a client.
+    fn read_command(stream: &mut TcpStream) -> String {
+        String::from_utf8(protocol::read_frame(stream).unwrap()).unwrap()
+    }
+
+    /// Test that messages are published to the named server only.
+    #[test]
+    fn test_publish_routes_by_name() {
+        let server_a = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_b = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = ClientPool::new();
+        pool.connect("a", &server_a.local_addr().unwrap().to_string())
+            .unwrap();
+        pool.connect("b", &server_b.local_addr().unwrap().to_string())
+            .unwrap();
+        assert_eq!(pool.names(), vec!["a", "b"]);
+
+        pool.publish("b", "news", "to b").unwrap();
+        pool.publish("a", "news", "to a").unwrap();
+
+        let (mut stream_a, _) = server_a.accept().unwrap();
+        let (mut stream_b, _) = server_b.accept().unwrap();
+        assert_eq!(read_command(&mut stream_a), "PUBLISH news\u{1f}to a");
+        assert_eq!(read_command(&mut stream_b), "PUBLISH news\u{1f}to b");
+    }
+
+    /// Test that publishing to a server that is not in the pool fails.
+    #[test]
+    fn test_publish_unknown_server() {
+        let mut pool = ClientPool::new();
+        assert!(pool.publish("missing", "news", "hello").is_err());
+    }
+
+    /// Test that the error publishing fails with is returned as it is, rather
+    /// than reconnecting, and that the connection is kept.
+    #[test]
+    fn test_publish_error() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = ClientPool::new();
+        pool.connect("a", &server.local_addr().unwrap().to_string())
+            .unwrap();
+        let (mut stream, _) = server.accept().unwrap();
+
+        let error = pool.publish("a", "ne\u{1f}ws", "hello").unwrap_err();
+        let error = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        pool.publish("a", "news", "hello").unwrap();
+        assert_eq!(read_command(&mut stream), "PUBLISH news\u{1f}hello");
+    }
+}
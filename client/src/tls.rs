@@ -0,0 +1,84 @@
+//! # TLS
+//! This library contains the optional TLS layer of the client, which encrypts
+//! the connection to servers started with `--tls-cert`.
+//! The client reads and writes the resulting `TlsStream` just as it does a
+//! plain `TcpStream`, see `stream::Stream`.
+
+use crate::stream::TlsStream;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+/// Creates the TLS configuration of a client.
+/// # Arguments
+/// * `ca` - A PEM file holding the certificates of the authorities trusted to
+///   sign the server's certificate, such as a private CA or a self-signed
+///   certificate. The Mozilla root certificates are trusted when not given.
+/// # Returns
+/// The configuration, or an error if the file cannot be read or holds no
+/// usable certificate.
+pub fn client_config(ca: Option<&Path>) -> io::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots
+                    .add(cert?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("No certificate found in {}", path.display()),
+                ));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Completes the TLS handshake with a server. Any timeouts set on the
+/// connection bound the handshake.
+/// # Arguments
+/// * `config` - The TLS configuration of the client.
+/// * `host` - The hostname of the server, which its certificate must be valid
+///   for.
+/// * `stream` - The connection to the server.
+/// # Returns
+/// The encrypted connection, or an error if the handshake failed.
+pub fn connect(
+    config: Arc<rustls::ClientConfig>,
+    host: &str,
+    stream: TcpStream,
+) -> io::Result<TlsStream> {
+    let name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let connection = rustls::ClientConnection::new(config, name).map_err(io::Error::other)?;
+    TlsStream::new(connection.into(), stream)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Test that a CA file without a certificate is refused, naming the file.
+    #[test]
+    fn test_missing_certificate() {
+        let path = std::env::temp_dir().join(format!("tls-ca-empty-{}.pem", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let error = client_config(Some(&path)).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("No certificate found"));
+    }
+}
@@ -0,0 +1,571 @@
+//! # Server Round Trips
+//! These tests run the client library against a real server listening on an
+//! ephemeral port.
+
+use client::cli::{Options, Parser};
+use client::client::{Client, ClientBuilder, Encoding, Message, ReconnectPolicy};
+use server::server::{RunningServer, ServerBuilder};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// Starts a server on a free port.
+fn start_server() -> RunningServer {
+    ServerBuilder::new("127.0.0.1".to_string(), 0)
+        .start()
+        .unwrap()
+}
+
+/// Connects a client to a server.
+fn connect(server: &RunningServer) -> Client {
+    Client::try_new("127.0.0.1".to_string(), server.local_addr().port()).unwrap()
+}
+
+/// Test that the subscribers of a channel are counted without subscribing,
+/// and that a channel nobody has subscribed to has none.
+#[test]
+fn test_count() {
+    let server = start_server();
+    let mut subscribers = vec![connect(&server), connect(&server)];
+    for subscriber in &mut subscribers {
+        subscriber
+            .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+            .unwrap();
+    }
+
+    let mut client = connect(&server);
+    assert_eq!(client.count("news".to_string()).unwrap(), 2);
+    assert_eq!(client.count("unknown".to_string()).unwrap(), 0);
+    assert_eq!(client.count("news".to_string()).unwrap(), 2);
+    server.shutdown();
+}
+
+/// Test that the server's counters follow clients connecting, subscribing,
+/// publishing and disconnecting.
+#[test]
+fn test_stats() {
+    let server = start_server();
+    let mut subscriber = connect(&server);
+    subscriber
+        .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+        .unwrap();
+    let mut publisher = connect(&server);
+    publisher.set_check_errors(true);
+    publisher
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap();
+    publisher
+        .publish("sport".to_string(), "goal".to_string())
+        .unwrap();
+
+    let stats = publisher.stats().unwrap();
+    assert_eq!(stats.total_connections, 2);
+    assert_eq!(stats.current_connections, 2);
+    assert_eq!(stats.channels, 1);
+    assert_eq!(stats.messages_published, 2);
+    assert_eq!(stats.bytes_published, 9);
+
+    drop(subscriber);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.stats().current_connections > 1 {
+        assert!(Instant::now() < deadline, "the disconnect was not counted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(publisher.stats().unwrap().total_connections, 2);
+    server.shutdown();
+}
+
+/// Test that once a client has disconnected the server no longer lists it,
+/// and that disconnecting without waiting still disconnects the client.
+#[test]
+fn test_disconnect() {
+    let config = server::config::Config {
+        admin_token: Some("admin".to_string()),
+        ..Default::default()
+    };
+    let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+        .config(config)
+        .start()
+        .unwrap();
+    let mut observer = connect(&server);
+    observer.authenticate("admin".to_string()).unwrap();
+    let mut client = connect(&server);
+    let id = client.whoami().unwrap();
+    client
+        .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+        .unwrap();
+    client.disconnect().unwrap();
+    let ids: Vec<String> = observer
+        .list_clients()
+        .unwrap()
+        .into_iter()
+        .map(|client| client.id)
+        .collect();
+    assert!(!ids.contains(&id));
+    assert!(server.channels().is_empty());
+
+    let mut client = connect(&server);
+    client.disconnect_nowait().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while observer.list_clients().unwrap().len() > 1 {
+        assert!(Instant::now() < deadline, "the client was not removed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown();
+}
+
+/// Test that a client is told the id the server knows it by, which stays the
+/// same on the same connection and differs between connections.
+#[test]
+fn test_whoami() {
+    let server = start_server();
+    let mut client = connect(&server);
+    let id = client.whoami().unwrap();
+    assert!(!id.is_empty());
+    assert_eq!(client.whoami().unwrap(), id);
+    assert_ne!(connect(&server).whoami().unwrap(), id);
+    server.shutdown();
+}
+
+/// Test that messages published on channels matching a pattern are received
+/// with their channel, and that other channels are not.
+#[test]
+fn test_psubscribe() {
+    let server = start_server();
+    let mut subscriber = connect(&server);
+    subscriber.psubscribe("sensors.#".to_string()).unwrap();
+    // The reply to the ping follows the subscription being in place.
+    subscriber.ping().unwrap();
+
+    let mut publisher = connect(&server);
+    for (channel, message) in [("lights.kitchen", "on"), ("sensors.kitchen.temp", "21")] {
+        publisher
+            .publish(channel.to_string(), message.to_string())
+            .unwrap();
+    }
+
+    let message = subscriber
+        .subscribe_once("unrelated".to_string(), Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(message, Some("sensors.kitchen.temp\u{1f}21".to_string()));
+    server.shutdown();
+}
+
+/// Test that a client subscribing after a message is retained receives it.
+#[test]
+fn test_publish_retained() {
+    let server = start_server();
+    let mut publisher = connect(&server);
+    publisher
+        .publish_retained("status".to_string(), "online".to_string())
+        .unwrap();
+    // The reply to the ping follows the message being retained.
+    publisher.ping().unwrap();
+
+    let mut subscriber = connect(&server);
+    let message = subscriber
+        .subscribe_once("status".to_string(), Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(message, Some("online".to_string()));
+    server.shutdown();
+}
+
+/// Test that the message retained on a channel is read without subscribing,
+/// and that nothing is read from a channel without one.
+#[test]
+fn test_get_retained() {
+    let server = start_server();
+    let mut publisher = connect(&server);
+    publisher
+        .publish_retained("status".to_string(), "online".to_string())
+        .unwrap();
+    publisher.ping().unwrap();
+
+    let mut client = connect(&server);
+    assert_eq!(
+        client.get_retained("status".to_string()).unwrap(),
+        Some(b"online".to_vec())
+    );
+    assert_eq!(client.get_retained("stat".to_string()).unwrap(), None);
+    assert_eq!(client.count("status".to_string()).unwrap(), 0);
+    server.shutdown();
+}
+
+/// Test that a request published by one client is replied to by another, and
+/// that the reply is returned to the requester.
+#[test]
+fn test_request_reply() {
+    let server = start_server();
+    let mut responder = connect(&server);
+    responder
+        .subscribe_with_ack("time".to_string(), Duration::from_secs(5))
+        .unwrap();
+    let responder = thread::spawn(move || {
+        let mut request = None;
+        responder
+            .receive_message(|message| request = Some(message.clone()))
+            .unwrap();
+        let Some(Message::Request { id, text }) = request else {
+            panic!("expected a request, got {:?}", request);
+        };
+        responder.reply(&id, &text.to_uppercase()).unwrap();
+        responder
+    });
+
+    let mut requester = connect(&server);
+    let reply = requester
+        .request("time".to_string(), "now?".to_string())
+        .unwrap();
+    assert_eq!(reply, "NOW?");
+    responder.join().unwrap();
+    server.shutdown();
+}
+
+/// Test that a multi-line message is received intact, and only once, by a
+/// subscriber using the binary encoding.
+#[test]
+fn test_multiline_message() {
+    let server = start_server();
+    let mut subscriber = ClientBuilder::new("127.0.0.1".to_string(), server.local_addr().port())
+        .encoding(Encoding::Binary)
+        .build()
+        .unwrap();
+    assert_eq!(subscriber.encoding(), Encoding::Binary);
+    subscriber
+        .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+        .unwrap();
+
+    let message = "first line\nsecond line\n\nlast line";
+    let mut publisher = connect(&server);
+    publisher
+        .publish("news".to_string(), message.to_string())
+        .unwrap();
+    publisher
+        .publish("news".to_string(), "next".to_string())
+        .unwrap();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        subscriber
+            .receive_message(|message| received.push(message.clone()))
+            .unwrap();
+    }
+    assert_eq!(
+        received,
+        vec![
+            Message::Regular(message.to_string()),
+            Message::Regular("next".to_string())
+        ]
+    );
+    server.shutdown();
+}
+
+/// Test that a message which is not UTF-8 is received byte for byte by a
+/// subscriber using the binary encoding, and that listening for bytes needs
+/// the binary encoding.
+#[test]
+fn test_binary_payload() {
+    let server = start_server();
+    let mut subscriber = ClientBuilder::new("127.0.0.1".to_string(), server.local_addr().port())
+        .encoding(Encoding::Binary)
+        .build()
+        .unwrap();
+    subscriber
+        .subscribe_with_ack("images".to_string(), Duration::from_secs(5))
+        .unwrap();
+    let (received, receiver) = mpsc::channel();
+    let listener = thread::spawn(move || {
+        let _ = subscriber.listen_bytes(|message| {
+            let _ = received.send(message.to_vec());
+        });
+    });
+
+    let payload = [0xff, 0x00, b'\n', 0xfe, b' ', 0x80];
+    let mut publisher = connect(&server);
+    publisher.set_check_errors(true);
+    publisher.publish_bytes("images", &payload).unwrap();
+    let message = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(message, payload);
+
+    let error = publisher.listen_bytes(|_| ()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    server.shutdown();
+    listener.join().unwrap();
+}
+
+/// Test that a client subscribing and listening receives messages published
+/// on its channels by another client, with the channel of each.
+#[test]
+fn test_subscribe_and_listen() {
+    let server = start_server();
+    let mut subscriber = connect(&server);
+    let (received, receiver) = mpsc::channel();
+    let listener = thread::spawn(move || {
+        let channels = vec!["news".to_string(), "sport".to_string()];
+        let _ = subscriber.subscribe_and_listen(
+            channels,
+            Duration::from_secs(5),
+            |channel, message| {
+                let _ = received.send((channel.map(str::to_string), message.clone()));
+            },
+        );
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.channels().len() < 2 {
+        assert!(Instant::now() < deadline, "the client did not subscribe");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut publisher = connect(&server);
+    publisher
+        .publish("sport".to_string(), "goal".to_string())
+        .unwrap();
+    publisher
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap();
+    let messages: Vec<_> = receiver.iter().take(2).collect();
+    assert_eq!(
+        messages,
+        vec![
+            (
+                Some("sport".to_string()),
+                Message::Regular("goal".to_string())
+            ),
+            (
+                Some("news".to_string()),
+                Message::Regular("hello".to_string())
+            )
+        ]
+    );
+    server.shutdown();
+    listener.join().unwrap();
+}
+
+/// Test that a message given to the CLI with `--msg-file` is published
+/// exactly as the file holds it.
+#[test]
+fn test_publish_msg_file() {
+    let server = start_server();
+    let mut subscriber = ClientBuilder::new("127.0.0.1".to_string(), server.local_addr().port())
+        .encoding(Encoding::Binary)
+        .build()
+        .unwrap();
+    subscriber
+        .subscribe_with_ack("files".to_string(), Duration::from_secs(5))
+        .unwrap();
+    let (received, receiver) = mpsc::channel();
+    let listener = thread::spawn(move || {
+        let _ = subscriber.listen_bytes(|message| {
+            let _ = received.send(message.to_vec());
+        });
+    });
+
+    let payload = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0x00];
+    let path = std::env::temp_dir().join(format!("msg-file-{}.png", std::process::id()));
+    fs::write(&path, payload).unwrap();
+    let options = Options::from_iter([
+        "client",
+        "-c",
+        "files",
+        "--msg-file",
+        path.to_str().unwrap(),
+    ]);
+    Parser::new(&options, &mut connect(&server)).parse_args();
+    fs::remove_file(&path).unwrap();
+
+    let message = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(message, payload);
+    server.shutdown();
+    listener.join().unwrap();
+}
+
+/// Test that a broadcast is received by every connected client, whatever
+/// they are subscribed to.
+#[test]
+fn test_broadcast() {
+    let server = start_server();
+    let mut clients = vec![connect(&server), connect(&server), connect(&server)];
+    clients[1]
+        .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+        .unwrap();
+    clients[0].set_check_errors(true);
+    clients[0].broadcast("hello everyone".to_string()).unwrap();
+
+    for client in &mut clients {
+        let mut message = None;
+        client
+            .receive_message(|received| message = Some(received.clone()))
+            .unwrap();
+        assert_eq!(
+            message,
+            Some(Message::Regular("hello everyone".to_string()))
+        );
+    }
+    server.shutdown();
+}
+
+/// Gets a file from the TLS fixtures, a test CA and a certificate for
+/// `localhost` and `127.0.0.1` signed by it.
+fn tls_fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+/// Test that messages are published and received over TLS, and that a client
+/// which does not trust the server's certificate cannot connect.
+#[test]
+fn test_tls() {
+    let config =
+        server::tls::server_config(&tls_fixture("server.pem"), &tls_fixture("server.key")).unwrap();
+    let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+        .tls(config)
+        .start()
+        .unwrap();
+    let port = server.local_addr().port();
+    let tls = client::tls::client_config(Some(&tls_fixture("ca.pem"))).unwrap();
+    let connect = || {
+        ClientBuilder::new("127.0.0.1".to_string(), port)
+            .tls(tls.clone())
+            .build()
+            .unwrap()
+    };
+
+    let mut subscriber = connect();
+    subscriber
+        .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+        .unwrap();
+    connect()
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap();
+    let mut message = None;
+    subscriber
+        .receive_message(|received| message = Some(received.clone()))
+        .unwrap();
+    assert_eq!(message, Some(Message::Regular("hello".to_string())));
+
+    let untrusted = ClientBuilder::new("127.0.0.1".to_string(), port)
+        .tls(client::tls::client_config(None).unwrap())
+        .build();
+    assert!(untrusted.is_err());
+    server.shutdown();
+}
+
+/// Test that a server with auth tokens refuses to publish for a client until
+/// it has authenticated, and that a wrong token is refused.
+#[test]
+fn test_authenticate() {
+    let config = server::config::Config {
+        auth_tokens: ["letmein".to_string()].into(),
+        ..Default::default()
+    };
+    let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+        .config(config)
+        .start()
+        .unwrap();
+    let mut client = connect(&server);
+    client.set_check_errors(true);
+    let error = client
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "The server refused: ERR unauthorized");
+
+    assert!(client.authenticate("wrong".to_string()).is_err());
+    client.authenticate("letmein".to_string()).unwrap();
+    client
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap();
+    server.shutdown();
+}
+
+/// Test that errors replied to commands are returned when the client checks
+/// for them.
+#[test]
+fn test_check_errors() {
+    let server = start_server();
+    let mut client = connect(&server);
+    client.set_check_errors(true);
+
+    client
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap();
+    let error = client
+        .publish("$presence".to_string(), "hello".to_string())
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The server refused: ERR reserved channel"
+    );
+    client.subscribe("news".to_string()).unwrap();
+    server.shutdown();
+}
+
+/// Test that publishing several messages at once reports the first message
+/// the server refused when the client checks for errors.
+#[test]
+fn test_publish_iter_check_errors() {
+    let server = start_server();
+    let mut client = connect(&server);
+    client.set_check_errors(true);
+
+    let messages = ["first", "second"].map(String::from);
+    assert_eq!(client.publish_iter("news", messages).unwrap(), 2);
+    let error = client
+        .publish_iter("$presence", ["hello".to_string()])
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The server refused: ERR reserved channel"
+    );
+    server.shutdown();
+}
+
+/// Test that a listening client reconnects once the server has restarted,
+/// subscribing again so that it receives messages published afterwards.
+#[test]
+fn test_reconnect_after_restart() {
+    let server = start_server();
+    let port = server.local_addr().port();
+    let mut subscriber = ClientBuilder::new("127.0.0.1".to_string(), port)
+        .auto_reconnect(ReconnectPolicy {
+            max_attempts: 8,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(200),
+        })
+        .build()
+        .unwrap();
+    subscriber
+        .subscribe_with_ack("news".to_string(), Duration::from_secs(5))
+        .unwrap();
+    let (received, receiver) = mpsc::channel();
+    let listener = thread::spawn(move || {
+        let _ = subscriber.listen(|message| {
+            let _ = received.send(message.clone());
+        });
+    });
+
+    server.shutdown();
+    let server = ServerBuilder::new("127.0.0.1".to_string(), port)
+        .start()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.channels() != vec![("news".to_string(), 1)] {
+        assert!(Instant::now() < deadline, "the client did not resubscribe");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    connect(&server)
+        .publish("news".to_string(), "hello".to_string())
+        .unwrap();
+    let message = receiver
+        .iter()
+        .find(|message| message != "SHUTDOWN")
+        .unwrap();
+    assert_eq!(message, "hello");
+    server.shutdown();
+    listener.join().unwrap();
+}
@@ -0,0 +1,6 @@
+//! # Common
+//! This library contains the code shared by the server and the client, so
+//! that both sides of a connection speak the protocol the same way.
+
+pub mod protocol;
+pub mod stream;
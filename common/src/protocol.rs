@@ -0,0 +1,150 @@
+//! # Protocol
+//! This library contains the framing used to send commands to the server and
+//! binary encoded messages to clients. The server and the client both use
+//! this module, so the two sides cannot frame payloads differently.
+//! Each frame is a 4 byte big-endian length followed by that many bytes of
+//! payload.
+//!
+//! ## Compatibility
+//! Version 1 of the protocol framed each payload with a 64 byte header holding
+//! its length as ASCII digits padded with spaces. Servers and clients speaking
+//! different versions cannot talk to each other, so both have to be upgraded
+//! together.
+
+use std::io::{self, Read};
+
+/// The version of the protocol described by this module. It is informational
+/// only: it is never sent on the wire, so a server and client speaking
+/// different versions are not told so, and fail to read each other's frames.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The start of every error reply sent by the server, followed by the reason
+/// for the error.
+pub const ERROR_PREFIX: &str = "ERR ";
+
+/// The size in bytes of the length prefix of a frame.
+pub const HEADER_LEN: usize = 4;
+
+/// Frames a payload.
+/// # Arguments
+/// * `payload` - The bytes to frame.
+/// # Returns
+/// The length of the payload followed by the payload.
+/// # Panics
+/// If the payload is longer than `u32::MAX` bytes.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let length = u32::try_from(payload.len()).expect("payload too large to frame");
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads a single frame, waiting until the whole frame has arrived.
+/// # Arguments
+/// * `reader` - The reader to read the frame from.
+/// # Returns
+/// The payload of the frame, or an `UnexpectedEof` error if the reader ends
+/// part way through the frame.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let length = read_length(reader)?;
+    read_payload(reader, length)
+}
+
+/// Reads the length prefix of a frame, so that the length can be checked
+/// before the payload is read.
+/// # Arguments
+/// * `reader` - The reader to read the length from.
+/// # Returns
+/// The length of the payload which follows.
+pub fn read_length(reader: &mut impl Read) -> io::Result<usize> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    Ok(u32::from_be_bytes(header) as usize)
+}
+
+/// Reads the payload of a frame whose length has been read.
+/// # Arguments
+/// * `reader` - The reader to read the payload from.
+/// * `length` - The length read from the frame's prefix.
+pub fn read_payload(reader: &mut impl Read, length: usize) -> io::Result<Vec<u8>> {
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Reads and discards the payload of a frame whose length has been read,
+/// without holding the payload in memory.
+/// # Arguments
+/// * `reader` - The reader to read the payload from.
+/// * `length` - The length read from the frame's prefix.
+/// # Returns
+/// An `UnexpectedEof` error if the reader ends part way through the payload.
+pub fn skip_payload(reader: &mut impl Read, length: usize) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(length as u64), &mut io::sink())?;
+    match skipped == length as u64 {
+        true => Ok(()),
+        false => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that payloads of any length survive being framed and read back.
+    #[test]
+    fn test_round_trip() {
+        for payload in [Vec::new(), b"x".to_vec(), vec![b'a'; 100]] {
+            let frame = encode_frame(&payload);
+            assert_eq!(frame.len(), HEADER_LEN + payload.len());
+            assert_eq!(read_frame(&mut frame.as_slice()).unwrap(), payload);
+        }
+    }
+
+    /// Test that the length is written big-endian.
+    #[test]
+    fn test_encode_frame() {
+        assert_eq!(encode_frame(b""), vec![0, 0, 0, 0]);
+        assert_eq!(encode_frame(b"x"), vec![0, 0, 0, 1, b'x']);
+        assert_eq!(&encode_frame(&[0; 300])[..HEADER_LEN], &[0, 0, 1, 44]);
+    }
+
+    /// Test that frames sent back-to-back are read one after another.
+    #[test]
+    fn test_read_frame_consecutive() {
+        let mut bytes = encode_frame(b"PING");
+        bytes.extend(encode_frame(b""));
+        bytes.extend(encode_frame(b"DISCONNECT"));
+        let mut reader = bytes.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), b"PING");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"DISCONNECT");
+        assert!(reader.is_empty());
+    }
+
+    /// Test that a skipped payload leaves the reader at the next frame.
+    #[test]
+    fn test_skip_payload() {
+        let mut bytes = encode_frame(b"too large");
+        bytes.extend(encode_frame(b"PING"));
+        let mut reader = bytes.as_slice();
+        let length = read_length(&mut reader).unwrap();
+        assert_eq!(length, 9);
+        skip_payload(&mut reader, length).unwrap();
+        assert_eq!(read_frame(&mut reader).unwrap(), b"PING");
+
+        let error = skip_payload(&mut &b"PI"[..], 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Test that a frame cut short is reported as an unexpected end.
+    #[test]
+    fn test_read_frame_truncated() {
+        for bytes in [&[0, 0][..], &[0, 0, 0, 5, b'P', b'I'][..]] {
+            let error = read_frame(&mut &bytes[..]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
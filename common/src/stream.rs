@@ -0,0 +1,225 @@
+//! # Stream
+//! This library contains the connections the server and client read and
+//! write, which are either plain `TcpStream`s or TLS sessions carried over
+//! them. Both are used through the `Stream` trait, so that the same framing
+//! code works on either.
+//!
+//! A connection is read by one thread while others write to it, each through
+//! its own handle from `Stream::try_clone`. The handles of a TLS session share
+//! its state, which is only locked while records are encrypted or decrypted,
+//! never while waiting for the peer to send something.
+
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+/// The size of the buffer encrypted data is read into. The plaintext of this
+/// much data always fits in the TLS session's buffer of received plaintext.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// A connection to a peer, either plain or encrypted.
+pub trait Stream: Read + Write + Send + Debug {
+    /// The TCP connection the stream is carried over, such as to set its
+    /// timeouts or find the address of the peer.
+    fn socket(&self) -> &TcpStream;
+
+    /// Creates another handle to the same connection.
+    /// # Returns
+    /// The handle, or an error if the socket could not be cloned.
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+
+    /// Shuts down the reading half, writing half or both halves of the
+    /// connection.
+    /// # Arguments
+    /// * `how` - Which halves to shut down.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// A TLS session over a TCP connection.
+#[derive(Debug)]
+pub struct TlsStream {
+    connection: Arc<Mutex<rustls::Connection>>,
+    socket: TcpStream,
+}
+
+impl TlsStream {
+    /// Completes the TLS handshake on a connection. Any timeouts set on the
+    /// socket bound the handshake.
+    /// # Arguments
+    /// * `connection` - The TLS state of the connection.
+    /// * `socket` - The connection to the peer.
+    /// # Returns
+    /// The session, or an error if the handshake failed, in which case the
+    /// connection is closed.
+    pub fn new(mut connection: rustls::Connection, mut socket: TcpStream) -> io::Result<TlsStream> {
+        while connection.is_handshaking() {
+            if let Err(e) = connection.complete_io(&mut socket) {
+                // Clones of the socket would otherwise keep the connection open.
+                let _ = socket.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        }
+        Ok(TlsStream {
+            connection: Arc::new(Mutex::new(connection)),
+            socket,
+        })
+    }
+
+    /// Reads encrypted data from the peer and decrypts it. The socket is read
+    /// without holding the lock, so that writers are not held up while
+    /// waiting for the peer.
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; BUFFER_SIZE];
+        let read = self.socket.read(&mut buffer)?;
+        let mut connection = self.connection.lock().unwrap();
+        // Reading nothing tells the session that the peer has closed the
+        // connection.
+        let mut ciphertext = &buffer[..read];
+        loop {
+            connection.read_tls(&mut ciphertext)?;
+            if let Err(e) = connection.process_new_packets() {
+                // Tell the peer why the connection is being closed.
+                let _ = connection.write_tls(&mut self.socket);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            if ciphertext.is_empty() {
+                break;
+            }
+        }
+        // Such as replies to key updates.
+        send(&mut connection, &mut self.socket)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // Plaintext may have been decrypted by the handshake or an earlier
+            // read.
+            match self.connection.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            self.receive()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    /// Encrypts data and writes it to the peer. Records left over from an
+    /// earlier write are sent first, and the data is only accepted once they
+    /// have been, so that a write which times out may be retried without
+    /// repeating anything. Records of the data which time out are left for
+    /// the next write or flush.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        send(&mut connection, &mut self.socket)?;
+        let written = connection.writer().write(buf)?;
+        match send(&mut connection, &mut self.socket) {
+            Err(e)
+                if !matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Err(e)
+            }
+            _ => Ok(written),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        connection.writer().flush()?;
+        send(&mut connection, &mut self.socket)
+    }
+}
+
+impl Stream for TlsStream {
+    fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TlsStream {
+            connection: self.connection.clone(),
+            socket: self.socket.try_clone()?,
+        }))
+    }
+
+    /// Tells the peer the session is closing before shutting down the
+    /// writing half. This is skipped when earlier records are still waiting
+    /// to be sent, as the peer is then not reading and would hold up the
+    /// shutdown.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut connection = self.connection.lock().unwrap();
+            if !connection.wants_write() {
+                connection.send_close_notify();
+                // The peer may have gone already.
+                let _ = send(&mut connection, &mut &self.socket);
+            }
+        }
+        self.socket.shutdown(how)
+    }
+}
+
+/// Writes the pending records of a session to the peer.
+/// # Arguments
+/// * `connection` - The TLS state of the connection.
+/// * `socket` - The connection to the peer.
+fn send(connection: &mut rustls::Connection, socket: &mut dyn Write) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(socket)?;
+    }
+    Ok(())
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Test that plain streams and their clones read and write the same
+    /// connection.
+    #[test]
+    fn test_tcp_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client: Box<dyn Stream> =
+            Box::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut server, _) = listener.accept().unwrap();
+
+        client.try_clone().unwrap().write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        assert_eq!(
+            client.socket().local_addr().unwrap(),
+            server.peer_addr().unwrap()
+        );
+
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server.read(&mut buffer).unwrap(), 0);
+    }
+}
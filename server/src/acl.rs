@@ -0,0 +1,244 @@
+//! # Access Control Lists
+//! This library contains the ACL, which restricts the channels each client may
+//! publish and subscribe to according to the token it authenticated with.
+//! An ACL file holds one rule per line of the form
+//! `<token> <operations> <pattern>`, where the operations are `pub`, `sub` or
+//! `pubsub` and the pattern is a channel pattern, see
+//! `state::matches_pattern`. The token `*` applies to every client, including
+//! clients which have not authenticated. Blank lines and lines starting with
+//! `#` are ignored. Anything no rule allows is denied.
+
+use super::state::matches_pattern;
+use std::{fs, io, path::Path, str::FromStr};
+
+/// An operation on a channel which the ACL controls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Publish,
+    Subscribe,
+}
+
+/// A single line of an ACL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    /// The token the rule applies to, or `*` for every client.
+    identity: String,
+    /// Whether matching channels may be published to.
+    publish: bool,
+    /// Whether matching channels may be subscribed to.
+    subscribe: bool,
+    /// The channels the rule applies to.
+    pattern: String,
+}
+
+impl Rule {
+    /// Checks whether the rule grants an operation to a client.
+    /// # Arguments
+    /// * `identity` - The token the client authenticated with, if any.
+    /// * `operation` - The operation.
+    fn grants(&self, identity: Option<&str>, operation: Operation) -> bool {
+        let applies = self.identity == "*" || Some(self.identity.as_str()) == identity;
+        applies
+            && match operation {
+                Operation::Publish => self.publish,
+                Operation::Subscribe => self.subscribe,
+            }
+    }
+}
+
+/// The rules restricting which channels clients may use.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Loads an ACL from a file.
+    /// # Arguments
+    /// * `path` - The file to load.
+    /// # Returns
+    /// The ACL, or an error if the file cannot be read or has a malformed
+    /// line.
+    pub fn load(path: &Path) -> io::Result<Acl> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Checks whether a client may publish or subscribe to a channel.
+    /// # Arguments
+    /// * `identity` - The token the client authenticated with, if any.
+    /// * `operation` - The operation.
+    /// * `channel` - The channel.
+    pub fn allows(&self, identity: Option<&str>, operation: Operation, channel: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.grants(identity, operation) && matches_pattern(&rule.pattern, channel))
+    }
+
+    /// Checks whether a client may subscribe to a pattern, which it may only
+    /// if it may subscribe to every channel the pattern matches.
+    /// # Arguments
+    /// * `identity` - The token the client authenticated with, if any.
+    /// * `pattern` - The pattern.
+    pub fn allows_pattern(&self, identity: Option<&str>, pattern: &str) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.grants(identity, Operation::Subscribe) && covers(&rule.pattern, pattern)
+        })
+    }
+}
+
+impl FromStr for Acl {
+    type Err = String;
+
+    /// Parses the rules of an ACL, one per line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [identity, operations, pattern] = fields[..] else {
+                return Err(format!(
+                    "line {}: expected <token> <operations> <pattern>",
+                    number + 1
+                ));
+            };
+            let (publish, subscribe) = match operations {
+                "pub" => (true, false),
+                "sub" => (false, true),
+                "pubsub" => (true, true),
+                _ => {
+                    return Err(format!(
+                        "line {}: unknown operations: {}",
+                        number + 1,
+                        operations
+                    ))
+                }
+            };
+            rules.push(Rule {
+                identity: identity.to_string(),
+                publish,
+                subscribe,
+                pattern: pattern.to_string(),
+            });
+        }
+        Ok(Acl { rules })
+    }
+}
+
+/// Checks whether every channel matching a subscription pattern also matches
+/// an ACL pattern. Unlike `matches_pattern`, wildcards in the subscription
+/// pattern are only covered by wildcards in the ACL pattern which match at
+/// least as much.
+/// # Arguments
+/// * `rule` - The pattern of the ACL rule.
+/// * `pattern` - The subscription pattern.
+fn covers(rule: &str, pattern: &str) -> bool {
+    let rule: Vec<&str> = rule.split('.').collect();
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    for (i, segment) in rule.iter().enumerate() {
+        if *segment == "#" && i == rule.len() - 1 {
+            return true;
+        }
+        let Some(requested) = pattern.get(i) else {
+            return false;
+        };
+        let requested_rest = *requested == "#" && i == pattern.len() - 1;
+        match *segment {
+            _ if requested_rest => return false,
+            "*" => (),
+            _ if segment == requested && *requested != "*" => (),
+            _ => return false,
+        }
+    }
+    rule.len() == pattern.len()
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ACL letting sensors publish readings, dashboards read them and
+    /// anyone read public channels.
+    fn acl() -> Acl {
+        "# Readings\n\
+         sensor pub sensors.#\n\
+         dashboard sub sensors.*.temp\n\
+         \n\
+         * sub public.#\n"
+            .parse()
+            .unwrap()
+    }
+
+    /// Test that operations granted to a token, or to everyone, are allowed.
+    #[test]
+    fn test_allow() {
+        let acl = acl();
+        assert!(acl.allows(Some("sensor"), Operation::Publish, "sensors.kitchen.temp"));
+        assert!(acl.allows(
+            Some("dashboard"),
+            Operation::Subscribe,
+            "sensors.kitchen.temp"
+        ));
+        assert!(acl.allows(None, Operation::Subscribe, "public.news"));
+        assert!(acl.allows(Some("sensor"), Operation::Subscribe, "public.news"));
+    }
+
+    /// Test that subscribing is denied where a token may only publish, or the
+    /// channel matches none of its rules.
+    #[test]
+    fn test_deny_subscribe() {
+        let acl = acl();
+        assert!(!acl.allows(Some("sensor"), Operation::Subscribe, "sensors.kitchen.temp"));
+        assert!(!acl.allows(
+            Some("dashboard"),
+            Operation::Subscribe,
+            "sensors.kitchen.humidity"
+        ));
+        assert!(!acl.allows(None, Operation::Subscribe, "sensors.kitchen.temp"));
+    }
+
+    /// Test that publishing is denied where a token may only subscribe, and
+    /// for tokens without rules.
+    #[test]
+    fn test_deny_publish() {
+        let acl = acl();
+        assert!(!acl.allows(
+            Some("dashboard"),
+            Operation::Publish,
+            "sensors.kitchen.temp"
+        ));
+        assert!(!acl.allows(Some("unknown"), Operation::Publish, "public.news"));
+        assert!(!acl.allows(None, Operation::Publish, "public.news"));
+    }
+
+    /// Test that patterns are only allowed when every channel they match is.
+    #[test]
+    fn test_allows_pattern() {
+        let acl = acl();
+        assert!(acl.allows_pattern(Some("dashboard"), "sensors.*.temp"));
+        assert!(acl.allows_pattern(Some("dashboard"), "sensors.kitchen.temp"));
+        assert!(acl.allows_pattern(None, "public.#"));
+        assert!(acl.allows_pattern(None, "public.*.headlines"));
+        assert!(!acl.allows_pattern(Some("dashboard"), "sensors.#"));
+        assert!(!acl.allows_pattern(Some("dashboard"), "sensors.*.*"));
+        assert!(!acl.allows_pattern(None, "#"));
+    }
+
+    /// Test that malformed lines are reported with their line number.
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "sensor pub\n".parse::<Acl>(),
+            Err("line 1: expected <token> <operations> <pattern>".to_string())
+        );
+        assert_eq!(
+            "\nsensor write sensors.#\n".parse::<Acl>(),
+            Err("line 2: unknown operations: write".to_string())
+        );
+    }
+}
@@ -0,0 +1,744 @@
+//! # Async Server
+//! This library contains an alternative server built on tokio, enabled with
+//! the `async` feature. Each connection is handled by a task rather than a
+//! thread, so idle subscribers only cost their socket and a few buffers, and
+//! thousands of them can be connected at once.
+//! It speaks the same protocol as the threaded server, see `protocol`, but
+//! only supports its core commands: `SUBSCRIBE`, `SUBSCRIBE_ACK`,
+//! `UNSUBSCRIBE`, `PUBLISH`, `COUNT`, `ENCODING`, `PING`, `PONG` and
+//! `DISCONNECT`. Messages and replies are written in each client's encoding
+//! exactly as the threaded server writes them, see `consumer::encode`.
+//!
+//! Each connection has a task of its own writing to it, which messages and
+//! replies are queued for. Publishers only queue messages, so a subscriber
+//! which stops reading never holds up a publisher. Once its queue is full it
+//! is unsubscribed and its connection closed.
+
+use super::{
+    config::{self, Config},
+    consumer::{self, validate_channel},
+    protocol,
+    state::{self, Encoding},
+};
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::mpsc::{self, error::TrySendError},
+    task::AbortHandle,
+    time,
+};
+
+/// The number of messages and replies which may wait to be written to a
+/// client. A subscriber which falls this far behind is disconnected.
+const QUEUE_SIZE: usize = 256;
+
+/// Something waiting to be written to a client.
+enum Outgoing {
+    /// A message or reply, written in the client's encoding.
+    Message(Vec<u8>),
+    /// The encoding of everything queued afterwards.
+    Encoding(Encoding),
+}
+
+/// A client's connection, written to by a task of its own, see
+/// `write_queued`.
+struct Connection {
+    queue: mpsc::Sender<Outgoing>,
+    writer: AbortHandle,
+}
+
+impl Connection {
+    /// Starts the task writing to a client.
+    /// # Arguments
+    /// * `writer` - The half of the client's connection to write to.
+    /// * `write_timeout` - How long writing a message may take, if limited.
+    fn new(writer: OwnedWriteHalf, write_timeout: Option<Duration>) -> Connection {
+        let (queue, queued) = mpsc::channel(QUEUE_SIZE);
+        let writer = tokio::spawn(write_queued(writer, queued, write_timeout));
+        Connection {
+            queue,
+            writer: writer.abort_handle(),
+        }
+    }
+
+    /// Queues a reply for the client, waiting for room in its queue.
+    /// # Arguments
+    /// * `message` - The reply.
+    /// # Returns
+    /// An error if the client can no longer be written to.
+    async fn write(&self, message: &[u8]) -> io::Result<()> {
+        self.queue
+            .send(Outgoing::Message(message.to_vec()))
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    /// Queues a published message for the client without waiting.
+    /// # Arguments
+    /// * `message` - The message.
+    /// # Returns
+    /// An error if the client's queue is full or it can no longer be written
+    /// to.
+    fn try_write(&self, message: &[u8]) -> Result<(), TrySendError<Outgoing>> {
+        self.queue.try_send(Outgoing::Message(message.to_vec()))
+    }
+
+    /// Switches the encoding of everything queued from now on.
+    /// # Arguments
+    /// * `encoding` - The encoding.
+    async fn set_encoding(&self, encoding: Encoding) {
+        // A client which cannot be written to has no use for an encoding.
+        let _ = self.queue.send(Outgoing::Encoding(encoding)).await;
+    }
+
+    /// Stops writing to the client and closes the writing half of its
+    /// connection, dropping anything still queued.
+    fn close(&self) {
+        self.writer.abort();
+    }
+}
+
+/// Writes what is queued for a client until the queue is closed, which is
+/// once nothing refers to the client's connection any more, or a write fails.
+/// # Arguments
+/// * `writer` - The half of the client's connection to write to.
+/// * `queue` - What is waiting to be written.
+/// * `write_timeout` - How long writing a message may take, if limited.
+async fn write_queued(
+    mut writer: OwnedWriteHalf,
+    mut queue: mpsc::Receiver<Outgoing>,
+    write_timeout: Option<Duration>,
+) {
+    let mut encoding = Encoding::default();
+    while let Some(outgoing) = queue.recv().await {
+        let message = match outgoing {
+            Outgoing::Message(message) => message,
+            Outgoing::Encoding(next) => {
+                encoding = next;
+                continue;
+            }
+        };
+        let data = consumer::encode(encoding, &message);
+        let write = writer.write_all(&data);
+        let written = match write_timeout {
+            Some(timeout) => time::timeout(timeout, write)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => write.await,
+        };
+        if let Err(e) = written {
+            warn!("Failed to write to a client: {}", e);
+            return;
+        }
+    }
+}
+
+/// A client's connection, shared with the channels it is subscribed to.
+type Writer = Arc<Connection>;
+
+/// The subscribers of each channel, keyed by the id of their connection.
+#[derive(Default)]
+pub struct Subscriptions {
+    channels: Mutex<HashMap<String, HashMap<u64, Writer>>>,
+}
+
+impl Subscriptions {
+    /// Subscribes a client to a channel.
+    /// # Arguments
+    /// * `channel` - The channel to subscribe to.
+    /// * `id` - The id of the client's connection.
+    /// * `writer` - Where messages published to the channel are written.
+    fn add(&self, channel: &str, id: u64, writer: &Writer) {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_default()
+            .insert(id, writer.clone());
+    }
+
+    /// Unsubscribes a client from a channel, forgetting the channel once it
+    /// has no subscribers left.
+    /// # Arguments
+    /// * `channel` - The channel to unsubscribe from.
+    /// * `id` - The id of the client's connection.
+    fn remove(&self, channel: &str, id: u64) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Unsubscribes a client from every channel.
+    /// # Arguments
+    /// * `id` - The id of the client's connection.
+    fn remove_client(&self, id: u64) {
+        self.channels.lock().unwrap().retain(|_, subscribers| {
+            subscribers.remove(&id);
+            !subscribers.is_empty()
+        });
+    }
+
+    /// Gets the subscribers of a channel. They are copied out, so that no
+    /// lock is held while writing to them.
+    /// # Arguments
+    /// * `channel` - The channel.
+    fn subscribers(&self, channel: &str) -> Vec<(u64, Writer)> {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .map(|(id, writer)| (*id, writer.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Counts the subscribers of a channel.
+    /// # Arguments
+    /// * `channel` - The channel.
+    pub fn count(&self, channel: &str) -> usize {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map_or(0, HashMap::len)
+    }
+
+    /// Gets every channel with at least one subscriber, along with its number
+    /// of subscribers.
+    /// # Returns
+    /// The channels and their subscriber counts, sorted by channel.
+    pub fn channels(&self) -> Vec<(String, usize)> {
+        let mut channels: Vec<(String, usize)> = self
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel, subscribers)| (channel.clone(), subscribers.len()))
+            .collect();
+        channels.sort();
+        channels
+    }
+}
+
+/// The state shared by every connection of an async server.
+struct State {
+    config: Config,
+    subscriptions: Subscriptions,
+    next_id: AtomicU64,
+}
+
+/// A server handling each connection as a tokio task.
+pub struct AsyncServer {
+    listener: TcpListener,
+    state: Arc<State>,
+}
+
+impl AsyncServer {
+    /// Starts listening for connections. Connections are not accepted until
+    /// the server is run.
+    /// # Arguments
+    /// * `host` - The hostname to listen on.
+    /// * `port` - The port to listen on, or 0 for any free port.
+    /// * `config` - The configuration of the server. Only the maximum message
+    ///   size, channel separator, write timeout and text only are supported.
+    /// # Returns
+    /// The server, or an error if the address cannot be listened on or the
+    /// configuration sets options which are not supported, see
+    /// `unsupported_options`.
+    pub async fn bind(host: &str, port: u16, config: Config) -> io::Result<AsyncServer> {
+        let unsupported = unsupported_options(&config);
+        if !unsupported.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported options: {}", unsupported.join(", ")),
+            ));
+        }
+        let listener = TcpListener::bind((host, port)).await?;
+        Ok(AsyncServer {
+            listener,
+            state: Arc::new(State {
+                config,
+                subscriptions: Subscriptions::default(),
+                next_id: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Gets the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Gets the subscriptions of the server's clients.
+    pub fn subscriptions(&self) -> &Subscriptions {
+        &self.state.subscriptions
+    }
+
+    /// Accepts connections, spawning a task to handle each of them, until the
+    /// returned future is dropped.
+    /// # Returns
+    /// An error if the server can no longer accept connections.
+    pub async fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                // Such as the connection being reset before it was accepted.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(e) => return Err(e),
+            };
+            info!("Connection established with {}", addr);
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+                handle(&state, id, stream).await;
+                state.subscriptions.remove_client(id);
+                info!("Connection closed with {}", addr);
+            });
+        }
+    }
+}
+
+/// Finds the options set in a configuration which the async server does not
+/// support, such as authentication and rate limits. Ignoring them would
+/// silently serve clients without the protection they were asked for.
+/// # Arguments
+/// * `config` - The configuration of the server.
+/// # Returns
+/// The command line names of the options, which is empty when every option
+/// set is supported.
+pub fn unsupported_options(config: &Config) -> Vec<&'static str> {
+    let default = Config::default();
+    [
+        ("--admin-token", config.admin_token.is_some()),
+        ("--auth-token", !config.auth_tokens.is_empty()),
+        ("--acl-file", config.acl.is_some()),
+        ("--presence", config.presence),
+        ("--presence-timeout", config.presence_timeout.is_some()),
+        ("--max-connections", config.max_connections.is_some()),
+        ("--workers", config.workers.is_some()),
+        ("--worker-queue", config.worker_queue.is_some()),
+        ("--sendto-admin-only", config.sendto_admin_only),
+        ("--keepalive", config.keepalive.is_some()),
+        ("--heartbeat", config.heartbeat.is_some()),
+        (
+            "--heartbeat-misses",
+            config.heartbeat_misses != default.heartbeat_misses,
+        ),
+        (
+            "--delivery-retries",
+            config.delivery_retries != default.delivery_retries,
+        ),
+        (
+            "--delivery-retry-delay",
+            config.delivery_retry_delay != default.delivery_retry_delay,
+        ),
+        ("--channel-rate", config.channel_rate.is_some()),
+        ("--channel-burst", config.channel_burst.is_some()),
+        ("--channel-rate-delay", config.channel_rate_delay),
+        ("--client-rate", config.client_rate.is_some()),
+        ("--client-burst", config.client_burst.is_some()),
+        (
+            "--dedup-window",
+            config.dedup_window != default.dedup_window,
+        ),
+        (
+            "--shutdown-timeout",
+            config.shutdown_timeout != default.shutdown_timeout,
+        ),
+        ("--session-grace", config.session_grace.is_some()),
+        ("--retained-memory", config.retained_memory.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+/// Handles the commands sent by a client until it disconnects.
+/// # Arguments
+/// * `state` - The state of the server.
+/// * `id` - The id of the client's connection.
+/// * `stream` - The client's connection.
+async fn handle(state: &State, id: u64, stream: TcpStream) {
+    let (reader, writer) = stream.into_split();
+    let writer: Writer = Arc::new(Connection::new(writer, state.config.write_timeout));
+    let mut reader = BufReader::new(reader);
+    loop {
+        let message = match read_message(state, &writer, &mut reader).await {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    error!("{}", e);
+                }
+                return;
+            }
+        };
+        if message.is_empty() {
+            continue;
+        }
+
+        // Only published bodies are kept as bytes, as they may not be UTF-8.
+        // Every other argument is read as text, with anything which is not
+        // UTF-8 replaced.
+        let (command, payload) = consumer::split_bytes(&message);
+        let command = String::from_utf8_lossy(command);
+        let argument = String::from_utf8_lossy(payload);
+        let argument = argument.as_ref();
+        match command.as_ref() {
+            "SUBSCRIBE" => {
+                subscribe_handler(state, id, &writer, argument).await;
+            }
+            "SUBSCRIBE_ACK" => {
+                if subscribe_handler(state, id, &writer, argument).await {
+                    reply(&writer, &format!("ACK SUBSCRIBE {}", argument)).await;
+                }
+            }
+            "UNSUBSCRIBE" => {
+                debug!("Unsubscribing from channel: {}", argument);
+                state.subscriptions.remove(argument, id);
+            }
+            "PUBLISH" => publish_handler(state, &writer, payload).await,
+            "COUNT" => {
+                let count = state.subscriptions.count(argument);
+                reply(&writer, &format!("COUNT {} {}", argument, count)).await;
+            }
+            "ENCODING" => {
+                let encoding = consumer::negotiate_encoding(argument, state.config.text_only);
+                // The reply is written using the previous encoding.
+                reply(&writer, &format!("ENCODING {}", encoding)).await;
+                writer.set_encoding(encoding).await;
+            }
+            "PING" => match argument.trim() {
+                "" => reply(&writer, "PONG").await,
+                token => reply(&writer, &format!("PONG {}", token)).await,
+            },
+            "PONG" => (),
+            "DISCONNECT" => {
+                // The client is told it is gone once it has been
+                // unsubscribed.
+                state.subscriptions.remove_client(id);
+                reply(&writer, "BYE").await;
+                return;
+            }
+            _ => {
+                warn!("Unknown command: {}", command);
+                reply_error(&writer, &format!("unknown command: {}", command)).await;
+            }
+        }
+    }
+}
+
+/// Reads the next command sent by a client. Commands larger than the
+/// configured maximum are skipped, replying `ERR message too large`.
+/// # Arguments
+/// * `state` - The state of the server.
+/// * `writer` - Where replies to the client are written.
+/// * `reader` - The connection the command is read from.
+/// # Returns
+/// The command, or `None` if it was skipped.
+async fn read_message(
+    state: &State,
+    writer: &Writer,
+    reader: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<Vec<u8>>> {
+    // The length prefix is big-endian, see `protocol`.
+    let length = reader.read_u32().await? as usize;
+    let max_size = state
+        .config
+        .max_message_size
+        .unwrap_or(config::MAX_MESSAGE_SIZE);
+    if length <= max_size {
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload).await?;
+        return Ok(Some(payload));
+    }
+
+    warn!("Dropping a command of {} bytes", length);
+    reply_error(writer, "message too large").await;
+    let skipped = tokio::io::copy(&mut reader.take(length as u64), &mut tokio::io::sink()).await?;
+    match skipped == length as u64 {
+        true => Ok(None),
+        false => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Subscribes a client to a channel.
+/// # Arguments
+/// * `state` - The state of the server.
+/// * `id` - The id of the client's connection.
+/// * `writer` - Where messages published to the channel are written.
+/// * `channel` - The channel to subscribe to.
+/// # Returns
+/// Whether the client was subscribed.
+async fn subscribe_handler(state: &State, id: u64, writer: &Writer, channel: &str) -> bool {
+    if let Err(e) = validate_channel(channel, channel_separator(state)) {
+        reply_error(writer, &format!("invalid channel: {}", e)).await;
+        return false;
+    }
+    debug!("Subscribing to channel: {}", channel);
+    state.subscriptions.add(channel, id, writer);
+    true
+}
+
+/// Queues a message for every subscriber of its channel, without waiting for
+/// it to be written. Subscribers whose queue is full are unsubscribed and
+/// disconnected, as are those which can no longer be written to.
+/// # Arguments
+/// * `state` - The state of the server.
+/// * `writer` - Where replies to the publisher are written.
+/// * `message` - The channel and body of the message. Only the channel has
+///   to be UTF-8.
+async fn publish_handler(state: &State, writer: &Writer, message: &[u8]) {
+    let Some((channel, body)) = state::split_payload(message, channel_separator(state)) else {
+        reply_error(writer, "bad publish format").await;
+        return;
+    };
+
+    for (id, subscriber) in state.subscriptions.subscribers(channel) {
+        if let Err(e) = subscriber.try_write(body) {
+            warn!("Failed to deliver to a subscriber of {}: {}", channel, e);
+            state.subscriptions.remove_client(id);
+            subscriber.close();
+        }
+    }
+}
+
+/// Gets the character separating the channel of a published message from its
+/// body.
+/// # Arguments
+/// * `state` - The state of the server.
+fn channel_separator(state: &State) -> char {
+    state
+        .config
+        .channel_separator
+        .unwrap_or(config::CHANNEL_SEPARATOR)
+}
+
+/// Writes a reply to a client.
+/// # Arguments
+/// * `writer` - Where replies to the client are written.
+/// * `message` - The reply.
+async fn reply(writer: &Writer, message: &str) {
+    if let Err(e) = writer.write(message.as_bytes()).await {
+        debug!("Failed to reply: {}", e);
+    }
+}
+
+/// Writes an error reply to a client, see `protocol::ERROR_PREFIX`.
+/// # Arguments
+/// * `writer` - Where replies to the client are written.
+/// * `reason` - Why the command failed.
+async fn reply_error(writer: &Writer, reason: &str) {
+    reply(writer, &format!("{}{}", protocol::ERROR_PREFIX, reason)).await;
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    /// Starts a server on a free port.
+    async fn start_server(config: Config) -> Arc<AsyncServer> {
+        let server = Arc::new(AsyncServer::bind("127.0.0.1", 0, config).await.unwrap());
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+        server
+    }
+
+    /// Sends a framed command to a server.
+    async fn send(stream: &mut TcpStream, command: &str) {
+        stream
+            .write_all(&protocol::encode_frame(command.as_bytes()))
+            .await
+            .unwrap();
+    }
+
+    /// Reads a line written by a server.
+    async fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        line
+    }
+
+    /// Test that a published message is delivered to the channel's
+    /// subscribers, and that replies are written to the client asking.
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let server = start_server(Config::default()).await;
+        let addr = server.local_addr().unwrap();
+        let mut subscriber = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        send(subscriber.get_mut(), "SUBSCRIBE_ACK news").await;
+        assert_eq!(read_line(&mut subscriber).await, "ACK SUBSCRIBE news\n");
+
+        let mut publisher = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        send(publisher.get_mut(), "COUNT news").await;
+        assert_eq!(read_line(&mut publisher).await, "COUNT news 1\n");
+        send(publisher.get_mut(), "PUBLISH news\u{1f}hello").await;
+        send(publisher.get_mut(), "PUBLISH other\u{1f}ignored").await;
+        send(publisher.get_mut(), "PING").await;
+        assert_eq!(read_line(&mut publisher).await, "PONG\n");
+        assert_eq!(read_line(&mut subscriber).await, "hello\n");
+
+        send(subscriber.get_mut(), "DISCONNECT").await;
+        assert_eq!(read_line(&mut subscriber).await, "BYE\n");
+        assert_eq!(read_line(&mut subscriber).await, "");
+        send(publisher.get_mut(), "COUNT news").await;
+        assert_eq!(read_line(&mut publisher).await, "COUNT news 0\n");
+        assert!(server.subscriptions().channels().is_empty());
+    }
+
+    /// Test that a subscriber asking for the binary encoding receives a
+    /// multi-line message intact, framed as the threaded server frames it.
+    #[tokio::test]
+    async fn test_binary_encoding() {
+        let server = start_server(Config::default()).await;
+        let addr = server.local_addr().unwrap();
+        let mut subscriber = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        send(subscriber.get_mut(), "ENCODING binary").await;
+        assert_eq!(read_line(&mut subscriber).await, "ENCODING BINARY\n");
+        send(subscriber.get_mut(), "SUBSCRIBE_ACK news").await;
+        let mut reply = vec![0; protocol::encode_frame(b"ACK SUBSCRIBE news").len()];
+        subscriber.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, protocol::encode_frame(b"ACK SUBSCRIBE news"));
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        send(
+            &mut publisher,
+            "PUBLISH news\u{1f}first line\nsecond line\n",
+        )
+        .await;
+        let length = subscriber.read_u32().await.unwrap() as usize;
+        let mut message = vec![0; length];
+        subscriber.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, b"first line\nsecond line\n");
+    }
+
+    /// Test that a message which is not UTF-8 is delivered byte for byte.
+    #[tokio::test]
+    async fn test_publish_bytes() {
+        let server = start_server(Config::default()).await;
+        let addr = server.local_addr().unwrap();
+        let mut subscriber = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        send(subscriber.get_mut(), "SUBSCRIBE_ACK news").await;
+        assert_eq!(read_line(&mut subscriber).await, "ACK SUBSCRIBE news\n");
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        publisher
+            .write_all(&protocol::encode_frame(b"PUBLISH news\x1f\xff\x00\xfe"))
+            .await
+            .unwrap();
+        let mut message = Vec::new();
+        subscriber.read_until(b'\n', &mut message).await.unwrap();
+        assert_eq!(message, b"\xff\x00\xfe\n");
+    }
+
+    /// Test that a subscriber which stops reading holds up neither the
+    /// publisher nor other subscribers, and is unsubscribed once it falls too
+    /// far behind.
+    #[tokio::test]
+    async fn test_slow_subscriber() {
+        let server = start_server(Config::default()).await;
+        let addr = server.local_addr().unwrap();
+        let mut slow = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        send(slow.get_mut(), "SUBSCRIBE_ACK news").await;
+        assert_eq!(read_line(&mut slow).await, "ACK SUBSCRIBE news\n");
+        let mut subscriber = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        send(subscriber.get_mut(), "SUBSCRIBE_ACK news").await;
+        assert_eq!(read_line(&mut subscriber).await, "ACK SUBSCRIBE news\n");
+
+        // Far more than the slow subscriber's socket buffers and queue hold.
+        let body = "x".repeat(64 * 1024);
+        let mut publisher = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let publishing = async {
+            for _ in 0..QUEUE_SIZE * 2 {
+                send(publisher.get_mut(), &format!("PUBLISH news\u{1f}{}", body)).await;
+            }
+            send(publisher.get_mut(), "COUNT news").await;
+            read_line(&mut publisher).await
+        };
+        let receiving = async {
+            for _ in 0..QUEUE_SIZE * 2 {
+                assert_eq!(read_line(&mut subscriber).await.len(), body.len() + 1);
+            }
+        };
+        let (count, ()) = time::timeout(Duration::from_secs(10), async {
+            tokio::join!(publishing, receiving)
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, "COUNT news 1\n");
+    }
+
+    /// Test that a configuration with options the server does not support,
+    /// such as authentication, is refused rather than ignored.
+    #[tokio::test]
+    async fn test_bind_unsupported_options() {
+        let config = Config {
+            auth_tokens: ["secret".to_string()].into(),
+            client_rate: Some(10),
+            ..Config::default()
+        };
+        let error = AsyncServer::bind("127.0.0.1", 0, config)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error.to_string(),
+            "unsupported options: --auth-token, --client-rate"
+        );
+        assert!(unsupported_options(&Config::default()).is_empty());
+    }
+
+    /// Test that unknown and oversized commands are refused without closing
+    /// the connection.
+    #[tokio::test]
+    async fn test_error_replies() {
+        let config = Config {
+            max_message_size: Some(16),
+            ..Config::default()
+        };
+        let server = start_server(config).await;
+        let mut client = BufReader::new(
+            TcpStream::connect(server.local_addr().unwrap())
+                .await
+                .unwrap(),
+        );
+        send(client.get_mut(), "SUBSCRIBE_ALL").await;
+        assert_eq!(
+            read_line(&mut client).await,
+            "ERR unknown command: SUBSCRIBE_ALL\n"
+        );
+        send(
+            client.get_mut(),
+            &format!("PUBLISH news {}", "x".repeat(32)),
+        )
+        .await;
+        assert_eq!(read_line(&mut client).await, "ERR message too large\n");
+        send(client.get_mut(), "PUBLISH news").await;
+        assert_eq!(read_line(&mut client).await, "ERR bad publish format\n");
+        send(client.get_mut(), "PING").await;
+        assert_eq!(read_line(&mut client).await, "PONG\n");
+    }
+}
@@ -0,0 +1,140 @@
+//! # Audit Log
+//! This library contains the audit log, which records every message published
+//! by clients to a file.
+//! Each record is a single line of the form
+//! `<timestamp> <channel> <sender> <size> [body]`, where the timestamp is the
+//! number of seconds since the Unix epoch.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A file that published messages are appended to. Writes are buffered, so
+/// records only reach the file once the buffer fills or is flushed.
+pub struct AuditLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: Option<u64>,
+    include_body: bool,
+}
+
+impl AuditLog {
+    /// Opens an audit log, appending to the file if it already exists.
+    /// # Arguments
+    /// * `path` - The file to log to.
+    /// * `max_size` - The size in bytes after which the file is rotated. The
+    ///   previous file is kept with a `.1` suffix.
+    /// * `include_body` - Whether the body of each message is logged.
+    pub fn open(path: PathBuf, max_size: Option<u64>, include_body: bool) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            writer: BufWriter::new(file),
+            size,
+            max_size,
+            include_body,
+        })
+    }
+
+    /// Records a published message.
+    /// # Arguments
+    /// * `sender` - The id of the client that published the message.
+    /// * `channel` - The channel the message was published on.
+    /// * `message` - The message, which may not be UTF-8.
+    pub fn record(&mut self, sender: &str, channel: &str, message: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut record = format!("{:.3} {} {} {}", timestamp, channel, sender, message.len());
+        if self.include_body {
+            // Escaped so that each record stays on a single line.
+            record.push(' ');
+            record.push_str(
+                &String::from_utf8_lossy(message)
+                    .escape_default()
+                    .to_string(),
+            );
+        }
+        record.push('\n');
+
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + record.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        self.writer.write_all(record.as_bytes())?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Writes any buffered records to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Moves the current file aside and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a path in the temporary directory which does not exist yet.
+    fn get_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Test that records are written with the body only when requested.
+    #[test]
+    fn test_record() {
+        let path = get_path("test_record");
+        let mut log = AuditLog::open(path.clone(), None, false).unwrap();
+        log.record("1", "news", b"hello\n").unwrap();
+        log.include_body = true;
+        log.record("2", "sport", b"goal\n").unwrap();
+        log.flush().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let records: Vec<Vec<&str>> = contents
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1.split(' ').collect())
+            .collect();
+        assert_eq!(
+            records,
+            vec![vec!["news", "1", "6"], vec!["sport", "2", "5", "goal\\n"]]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Test that the file is rotated once it would grow past its maximum size.
+    #[test]
+    fn test_rotate() {
+        let path = get_path("test_rotate");
+        let mut log = AuditLog::open(path.clone(), Some(40), true).unwrap();
+        log.record("1", "news", b"first").unwrap();
+        log.record("1", "news", b"second").unwrap();
+        log.flush().unwrap();
+
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        assert!(fs::read_to_string(&rotated).unwrap().ends_with(" first\n"));
+        assert!(fs::read_to_string(&path).unwrap().ends_with(" second\n"));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+//! # Async Server
+//! This binary runs the tokio based server, see `server::async_server`, as an
+//! alternative to the threaded server for many mostly idle connections. It
+//! takes the same options as the threaded server, of which only the host,
+//! port, maximum message size, channel separator, write timeout and text only
+//! are supported. It refuses to start when any other option is set.
+
+use log::{error, info};
+use server::{async_server, async_server::AsyncServer, config};
+use structopt::StructOpt;
+
+/// Finds the options set on the command line which the async server does not
+/// support, including those which only exist on the command line, such as
+/// TLS.
+/// # Arguments
+/// * `options` - The command line options.
+/// # Returns
+/// The names of the options, which is empty when every option set is
+/// supported.
+fn unsupported_options(options: &config::Options) -> Vec<&'static str> {
+    let mut unsupported: Vec<&str> = [
+        ("--bind", !options.bind.is_empty()),
+        ("--auth-tokens-file", options.auth_tokens_file.is_some()),
+        ("--acl-file", options.acl_file.is_some()),
+        ("--audit-log", options.audit_log.is_some()),
+        ("--audit-log-body", options.audit_log_body),
+        ("--audit-log-max-size", options.audit_log_max_size.is_some()),
+        ("--tls-cert", options.tls_cert.is_some()),
+        ("--tls-key", options.tls_key.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(name, _)| name)
+    .collect();
+    unsupported.extend(async_server::unsupported_options(&options.config()));
+    unsupported
+}
+
+#[tokio::main]
+async fn main() {
+    // Log at info unless RUST_LOG says otherwise, so that connections are
+    // reported by default.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let options = config::Options::from_args();
+    let unsupported = unsupported_options(&options);
+    if !unsupported.is_empty() {
+        error!(
+            "The async server does not support {}",
+            unsupported.join(", ")
+        );
+        std::process::exit(1);
+    }
+    let server = AsyncServer::bind(&options.host, options.port, options.config())
+        .await
+        .expect("Could not bind to port");
+    info!("Listening on {}", server.local_addr().unwrap());
+
+    tokio::select! {
+        result = server.run() => result.expect("Could not accept connections"),
+        _ = tokio::signal::ctrl_c() => info!("Shutting down"),
+    }
+}
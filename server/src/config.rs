@@ -0,0 +1,443 @@
+//! # Server Configuration
+//! This library contains the command line options for the server and the
+//! runtime configuration shared with the consumers.
+
+use super::acl::Acl;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// The default character separating the channel from the body of a published
+/// message. Being a control character, it does not appear in channel names
+/// and is unambiguous whatever the body contains.
+pub const CHANNEL_SEPARATOR: char = '\u{1f}';
+
+/// The default number of connections which may wait for a worker thread to
+/// handle them.
+pub const DEFAULT_WORKER_QUEUE: usize = 128;
+
+/// The default size in bytes of the largest command a client may send. Larger
+/// commands are refused without being read into memory.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// How long a request is waited on for its reply, after which its
+/// correlation id is forgotten and a late reply is refused.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Reads a file of tokens, one per line. Blank lines and lines starting with
+/// `#` are skipped.
+/// # Arguments
+/// * `path` - The file to read.
+/// # Returns
+/// The tokens, or an error if the file could not be read.
+pub fn read_tokens(path: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The command line options for the server.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "server")]
+pub struct Options {
+    /// The hostname to listen on
+    #[structopt(default_value = "localhost")]
+    pub host: String,
+
+    /// The port to listen on
+    #[structopt(default_value = "7878")]
+    pub port: u16,
+
+    /// Another hostname to listen on using the same port, such as `::` to
+    /// also accept IPv6 connections. May be given several times.
+    #[structopt(long)]
+    pub bind: Vec<String>,
+
+    /// Token which grants a connection admin rights once sent using `AUTH`.
+    /// Admin only commands are disabled when this is not set.
+    #[structopt(long)]
+    pub admin_token: Option<String>,
+
+    /// Token which allows a connection to use the server once sent using
+    /// `AUTH`. May be given several times. Clients must authenticate before
+    /// subscribing or publishing once any token is set.
+    #[structopt(long)]
+    pub auth_token: Vec<String>,
+
+    /// File of tokens accepted as `--auth-token`, one per line. Blank lines
+    /// and lines starting with `#` are ignored.
+    #[structopt(long, parse(from_os_str))]
+    pub auth_tokens_file: Option<PathBuf>,
+
+    /// File of rules restricting the channels each token may publish and
+    /// subscribe to, one `<token> <pub|sub|pubsub> <pattern>` rule per line.
+    /// Every channel may be used when this is not set.
+    #[structopt(long, parse(from_os_str))]
+    pub acl_file: Option<PathBuf>,
+
+    /// Publish join and leave events on the `$presence` channel
+    #[structopt(long)]
+    pub presence: bool,
+
+    /// Seconds without a heartbeat after which a client is considered to have
+    /// left. Clients are never reaped when this is not set.
+    #[structopt(long)]
+    pub presence_timeout: Option<u64>,
+
+    /// Connections which may be open at once. Further connections are told
+    /// `SERVER_BUSY` and closed. Connections are not limited when this is not
+    /// set.
+    #[structopt(long)]
+    pub max_connections: Option<usize>,
+
+    /// Threads handling connections. Each connection is handled by one thread
+    /// for as long as it is open. Defaults to four times the number of CPUs.
+    #[structopt(long)]
+    pub workers: Option<usize>,
+
+    /// Connections which may wait for a thread to handle them. Further
+    /// connections are told `ERR server busy` and closed. Defaults to 128.
+    #[structopt(long)]
+    pub worker_queue: Option<usize>,
+
+    /// Size in bytes of the largest command, such as a publish, a client may
+    /// send. Larger commands are told `ERR message too large` and dropped.
+    /// Defaults to 1 MiB.
+    #[structopt(long)]
+    pub max_message_size: Option<usize>,
+
+    /// Character separating the channel from the body of a published message.
+    /// Defaults to the unit separator control character.
+    #[structopt(long)]
+    pub channel_separator: Option<char>,
+
+    /// Only allow admins to send messages directly to a client with `SENDTO`
+    #[structopt(long)]
+    pub sendto_admin_only: bool,
+
+    /// Milliseconds a write to a client may block before the write fails and
+    /// the client is unsubscribed. A value of 0 disables the timeout.
+    #[structopt(long, default_value = "5000")]
+    pub write_timeout: u64,
+
+    /// Seconds between probes of every connection's socket, which close
+    /// connections the client has reset. Connections are not probed when this
+    /// is not set.
+    #[structopt(long)]
+    pub keepalive: Option<u64>,
+
+    /// Seconds between the `PING` heartbeats sent to every client, which the
+    /// client answers with `PONG`. Clients are never sent heartbeats when this
+    /// is not set.
+    #[structopt(long)]
+    pub heartbeat: Option<u64>,
+
+    /// Heartbeats a client may leave unanswered in a row before it is
+    /// disconnected
+    #[structopt(long, default_value = "3")]
+    pub heartbeat_misses: u32,
+
+    /// Times to retry a write to a subscriber which fails with a transient
+    /// error before the subscriber is unsubscribed
+    #[structopt(long, default_value = "3")]
+    pub delivery_retries: u32,
+
+    /// Milliseconds to wait before retrying a write to a subscriber
+    #[structopt(long, default_value = "10")]
+    pub delivery_retry_delay: u64,
+
+    /// Messages per second which may be published to a single channel.
+    /// Channels are not limited when this is not set.
+    #[structopt(long)]
+    pub channel_rate: Option<u32>,
+
+    /// Messages which may be published to a channel at once before the rate
+    /// applies. Defaults to the rate.
+    #[structopt(long)]
+    pub channel_burst: Option<u32>,
+
+    /// Delay messages published to a channel over its rate, rather than
+    /// dropping them
+    #[structopt(long)]
+    pub channel_rate_delay: bool,
+
+    /// Commands per second which a single client may send. Clients are not
+    /// limited when this is not set.
+    #[structopt(long)]
+    pub client_rate: Option<u32>,
+
+    /// Commands which a client may send at once before the rate applies.
+    /// Defaults to the rate.
+    #[structopt(long)]
+    pub client_burst: Option<u32>,
+
+    /// Number of recent message ids remembered for each publisher, so that
+    /// messages published again with `PUBLISH_ID` are not delivered twice.
+    /// Messages are never deduplicated when this is 0.
+    #[structopt(long, default_value = "0")]
+    pub dedup_window: usize,
+
+    /// Milliseconds to spend delivering messages still queued for subscribers
+    /// when the server is shut down.
+    #[structopt(long, default_value = "5000")]
+    pub shutdown_timeout: u64,
+
+    /// Only write messages to clients as text, downgrading clients which ask
+    /// for the binary encoding
+    #[structopt(long)]
+    pub text_only: bool,
+
+    /// Seconds to keep the subscriptions of a disconnected client which has
+    /// started a session, so that it can resume them when it reconnects.
+    /// Sessions are disabled when this is not set.
+    #[structopt(long)]
+    pub session_grace: Option<u64>,
+
+    /// Bytes that retained messages, along with their channels, may use in
+    /// total. The messages of the least recently used channels are evicted
+    /// to stay within it. Retained messages are not limited when this is not
+    /// set.
+    #[structopt(long)]
+    pub retained_memory: Option<usize>,
+
+    /// File to append a record of every published message to
+    #[structopt(long, parse(from_os_str))]
+    pub audit_log: Option<PathBuf>,
+
+    /// Include the body of each message in the audit log
+    #[structopt(long)]
+    pub audit_log_body: bool,
+
+    /// Size in bytes after which the audit log is rotated, keeping the
+    /// previous file with a `.1` suffix. The log is never rotated when this is
+    /// not set.
+    #[structopt(long)]
+    pub audit_log_max_size: Option<u64>,
+
+    /// PEM file holding the certificate chain to accept TLS connections with.
+    /// Clients must connect over TLS when this is set.
+    #[structopt(long, parse(from_os_str), requires = "tls-key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file holding the private key of `--tls-cert`
+    #[structopt(long, parse(from_os_str), requires = "tls-cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Options {
+    /// Builds the runtime configuration from the command line options.
+    pub fn config(&self) -> Config {
+        Config {
+            admin_token: self.admin_token.clone(),
+            auth_tokens: self.auth_token.iter().cloned().collect(),
+            acl: None,
+            presence: self.presence,
+            presence_timeout: self.presence_timeout.map(Duration::from_secs),
+            max_connections: self.max_connections,
+            workers: self.workers,
+            worker_queue: self.worker_queue,
+            max_message_size: self.max_message_size,
+            channel_separator: self.channel_separator,
+            sendto_admin_only: self.sendto_admin_only,
+            write_timeout: match self.write_timeout {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+            keepalive: self.keepalive.map(Duration::from_secs),
+            heartbeat: self.heartbeat.map(Duration::from_secs),
+            heartbeat_misses: self.heartbeat_misses,
+            delivery_retries: self.delivery_retries,
+            delivery_retry_delay: Duration::from_millis(self.delivery_retry_delay),
+            channel_rate: self.channel_rate,
+            channel_burst: self.channel_burst,
+            channel_rate_delay: self.channel_rate_delay,
+            client_rate: self.client_rate,
+            client_burst: self.client_burst,
+            dedup_window: self.dedup_window,
+            shutdown_timeout: Duration::from_millis(self.shutdown_timeout),
+            text_only: self.text_only,
+            session_grace: self.session_grace.map(Duration::from_secs),
+            retained_memory: self.retained_memory,
+        }
+    }
+}
+
+/// The runtime configuration used by the consumers. The defaults are those
+/// of the command line options, so that embedded servers behave like the
+/// server binary.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Token which grants a connection admin rights.
+    pub admin_token: Option<String>,
+
+    /// Tokens which allow a connection to use the broker. Clients have to
+    /// authenticate with one of them, or the admin token, before anything
+    /// else. Authentication is not required when there are none.
+    pub auth_tokens: HashSet<String>,
+
+    /// The channels each client may publish and subscribe to, by the token it
+    /// authenticated with. Admins may use every channel. Every client may use
+    /// every channel when not set.
+    pub acl: Option<Acl>,
+
+    /// Whether join and leave events are published on the presence channel.
+    pub presence: bool,
+
+    /// How long a client may go without a heartbeat before it is reaped from
+    /// the presence channel.
+    pub presence_timeout: Option<Duration>,
+
+    /// How many connections may be open at once before new connections are
+    /// rejected, so that an overloaded server refuses work quickly rather
+    /// than spawning a thread for every connection.
+    pub max_connections: Option<usize>,
+
+    /// How many threads handle connections. Defaults to four times the number
+    /// of CPUs when not set.
+    pub workers: Option<usize>,
+
+    /// How many connections may wait for a thread once every thread is busy.
+    /// Defaults to `DEFAULT_WORKER_QUEUE` when not set.
+    pub worker_queue: Option<usize>,
+
+    /// The size in bytes of the largest command a client may send, so that a
+    /// client cannot make the server allocate an arbitrary amount of memory.
+    /// Defaults to `MAX_MESSAGE_SIZE` when not set.
+    pub max_message_size: Option<usize>,
+
+    /// The character separating the channel from the body of a published
+    /// message. Defaults to `CHANNEL_SEPARATOR` when not set.
+    pub channel_separator: Option<char>,
+
+    /// Whether only admins may send messages directly to a client.
+    pub sendto_admin_only: bool,
+
+    /// How long a write to a client may block before it fails. Slow
+    /// subscribers would otherwise stall delivery to every other subscriber.
+    pub write_timeout: Option<Duration>,
+
+    /// How often every connection is probed, so that connections reset by the
+    /// client are closed even when no messages are being written to them.
+    pub keepalive: Option<Duration>,
+
+    /// How often every client is sent a heartbeat, so that clients which
+    /// vanished without disconnecting are removed rather than written to
+    /// forever.
+    pub heartbeat: Option<Duration>,
+
+    /// How many heartbeats in a row a client may leave unanswered before it
+    /// is disconnected.
+    pub heartbeat_misses: u32,
+
+    /// How many times a write to a subscriber which fails with a transient
+    /// error, such as timing out, is retried in a row. Other errors, such as
+    /// a broken pipe, are never retried.
+    pub delivery_retries: u32,
+
+    /// How long to wait before retrying a write to a subscriber.
+    pub delivery_retry_delay: Duration,
+
+    /// How many messages per second may be published to each channel, so
+    /// that one busy channel cannot monopolize the broker.
+    pub channel_rate: Option<u32>,
+
+    /// How many messages may be published to a channel at once before the
+    /// rate applies. Defaults to the rate.
+    pub channel_burst: Option<u32>,
+
+    /// Whether a publisher over a channel's rate waits for the rate to allow
+    /// the message, rather than the message being dropped.
+    pub channel_rate_delay: bool,
+
+    /// How many commands per second each client may send, so that one chatty
+    /// client cannot starve the others.
+    pub client_rate: Option<u32>,
+
+    /// How many commands a client may send at once before the rate applies.
+    /// Defaults to the rate.
+    pub client_burst: Option<u32>,
+
+    /// How many of the most recent message ids published by each client are
+    /// remembered to drop duplicates. Disabled when 0.
+    pub dedup_window: usize,
+
+    /// The most time spent delivering queued messages on shutdown before the
+    /// remaining messages are dropped.
+    pub shutdown_timeout: Duration,
+
+    /// Whether clients asking for the binary encoding are given text instead.
+    pub text_only: bool,
+
+    /// How long the subscriptions of a disconnected client are kept under its
+    /// session. Sessions are disabled when this is not set.
+    pub session_grace: Option<Duration>,
+
+    /// The most bytes retained messages and their channels may use, beyond
+    /// which the messages of the channels least recently retained or read are
+    /// evicted. Unlimited when this is not set.
+    pub retained_memory: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            admin_token: None,
+            auth_tokens: HashSet::new(),
+            acl: None,
+            presence: false,
+            presence_timeout: None,
+            max_connections: None,
+            workers: None,
+            worker_queue: None,
+            max_message_size: None,
+            channel_separator: None,
+            sendto_admin_only: false,
+            write_timeout: Some(Duration::from_millis(5000)),
+            keepalive: None,
+            heartbeat: None,
+            heartbeat_misses: 3,
+            delivery_retries: 3,
+            delivery_retry_delay: Duration::from_millis(10),
+            channel_rate: None,
+            channel_burst: None,
+            channel_rate_delay: false,
+            client_rate: None,
+            client_burst: None,
+            dedup_window: 0,
+            shutdown_timeout: Duration::from_millis(5000),
+            text_only: false,
+            session_grace: None,
+            retained_memory: None,
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that the default configuration is the one the command line
+    /// options give when none are set, so that writes to subscribers are
+    /// bounded, failed writes are retried and queued messages are delivered
+    /// on shutdown.
+    #[test]
+    fn test_default() {
+        let config = Config::default();
+        assert_eq!(config.write_timeout, Some(Duration::from_millis(5000)));
+        assert_eq!(config.shutdown_timeout, Duration::from_millis(5000));
+        assert_eq!(config.delivery_retries, 3);
+        assert_eq!(config.delivery_retry_delay, Duration::from_millis(10));
+        assert_eq!(config.heartbeat_misses, 3);
+        assert_eq!(
+            format!("{:?}", Options::from_iter(["server"]).config()),
+            format!("{:?}", config)
+        );
+    }
+}
@@ -5,73 +5,175 @@
 //! handling messages from the client and passing them onto the right function
 //! to handle them.
 
-use super::state;
+use super::state::{self, BrokerState};
+use super::stream::Stream;
+use super::{acl, config, protocol};
+use log::{debug, error, info, warn};
 use std::{
-    io::{BufWriter, Read, Write},
-    net::TcpStream,
+    borrow::Cow,
+    collections::HashSet,
+    fmt,
+    io::{self, BufReader, Read, Write},
+    net::Shutdown,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
+/// The channel on which join and leave events are published.
+pub const PRESENCE_CHANNEL: &str = "$presence";
+
+/// Marks the start of a system message delivered to a subscriber, so that
+/// operational notices can be told apart from regular messages.
+pub const SYSTEM_MESSAGE_MARKER: char = '\u{1}';
+
+/// Marks the start of a message sent directly to a client rather than
+/// published on a channel. The marker is followed by the id of the sender.
+pub const DIRECT_MESSAGE_MARKER: char = '\u{2}';
+
+/// Marks the start of a message delivered with its sequence number. The
+/// marker is followed by the number, a space, and the channel and message
+/// joined by the channel separator.
+pub const SEQUENCE_MARKER: char = '\u{3}';
+
+/// Marks the start of a request published on a channel. The marker is
+/// followed by the correlation id of the request, a space and the request.
+pub const REQUEST_MARKER: char = '\u{4}';
+
 /// A consumer for handling incoming messages. This is done by calling other
 /// functions to handle the message.
 /// # Arguments
+/// * `broker` - The state of the broker.
 /// * `client` - The stream to read from.
-pub fn consumer(client: &mut TcpStream) {
+pub fn consumer(broker: &BrokerState, client: &mut dyn Stream) {
     let mut connected = true;
+    let Some(client_id) = connect_handler(broker, client) else {
+        return;
+    };
 
-    while connected {
-        let mut buffer = [0; 64];
-        client.read(&mut buffer).unwrap();
-
-        if is_buffer_empty(&buffer) {
-            continue;
-        }
+    // Frames are read through a single buffered reader so that frames sent
+    // back-to-back are decoded one after another, however the bytes arrive.
+    let mut reader = BufReader::new(client);
+    // From here on the client is known by its id.
+    let client = client_id.as_str();
 
-        // Create a buffer to hold the message. As we know the message length
-        // from the initial message, we can create a buffer of the correct
-        // size.
-        let message_length = match String::from_utf8(buffer.to_vec()) {
-            Ok(message) => match message.trim().parse::<usize>() {
-                Ok(length) => length,
-                Err(e) => {
-                    println!("Error: {}", e);
-                    client.flush().unwrap();
-                    clear_buffer(&mut buffer);
-                    continue;
+    while connected {
+        let message = match read_message(broker, client, &mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(e) => {
+                // The client closing the connection, even part way through a
+                // frame, is an ordinary disconnect rather than an error.
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    error!("{}", e);
                 }
-            },
-            Err(_) => {
-                println!("Error: Failed to parse message length.");
-                client.flush().unwrap();
-                clear_buffer(&mut buffer);
-                continue;
+                disconnect_handler(broker, client);
+                break;
             }
         };
 
-        // Create a buffer to hold the message.
-        let mut message = vec![0; message_length.into()];
-
-        // Convert the message to a string.
-        client.read(&mut message).unwrap();
+        if message.is_empty() {
+            continue;
+        }
 
-        let [handler, message] =
-            get_message_components(String::from_utf8(message).unwrap().as_str());
+        // Only the command has to be text. Published payloads are kept as
+        // bytes, as they may not be UTF-8, and every other command reads its
+        // arguments as text, with anything which is not UTF-8 replaced.
+        let (handler, payload) = split_bytes(&message);
+        let handler = String::from_utf8_lossy(handler).into_owned();
+        let message = String::from_utf8_lossy(payload).into_owned();
 
         match handler.as_str() {
-            "SUBSCRIBE" => subscribe_handler(&client, &message),
-            "UNSUBSCRIBE" => unsubscribe_handler(&client, &message),
+            command if !within_rate_limit(broker, client, command) => {
+                warn!("Rate limited command: {}", command);
+                reply_error(broker, client, ErrorReply::new("rate limited"));
+            }
+            command if !is_permitted(broker, client, command) => {
+                warn!("Unauthenticated command: {}", command);
+                reply_error(broker, client, ErrorReply::new("unauthorized"));
+            }
+            "SUBSCRIBE" => {
+                subscribe_handler(broker, client, &message);
+            }
+            "SUBSCRIBE_ACK" => subscribe_ack_handler(broker, client, &message),
+            "UNSUBSCRIBE" => unsubscribe_handler(broker, client, &message),
+            "PSUBSCRIBE" => psubscribe_handler(broker, client, &message),
+            "PUNSUBSCRIBE" => punsubscribe_handler(broker, client, &message),
             "DISCONNECT" => {
                 connected = false;
-                disconnect_handler(&client);
+                goodbye_handler(broker, client);
+            }
+            "PUBLISH" => {
+                client_publish_handler(broker, client, payload, state::Priority::Normal);
+            }
+            "PUBLISH_PRIORITY" => publish_priority_handler(broker, client, payload),
+            "PUBLISH_ID" => publish_id_handler(broker, client, payload),
+            "PUBLISH_SYSTEM" => publish_system_handler(broker, client, &message),
+            "RETAIN" => retain_handler(broker, client, payload),
+            "REQUEST" => request_handler(broker, client, &message),
+            "REPLY" => reply_handler(broker, client, &message),
+            "AUTH" => auth_handler(broker, client, &message),
+            "HEARTBEAT" => heartbeat_handler(broker, client),
+            "NAME" => name_handler(broker, client, &message),
+            "CLIENTS" => clients_handler(broker, client),
+            "LIST" => list_handler(broker, client),
+            "COUNT" => count_handler(broker, client, &message),
+            "GET" => get_handler(broker, client, &message),
+            "ENCODING" => encoding_handler(broker, client, &message),
+            "SEQUENCE" => sequence_handler(broker, client),
+            "SESSION" => session_handler(broker, client),
+            "RESUME" => resume_handler(broker, client, &message),
+            "SHUTDOWN" => shutdown_handler(broker, client),
+            "DROP" => drop_handler(broker, client, &message),
+            "SENDTO" => sendto_handler(broker, client, &message),
+            "BROADCAST" => broadcast_handler(broker, client, &message),
+            "SUBSCRIBE_ALL" => subscribe_all_handler(broker, client),
+            "UNSUBSCRIBE_ALL" => unsubscribe_all_handler(broker, client),
+            "PING" => ping_handler(broker, client, &message),
+            "PONG" => broker.clients().answer_heartbeat(client),
+            "VERSION" => version_handler(broker, client),
+            "WHOAMI" => whoami_handler(broker, client),
+            "STATS" => stats_handler(broker, client),
+            _ => {
+                warn!("Unknown command: {}", handler);
+                reply_error(
+                    broker,
+                    client,
+                    ErrorReply::new(format!("unknown command: {}", handler)),
+                );
             }
-            "PUBLISH" => publish_handler(&message),
-            "PING" => ping_handler(&client),
-            _ => println!("Unknown command: {}", handler),
         }
-        client.flush().unwrap();
-        // Empty the buffer
-        clear_buffer(&mut buffer);
     }
-    println!("Client disconnected.");
+    info!("Client disconnected.");
+}
+
+/// Reads the next command sent by a client. A command larger than the
+/// configured limit is replied to with an error and discarded as it is read,
+/// so that it is never held in memory.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client sending the command.
+/// * `reader` - The reader the client's frames are read from.
+/// # Returns
+/// The command, or `None` if it was too large.
+fn read_message(
+    broker: &BrokerState,
+    client: &str,
+    reader: &mut impl Read,
+) -> io::Result<Option<Vec<u8>>> {
+    let length = protocol::read_length(reader)?;
+    let max_size = broker
+        .config()
+        .max_message_size
+        .unwrap_or(config::MAX_MESSAGE_SIZE);
+    if length <= max_size {
+        return protocol::read_payload(reader, length).map(Some);
+    }
+
+    warn!("Dropping a command of {} bytes", length);
+    reply_error(broker, client, ErrorReply::new("message too large"));
+    protocol::skip_payload(reader, length)?;
+    Ok(None)
 }
 
 /// Splits a message returning the method and the message.
@@ -83,200 +185,3461 @@ fn get_message_components(message: &str) -> [String; 2] {
     [method.to_string(), msg.to_string()]
 }
 
-/// Checks if buffer is in it's empty state.
+/// Splits a message at its first space, as `get_message_components` does,
+/// without requiring the message to be UTF-8.
 /// # Arguments
-/// * `buffer` - The buffer to check.
+/// * `message` - The message to split.
 /// # Returns
-/// * `bool` - Whether the buffer is empty.
-fn is_buffer_empty(buffer: &[u8]) -> bool {
-    buffer.iter().all(|&x| x == 0)
+/// The part before the first space, and the rest of the message.
+pub(crate) fn split_bytes(message: &[u8]) -> (&[u8], &[u8]) {
+    match message.iter().position(|&byte| byte == b' ') {
+        Some(at) => (&message[..at], &message[at + 1..]),
+        None => (message, &[]),
+    }
 }
 
-/// Set the buffer to the empty state.
+/// Checks that a channel name is usable.
 /// # Arguments
-/// * `buffer` - The buffer to set.
-fn clear_buffer(buffer: &mut [u8; 64]) {
-    for i in 0..64 {
-        buffer[i] = 0;
+/// * `channel` - The channel name to check.
+/// * `separator` - The character separating the channel from the body of a
+///   published message, which channel names cannot contain.
+/// # Returns
+/// The reason the channel name is invalid, if it is.
+pub fn validate_channel(channel: &str, separator: char) -> Result<(), String> {
+    if channel.trim().is_empty() {
+        return Err("channel name cannot be empty".to_string());
+    }
+    if channel.contains(separator) {
+        return Err(format!(
+            "channel name cannot contain the separator {:?}",
+            separator
+        ));
     }
+    Ok(())
 }
 
 /// Subscribes a client to a channel.
 /// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to subscribe.
+/// * `channel` - The channel to subscribe to.
+/// # Returns
+/// Whether the client was subscribed.
+fn subscribe_handler(broker: &BrokerState, client: &str, channel: &String) -> bool {
+    if let Err(e) = validate_channel(channel, broker.channel_separator()) {
+        reply_error(
+            broker,
+            client,
+            ErrorReply::new(format!("invalid channel: {}", e)),
+        );
+        return false;
+    }
+    if !check_acl(broker, client, acl::Operation::Subscribe, channel) {
+        return false;
+    }
+    debug!("Subscribing to channel: {}", channel);
+    broker.subscriptions().add_subscription(client, channel);
+
+    // A client subscribing to a status channel learns the current status
+    // straight away, rather than waiting for it to next change.
+    if let Some(message) = broker.retained(channel) {
+        deliver(
+            broker,
+            client,
+            state::QueuedMessage::new(state::Priority::Normal, channel, false, &message),
+        );
+    }
+    true
+}
+
+/// Subscribes a client to a channel, replying `ACK SUBSCRIBE <channel>` once
+/// the subscription is in place. Messages published after the reply is sent
+/// are delivered to the client.
+/// # Arguments
+/// * `broker` - The state of the broker.
 /// * `client` - The client to subscribe.
 /// * `channel` - The channel to subscribe to.
-fn subscribe_handler(client: &TcpStream, channel: &String) {
-    println!("Subscribing to channel: {}", channel);
-    state::Subscription {}.add_subscription(&client, &channel)
+fn subscribe_ack_handler(broker: &BrokerState, client: &str, channel: &String) {
+    if subscribe_handler(broker, client, channel) {
+        reply(broker, client, &format!("ACK SUBSCRIBE {}", channel));
+    }
+}
+
+/// Unsubscribes a client from a channel, and from the pattern of the same
+/// name if it has subscribed to one with `PSUBSCRIBE`, so that
+/// `UNSUBSCRIBE sensors.*` removes the pattern `sensors.*`. Subscriptions to
+/// channels the pattern matches, such as `sensors.hall`, are left in place.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to unsubscribe.
+/// * `channel` - The channel or pattern to unsubscribe from.
+fn unsubscribe_handler(broker: &BrokerState, client: &str, channel: &String) {
+    debug!("Unsubscribing from channel: {}", channel);
+    let subscriptions = broker.subscriptions();
+    subscriptions.remove_subscription(client, channel);
+    subscriptions.remove_pattern_subscription(client, channel);
+}
+
+/// Subscribes a client to every channel matching a pattern, see
+/// `state::matches_pattern`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to subscribe.
+/// * `pattern` - The pattern to subscribe to.
+fn psubscribe_handler(broker: &BrokerState, client: &str, pattern: &String) {
+    if let Err(e) = validate_channel(pattern, broker.channel_separator()) {
+        reply_error(
+            broker,
+            client,
+            ErrorReply::new(format!("invalid pattern: {}", e)),
+        );
+        return;
+    }
+    let allowed = match &broker.config().acl {
+        Some(acl) if !broker.clients().is_admin(client) => {
+            acl.allows_pattern(broker.clients().identity(client).as_deref(), pattern)
+        }
+        _ => true,
+    };
+    if !allowed {
+        warn!("Forbidden pattern subscription: {}", pattern);
+        reply_error(broker, client, ErrorReply::new("forbidden"));
+        return;
+    }
+    debug!("Subscribing to pattern: {}", pattern);
+    broker
+        .subscriptions()
+        .add_pattern_subscription(client, pattern);
 }
 
-/// Unsubscribes a client from a channel.
+/// Unsubscribes a client from a pattern.
 /// # Arguments
+/// * `broker` - The state of the broker.
 /// * `client` - The client to unsubscribe.
-/// * `channel` - The channel to unsubscribe from.
-fn unsubscribe_handler(client: &TcpStream, channel: &String) {
-    println!("Unsubscribing from channel: {}", channel);
-    state::Subscription {}.remove_subscription(&client, &channel)
+/// * `pattern` - The pattern to unsubscribe from.
+fn punsubscribe_handler(broker: &BrokerState, client: &str, pattern: &String) {
+    debug!("Unsubscribing from pattern: {}", pattern);
+    broker
+        .subscriptions()
+        .remove_pattern_subscription(client, pattern)
 }
 
 /// Removes a client from the collection of clients.
 /// # Arguments
+/// * `broker` - The state of the broker.
 /// * `client` - The client to disconnect.
 /// * `channel` - The channel to disconnect from.
-fn disconnect_handler(client: &TcpStream) {
-    println!("{} from {}", "DISCONNECT", client.peer_addr().unwrap());
-    state::Client {}.remove_client(client);
+fn disconnect_handler(broker: &BrokerState, client: &str) {
+    if let Some(addr) = broker.clients().addr(client) {
+        info!("DISCONNECT from {}", addr);
+    }
+    if let Some(grace) = broker.config().session_grace {
+        broker.sessions().suspend(client, grace);
+    }
+    broker.clients().remove_client(client);
+
+    let presence = broker.presence();
+    if let Some(identity) = presence.leave(client) {
+        publish_presence_event(broker, "LEAVE", &identity);
+    }
 }
 
-/// Publishes a messages to all clients subscribed to a channel.
+/// Handles a client disconnecting deliberately, replying `BYE` once the client
+/// has been removed so that the client knows it is gone.
 /// # Arguments
-/// * `channel` - The channel to publish to.
-/// * `message` - The message to publish.
-fn publish_handler(message: &String) {
-    // The message could contain both the channel name and the actual message.
-    // We need to split the message into two parts.
-    let msg_split_point = match message.find(" ") {
-        Some(point) => point,
-        None => {
-            println!("Error: Failed to parse message.");
-            return;
+/// * `broker` - The state of the broker.
+/// * `client` - The client disconnecting.
+fn goodbye_handler(broker: &BrokerState, client: &str) {
+    // The client's encoding and connection are forgotten along with the
+    // client.
+    let encoding = broker.clients().get_encoding(client);
+    let stream = broker.clients().stream(client);
+    // A client which disconnects deliberately has no use for its session.
+    broker.sessions().end(client);
+    disconnect_handler(broker, client);
+    // Nothing else is written to the client once it has been removed, but a
+    // delivery may still be part way through. Clients which do not wait for
+    // the reply may have closed the connection already.
+    let Some(stream) = stream else {
+        return;
+    };
+    let result = stream.lock().unwrap().write_all(&encode(encoding, b"BYE"));
+    if let Err(e) = result {
+        debug!("Failed to say goodbye: {}", e);
+    }
+}
+
+/// Handles a new client connection. When presence is enabled, the client is
+/// announced on the presence channel.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The connection of the client that has connected.
+/// # Returns
+/// The id the client is known by, or `None` if it could not be registered.
+fn connect_handler(broker: &BrokerState, client: &dyn Stream) -> Option<String> {
+    // Bound how long publishing may block on this client when it stops
+    // reading.
+    if client
+        .socket()
+        .set_write_timeout(broker.config().write_timeout)
+        .is_err()
+    {
+        warn!("Failed to set write timeout.");
+    }
+    let stream = match client.try_clone() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to register client: {}", e);
+            return None;
         }
     };
+    let client = broker.clients().add_client(stream);
 
-    let channel = &message[0..msg_split_point];
-    let message = &message[msg_split_point + 1..];
+    join_presence(broker, &client);
+    Some(client)
+}
 
-    let subscribers = state::Subscription {}.get_subscribers(&channel.to_string());
+/// Records a heartbeat from a client. A client that has been reaped from the
+/// presence channel rejoins it.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client that sent the heartbeat.
+fn heartbeat_handler(broker: &BrokerState, client: &str) {
+    let presence = broker.presence();
+    if !presence.heartbeat(client) {
+        join_presence(broker, client);
+    }
+}
 
-    // If there are no subscribers, we can return early.
-    if subscribers.is_empty() {
+/// Announces a client on the presence channel if presence is enabled and the
+/// client is not already present. Clients are announced by their id, which
+/// other clients are able to send direct messages to. Neither their address,
+/// which may be shared by many clients, nor the token they authenticated
+/// with, which is a secret, would do.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client that has joined.
+fn join_presence(broker: &BrokerState, client: &str) {
+    if !broker.config().presence {
         return;
     }
 
-    let message_length = format!("{}", message.len()).to_string();
-    let mut msg_size_buffer = [0; 64];
+    let presence = broker.presence();
+    if presence.join(client, client) {
+        publish_presence_event(broker, "JOIN", client);
+    }
+}
 
-    msg_size_buffer[0..message_length.len()].copy_from_slice(message_length.as_bytes());
-    msg_size_buffer[message_length.len()..]
-        .copy_from_slice(" ".repeat(64 - message_length.len()).as_bytes());
+/// Removes every client that has not sent a heartbeat within the timeout from
+/// the presence channel, publishing a leave event for each.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `timeout` - How long a client may go without a heartbeat.
+pub fn reap_presence(broker: &BrokerState, timeout: Duration) {
+    let presence = broker.presence();
+    for identity in presence.reap(timeout) {
+        publish_presence_event(broker, "LEAVE", &identity);
+    }
+}
 
-    let msg_bytes = message.as_bytes();
+/// Publishes a join or leave event on the presence channel.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `event` - The event type.
+/// * `identity` - The identity of the client the event is about.
+fn publish_presence_event(broker: &BrokerState, event: &str, identity: &str) {
+    publish_handler(
+        broker,
+        &format!(
+            "{}{}{} {}",
+            PRESENCE_CHANNEL,
+            broker.channel_separator(),
+            event,
+            identity
+        ),
+    );
+}
 
-    for subscriber in subscribers {
-        // A subscriber is a memory address, so we need to convert it to a
-        // TcpStream.
-        let stream = subscriber.parse::<usize>().unwrap() as *mut TcpStream;
-        let stream = unsafe { &mut *stream };
+/// Publishes a message sent by a client. Clients are not allowed to publish on
+/// the presence channel.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client publishing the message.
+/// * `message` - The channel and message to publish. Only the channel has to
+///   be UTF-8.
+/// * `priority` - The priority of the message.
+/// # Returns
+/// Whether the message was accepted for delivery.
+fn client_publish_handler(
+    broker: &BrokerState,
+    client: &str,
+    message: &[u8],
+    priority: state::Priority,
+) -> bool {
+    if !accept_publish(broker, client, message) {
+        return false;
+    }
+    publish(broker, message, priority, false);
+    true
+}
 
-        let mut writer = BufWriter::new(&*stream);
+/// Checks that a client may publish a message, replying with why not when it
+/// may not, and records the message to the audit log when it may.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client publishing the message.
+/// * `message` - The channel and message to publish.
+/// # Returns
+/// Whether the message may be published.
+fn accept_publish(broker: &BrokerState, client: &str, message: &[u8]) -> bool {
+    let Some((channel, _)) = broker.split_payload(message) else {
+        reply_error(broker, client, ErrorReply::new("bad publish format"));
+        return false;
+    };
+    if channel == PRESENCE_CHANNEL {
+        reply_error(broker, client, ErrorReply::new("reserved channel"));
+        return false;
+    }
+    if !check_acl(broker, client, acl::Operation::Publish, channel) {
+        return false;
+    }
+    if !wait_for_publish_token(broker, channel) {
+        reply(broker, client, &format!("RATE_LIMITED {}", channel));
+        return false;
+    }
+    broker.audit(client, message);
+    true
+}
 
-        match writer.write_all(msg_bytes) {
-            Ok(_) => (),
-            Err(_) => state::Subscription {}.remove_subscription(stream, &channel.to_string()),
-        };
+/// Checks that the ACL allows a client to use a channel, replying
+/// `ERR forbidden` when it does not. Admins, and every client of a broker
+/// without an ACL, may use every channel.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client.
+/// * `operation` - What the client is doing with the channel.
+/// * `channel` - The channel.
+/// # Returns
+/// Whether the client may go ahead.
+fn check_acl(broker: &BrokerState, client: &str, operation: acl::Operation, channel: &str) -> bool {
+    let Some(acl) = &broker.config().acl else {
+        return true;
+    };
+    if broker.clients().is_admin(client)
+        || acl.allows(
+            broker.clients().identity(client).as_deref(),
+            operation,
+            channel,
+        )
+    {
+        return true;
+    }
+    warn!("Forbidden {:?} on {}", operation, channel);
+    reply_error(broker, client, ErrorReply::new("forbidden"));
+    false
+}
 
-        // Flush and close the stream.
-        match writer.flush() {
-            Ok(_) => (),
-            Err(_) => state::Subscription {}.remove_subscription(stream, &channel.to_string()),
-        };
+/// Publishes a message and retains it on its channel, so that clients which
+/// subscribe to the channel later receive it straight away. The message is
+/// retained as the hooks transformed it, and not at all when a hook dropped
+/// it. A message with an empty body clears the retained message without being
+/// published.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client publishing the message.
+/// * `message` - The channel and message to retain.
+fn retain_handler(broker: &BrokerState, client: &str, message: &[u8]) {
+    match broker.split_payload(message) {
+        Some((channel, [])) => {
+            if check_acl(broker, client, acl::Operation::Publish, channel) {
+                broker.set_retained(channel, b"");
+            }
+        }
+        Some((channel, _)) => {
+            if !accept_publish(broker, client, message) {
+                return;
+            }
+            if let Some(body) = publish(broker, message, state::Priority::Normal, false) {
+                broker.set_retained(channel, &body);
+            }
+        }
+        None => reply_error(broker, client, ErrorReply::new("bad publish format")),
     }
 }
 
-/// Server ping. Responds with a PONG message.
+/// Publishes a request on a channel, remembering the requester so that the
+/// reply sent with `REPLY` is routed back to it. Subscribers receive the
+/// request marked with `REQUEST_MARKER` and its correlation id.
 /// # Arguments
-/// * `client` - The client to ping.
-fn ping_handler(client: &TcpStream) {
-    println!("Got ping from {}", client.peer_addr().unwrap());
-    let mut writer = BufWriter::new(client);
-    match writer.write_all(b"PONG\n") {
-        Ok(_) => (),
-        Err(_) => println!("WARNING: Failed to write to client."),
+/// * `broker` - The state of the broker.
+/// * `client` - The client sending the request.
+/// * `message` - The correlation id, channel and request.
+fn request_handler(broker: &BrokerState, client: &str, message: &str) {
+    let [id, message] = get_message_components(message);
+    let Some((channel, body)) = broker.split_channel(&message) else {
+        reply_error(broker, client, ErrorReply::new("bad request format"));
+        return;
     };
-    match writer.flush() {
-        Ok(_) => (),
-        Err(_) => println!("WARNING: Failed to flush writer."),
+    if id.is_empty() {
+        reply_error(broker, client, ErrorReply::new("missing correlation id"));
+        return;
+    }
+    if !broker.add_request(&id, client) {
+        reply_error(broker, client, ErrorReply::new("duplicate correlation id"));
+        return;
+    }
+
+    let request = format!(
+        "{}{}{}{} {}",
+        channel,
+        broker.channel_separator(),
+        REQUEST_MARKER,
+        id,
+        body
+    );
+    // A request which was not published will never be replied to.
+    if !client_publish_handler(broker, client, request.as_bytes(), state::Priority::Normal) {
+        broker.take_request(&id);
+    }
+}
+
+/// Routes a reply to the client which sent the request, as
+/// `REPLY <correlation-id> <reply>`. Each request is only replied to once.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client replying.
+/// * `message` - The correlation id of the request and the reply.
+fn reply_handler(broker: &BrokerState, client: &str, message: &str) {
+    let [id, reply] = get_message_components(message);
+    let Some(requester) = broker.take_request(&id) else {
+        reply_error(
+            broker,
+            client,
+            ErrorReply::new(format!("unknown request: {}", id)),
+        );
+        return;
     };
+    let payload = format!("REPLY {} {}", id, reply);
+    deliver(
+        broker,
+        &requester,
+        state::QueuedMessage::new(state::Priority::Normal, "", false, payload.as_bytes()),
+    );
 }
 
-/// Unit tests
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::net::TcpStream;
+/// Publishes a message carrying an id chosen by the client, replying
+/// `ACK <id>` once the message is accepted. A message with the same id as one
+/// the client published recently is acknowledged again without being
+/// delivered, so a client can safely retry a message whose acknowledgement it
+/// missed.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client publishing the message.
+/// * `message` - The id, channel and message to publish.
+fn publish_id_handler(broker: &BrokerState, client: &str, message: &[u8]) {
+    let (id, message) = split_bytes(message);
+    let id = String::from_utf8_lossy(id);
+    if broker.is_duplicate(client, &id) {
+        debug!("Dropping duplicate message: {}", id);
+    } else if client_publish_handler(broker, client, message, state::Priority::Normal) {
+        broker.record_message_id(client, &id);
+    } else {
+        return;
+    }
+    reply(broker, client, &format!("ACK {}", id));
+}
+
+/// Takes a token from a channel's rate limit. When configured to delay
+/// publishers, waits until a token is available.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `channel` - The channel being published to.
+/// # Returns
+/// False if the message must be dropped as the channel is over its rate.
+fn wait_for_publish_token(broker: &BrokerState, channel: &str) -> bool {
+    loop {
+        match broker.take_publish_token(channel) {
+            Ok(()) => return true,
+            Err(wait) if broker.config().channel_rate_delay && wait != Duration::MAX => {
+                thread::sleep(wait)
+            }
+            Err(_) => return false,
+        }
+    }
+}
 
-    /// Helper function to create a client (`TcpStream`).
-    fn get_client() -> TcpStream {
-        TcpStream::connect("localhost:8080").unwrap()
+/// Publishes a system message, such as a notice that a channel will close.
+/// Subscribers receive system messages starting with the system message
+/// marker. This is restricted to admin clients.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client publishing the message.
+/// * `message` - The channel and message to publish.
+fn publish_system_handler(broker: &BrokerState, client: &str, message: &str) {
+    let is_admin = broker.clients().is_admin(client);
+    if !is_admin {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
     }
+    if broker.split_channel(message).is_none() {
+        reply_error(broker, client, ErrorReply::new("bad publish format"));
+        return;
+    }
+    broker.audit(client, message.as_bytes());
+    publish(broker, message.as_bytes(), state::Priority::High, true);
+}
 
-    /// Test that the function is able to split a message into it's components.
-    #[test]
-    fn test_get_message_components() {
-        let [method, message] = get_message_components("SUBSCRIBE test");
-        assert_eq!(method, "SUBSCRIBE");
-        assert_eq!(message, "test");
+/// Publishes a messages to all clients subscribed to a channel.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `message` - The channel and message to publish.
+fn publish_handler(broker: &BrokerState, message: &str) {
+    publish(broker, message.as_bytes(), state::Priority::Normal, false);
+}
+
+/// Publishes a message with a priority. Higher priority messages are
+/// delivered ahead of lower priority messages still queued for a subscriber.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client publishing the message.
+/// * `message` - The priority, channel and message to publish.
+fn publish_priority_handler(broker: &BrokerState, client: &str, message: &[u8]) {
+    let (priority, message) = split_bytes(message);
+    match String::from_utf8_lossy(priority).parse::<state::Priority>() {
+        Ok(priority) => {
+            client_publish_handler(broker, client, message, priority);
+        }
+        Err(e) => reply_error(broker, client, ErrorReply::new(e.to_string())),
     }
+}
 
-    /// Test that the function is able to split a message into multiple
-    /// components even when the message has spaces in it.
-    #[test]
-    fn test_get_message_components_multi_space() {
-        let [method, message] = get_message_components("SUBSCRIBE test channel");
-        assert_eq!(method, "SUBSCRIBE");
-        assert_eq!(message, "test channel");
+/// Publishes a message to all clients subscribed to a channel, including
+/// catch-all subscribers.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `message` - The channel and message to publish. Only the channel has to
+///   be UTF-8.
+/// * `priority` - The priority of the message.
+/// # Returns
+/// The body of the message as delivered, once the hooks have transformed it,
+/// or `None` if it could not be parsed or a hook dropped it.
+fn publish(
+    broker: &BrokerState,
+    message: &[u8],
+    priority: state::Priority,
+    system: bool,
+) -> Option<Vec<u8>> {
+    let (channel, message) = match broker.split_payload(message) {
+        Some(parts) => parts,
+        None => {
+            warn!("Failed to parse message.");
+            return None;
+        }
+    };
+
+    let message = broker.apply_hooks(channel, message.to_vec())?;
+    broker.metrics().published(message.len());
+    // Numbered even when nobody is subscribed, so that a subscriber which
+    // reconnects can tell it missed the message.
+    let sequence = broker.next_sequence(channel);
+
+    let subscription = broker.subscriptions();
+    let catch_all_subscribers = subscription.get_catch_all_subscribers();
+
+    // Catch-all subscribers receive their own copy of the message (including
+    // the channel name) so they are skipped when delivering to the channel.
+    let subscribers: HashSet<String> = subscription
+        .get_subscribers(&channel.to_string())
+        .difference(&catch_all_subscribers)
+        .cloned()
+        .collect();
+
+    // Pattern subscribers are sent the message once, however many of their
+    // patterns match, and not at all when they already receive it through
+    // another subscription.
+    let pattern_subscribers: HashSet<String> = subscription
+        .get_pattern_subscribers(channel)
+        .into_iter()
+        .filter(|id| !subscribers.contains(id) && !catch_all_subscribers.contains(id))
+        .collect();
+
+    // If there are no subscribers, we can return early.
+    if subscribers.is_empty() && catch_all_subscribers.is_empty() && pattern_subscribers.is_empty()
+    {
+        return Some(message);
     }
 
-    /// Test that the function is able to correctly identify an empty buffer.
-    #[test]
-    fn test_is_buffer_empty_true() {
-        let buffer = [0; 64];
-        assert!(is_buffer_empty(&buffer));
+    // System messages are marked so that subscribers can tell them apart from
+    // regular messages.
+    let marker = match system {
+        true => SYSTEM_MESSAGE_MARKER.to_string(),
+        false => String::new(),
+    };
+    let marked_message = [marker.as_bytes(), &message].concat();
+    let sequence_header = format!(
+        "{}{} {}{}",
+        SEQUENCE_MARKER,
+        sequence,
+        channel,
+        broker.channel_separator()
+    );
+
+    for subscriber in subscribers {
+        let payload = sequenced(broker, &subscriber, &sequence_header, &marked_message);
+        deliver(
+            broker,
+            &subscriber,
+            state::QueuedMessage::new(priority, channel, false, &payload),
+        );
     }
 
-    /// Test that the function is able to correctly identify a non-empty
-    /// buffer.
-    #[test]
-    fn test_is_buffer_empty_false() {
-        let mut buffer = [0; 64];
-        buffer[0] = 1;
-        assert!(!is_buffer_empty(&buffer));
+    // Catch-all and pattern subscribers need to know which channel the
+    // message was published on, which is joined to the message with the
+    // channel separator so that it can be split off again.
+    let catch_all_message = [
+        format!("{}{}{}", marker, channel, broker.channel_separator()).as_bytes(),
+        &message,
+    ]
+    .concat();
+
+    for subscriber in pattern_subscribers {
+        let payload = sequenced(broker, &subscriber, &sequence_header, &catch_all_message);
+        deliver(
+            broker,
+            &subscriber,
+            state::QueuedMessage::new(priority, channel, false, &payload),
+        );
     }
 
-    /// Test that the function is able to clear a buffer.
-    #[test]
-    fn test_clear_buffer() {
-        let mut buffer = [0; 64];
-        buffer[0] = 1;
-        clear_buffer(&mut buffer);
-        assert!(buffer.iter().all(|&x| x == 0));
+    for subscriber in catch_all_subscribers {
+        let payload = sequenced(broker, &subscriber, &sequence_header, &catch_all_message);
+        deliver(
+            broker,
+            &subscriber,
+            state::QueuedMessage::new(priority, channel, true, &payload),
+        );
     }
+    Some(message)
+}
 
-    /// Test that the function is to subscribe a client to a channel.
-    #[test]
-    fn test_subscribe_handler() {
-        let client = get_client();
-        let channel = "test".to_string();
-        subscribe_handler(&client, &channel);
-        assert!(state::Subscription {}.is_subscribed(&client, &channel));
+/// Prefixes a message with its sequence number for subscribers which asked
+/// for sequence numbers.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `subscriber` - The id of the subscriber the message is for.
+/// * `header` - The sequence marker, number and channel of the message.
+/// * `message` - The message.
+fn sequenced<'a>(
+    broker: &BrokerState,
+    subscriber: &str,
+    header: &str,
+    message: &'a [u8],
+) -> Cow<'a, [u8]> {
+    match broker.clients().is_sequenced(subscriber) {
+        true => Cow::Owned([header.as_bytes(), message].concat()),
+        false => Cow::Borrowed(message),
     }
+}
 
-    /// Test that the function is to unsubscribe a client from a channel.
-    #[test]
-    fn test_unsubscribe_handler() {
-        let client = get_client();
-        let channel = "test".to_string();
-        state::Subscription {}.add_subscription(&client, &channel);
-        unsubscribe_handler(&client, &channel);
-        assert!(!state::Subscription {}.is_subscribed(&client, &channel));
+/// Queues a message for a subscriber and delivers everything queued for it.
+/// A subscriber that cannot be written to is unsubscribed.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `subscriber` - The subscriber to deliver to.
+/// * `message` - The message to deliver.
+fn deliver(broker: &BrokerState, subscriber: &str, message: state::QueuedMessage) {
+    // A subscriber without an outbox has disconnected, so its stream may
+    // already be gone.
+    let Some(outbox) = broker.outbox(subscriber) else {
+        return;
+    };
+    outbox.push(message);
+    outbox.deliver(|message| write_queued_message(broker, subscriber, message));
+}
+
+/// Writes a queued message to a subscriber. A subscriber that cannot be
+/// written to is unsubscribed and its connection closed, see
+/// `prune_subscriber`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `subscriber` - The subscriber to write to.
+/// * `message` - The message to write.
+fn write_queued_message(broker: &BrokerState, subscriber: &str, message: state::QueuedMessage) {
+    if write_to_subscriber(broker, subscriber, &message.payload).is_err() {
+        let subscription = broker.subscriptions();
+        if message.catch_all {
+            subscription.remove_catch_all(subscriber);
+        } else {
+            subscription.remove_subscription(subscriber, &message.channel);
+        }
+        if let Some(stream) = broker.clients().stream(subscriber) {
+            prune_subscriber(&**stream.lock().unwrap());
+        }
     }
+}
 
-    /// Test that the function is to disconnect a client.
-    #[test]
-    fn test_disconnect_handler() {
-        let client = get_client();
-        state::Client {}.add_client(&client);
-        disconnect_handler(&client);
-        assert!(!state::Client {}.is_registered(&client));
+/// Closes the connection of a subscriber which can no longer be written to,
+/// such as one which has stopped reading or has gone away. Part of a message
+/// may have been written, so nothing written afterwards could be read
+/// correctly. Closing the connection ends its consumer, which cleans up the
+/// rest of the client's state.
+/// # Arguments
+/// * `stream` - The subscriber's connection.
+fn prune_subscriber(stream: &dyn Stream) {
+    if let Ok(addr) = stream.socket().peer_addr() {
+        info!("Pruning subscriber {}", addr);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Probes the socket of every client for an error, or for having lost its
+/// peer, which happens once the client has reset the connection, and closes
+/// the connections that have. This finds clients which have gone away while
+/// nothing was being written to them, and works the same over TLS as nothing
+/// is written. A client which vanished without resetting the connection,
+/// such as one whose host lost power, is not found, see `send_heartbeats`
+/// for that. A client which is connected but has stopped reading is only
+/// found once writing to it times out.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// # Returns
+/// The number of connections closed.
+pub fn probe_clients(broker: &BrokerState) -> usize {
+    let mut pruned = 0;
+    for client in broker.clients().ids() {
+        // The probe waits its turn behind any message being written, and is
+        // skipped once the client has disconnected.
+        let Some(outbox) = broker.outbox(&client) else {
+            continue;
+        };
+        outbox.write_now(
+            || {
+                let Some(stream) = broker.clients().stream(&client) else {
+                    return;
+                };
+                let stream = stream.lock().unwrap();
+                let socket = stream.socket();
+                if !matches!(socket.take_error(), Ok(None)) || socket.peer_addr().is_err() {
+                    prune_subscriber(&**stream);
+                    pruned += 1;
+                }
+            },
+            |message| write_queued_message(broker, &client, message),
+        );
+    }
+    pruned
+}
+
+/// Sends every client a `PING` heartbeat, which the client answers with
+/// `PONG`. A client which has left more than `misses` heartbeats in a row
+/// unanswered has its connection closed instead, so that its consumer removes
+/// it as if it had disconnected.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `misses` - How many heartbeats in a row a client may leave unanswered.
+/// # Returns
+/// The number of connections closed.
+pub fn send_heartbeats(broker: &BrokerState, misses: u32) -> usize {
+    let mut pruned = 0;
+    for client in broker.clients().ids() {
+        let Some(outbox) = broker.outbox(&client) else {
+            continue;
+        };
+        let Some(unanswered) = broker.clients().send_heartbeat(&client) else {
+            continue;
+        };
+        outbox.write_now(
+            || {
+                let encoding = broker.clients().get_encoding(&client);
+                let Some(stream) = broker.clients().stream(&client) else {
+                    return;
+                };
+                let mut stream = stream.lock().unwrap();
+                if unanswered > misses || stream.write_all(&encode(encoding, b"PING")).is_err() {
+                    prune_subscriber(&**stream);
+                    pruned += 1;
+                }
+            },
+            |message| write_queued_message(broker, &client, message),
+        );
+    }
+    pruned
+}
+
+/// Tells every client that the server is shutting down, with a `SHUTDOWN`
+/// notice written after anything already being delivered to the client.
+/// # Arguments
+/// * `broker` - The state of the broker.
+pub fn notify_shutdown(broker: &BrokerState) {
+    for client in broker.clients().ids() {
+        let Some(outbox) = broker.outbox(&client) else {
+            continue;
+        };
+        outbox.write_now(
+            || {
+                let encoding = broker.clients().get_encoding(&client);
+                if let Some(stream) = broker.clients().stream(&client) {
+                    // The client may already be gone, in which case there is
+                    // nobody to tell.
+                    let _ = stream
+                        .lock()
+                        .unwrap()
+                        .write_all(&encode(encoding, b"SHUTDOWN"));
+                }
+            },
+            |message| write_queued_message(broker, &client, message),
+        );
+    }
+}
+
+/// Delivers the messages still queued for subscribers, waiting for other
+/// threads that are part way through delivering.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `timeout` - The most time to spend delivering. A write that is already
+///   in progress may take up to the write timeout to finish.
+/// # Returns
+/// True if every queued message was delivered.
+pub fn flush_outboxes(broker: &BrokerState, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pending: Vec<(String, Arc<state::Outbox>)> = broker
+            .all_outboxes()
+            .into_iter()
+            .filter(|(_, outbox)| !outbox.is_empty())
+            .collect();
+        if pending.is_empty() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        for (subscriber, outbox) in pending {
+            outbox.deliver(|message| write_queued_message(broker, &subscriber, message));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Shuts down the server once the messages queued for subscribers have been
+/// delivered, or the shutdown timeout has passed. This is restricted to
+/// admin clients. The process running the server is left running, and closes
+/// the connections once it sees the server has stopped, see
+/// `server::RunningServer::is_stopped`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client requesting the shutdown.
+fn shutdown_handler(broker: &BrokerState, client: &str) {
+    let is_admin = broker.clients().is_admin(client);
+    if !is_admin {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
+    }
+
+    info!("Shutting down");
+    reply(broker, client, "OK");
+    stop(broker);
+}
+
+/// Stops the broker accepting connections, delivers the messages still queued
+/// for subscribers, for at most the shutdown timeout, and then tells every
+/// client `SHUTDOWN`. Only the first call does anything, so that clients are
+/// told once however the broker is shut down.
+/// # Arguments
+/// * `broker` - The state of the broker.
+pub fn stop(broker: &BrokerState) {
+    if !broker.stop() {
+        return;
+    }
+    if !flush_outboxes(broker, broker.config().shutdown_timeout) {
+        warn!("Shutting down with undelivered messages.");
+    }
+    notify_shutdown(broker);
+    broker.flush_audit_log();
+}
+
+/// Sends a message directly to a single client, replying `DELIVERED <id>`, or
+/// `NOT_FOUND <id>` if no client has that id. The recipient receives the
+/// message prefixed with the direct message marker and the id of the sender,
+/// so that it can reply.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client sending the message.
+/// * `message` - The id of the recipient and the message.
+fn sendto_handler(broker: &BrokerState, client: &str, message: &str) {
+    if broker.config().sendto_admin_only && !broker.clients().is_admin(client) {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
+    }
+    let [recipient, message] = get_message_components(message);
+    // The id is turned back into a stream when writing, so it must belong to
+    // a connected client.
+    if !broker.clients().is_connected(&recipient) {
+        reply(broker, client, &format!("NOT_FOUND {}", recipient));
+        return;
+    }
+
+    let payload = format!("{}{} {}", DIRECT_MESSAGE_MARKER, client, message);
+    deliver(
+        broker,
+        &recipient,
+        state::QueuedMessage::new(state::Priority::Normal, "", false, payload.as_bytes()),
+    );
+    reply(broker, client, &format!("DELIVERED {}", recipient));
+}
+
+/// Sends a message to every connected client, whatever it is subscribed to,
+/// including the client broadcasting it. Once the broker has an admin token,
+/// broadcasting requires admin rights. Clients which cannot be written to are
+/// disconnected, as subscribers are, see `write_queued_message`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client broadcasting the message.
+/// * `message` - The message to broadcast.
+fn broadcast_handler(broker: &BrokerState, client: &str, message: &str) {
+    if broker.config().admin_token.is_some() && !broker.clients().is_admin(client) {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
+    }
+    if message.is_empty() {
+        reply_error(broker, client, ErrorReply::new("missing message"));
+        return;
+    }
+
+    let recipients = broker.clients().ids();
+    info!("Broadcasting to {} clients", recipients.len());
+    for recipient in recipients {
+        deliver(
+            broker,
+            &recipient,
+            state::QueuedMessage::new(state::Priority::Normal, "", false, message.as_bytes()),
+        );
+    }
+}
+
+/// Unsubscribes every client from a channel, without disconnecting them,
+/// replying `DROPPED <count>` with the number of clients unsubscribed.
+/// Requires admin rights.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client dropping the channel.
+/// * `message` - The channel, optionally followed by a notice which is sent to
+///   each unsubscribed client as a system message.
+fn drop_handler(broker: &BrokerState, client: &str, message: &str) {
+    let is_admin = broker.clients().is_admin(client);
+    if !is_admin {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
+    }
+    let [channel, notice] = get_message_components(message.trim_end());
+    if channel.is_empty() {
+        reply_error(broker, client, ErrorReply::new("missing channel"));
+        return;
+    }
+
+    info!("Dropping channel: {}", channel);
+    let dropped = broker.subscriptions().remove_channel(&channel);
+    broker.set_retained(&channel, b"");
+    if !notice.is_empty() {
+        let notice = format!("{}{}", SYSTEM_MESSAGE_MARKER, notice);
+        for subscriber in &dropped {
+            deliver(
+                broker,
+                subscriber,
+                state::QueuedMessage::new(
+                    state::Priority::High,
+                    &channel,
+                    false,
+                    notice.as_bytes(),
+                ),
+            );
+        }
+    }
+    reply(broker, client, &format!("DROPPED {}", dropped.len()));
+}
+
+/// Writes a message to a subscriber. Writes that fail with a transient error
+/// are retried, see `write_with_retry`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `subscriber` - The subscriber to write to.
+/// * `message` - The message to write.
+/// # Returns
+/// An error if the subscriber is no longer connected or the write failed, so
+/// that the caller is able to clean up the subscription.
+fn write_to_subscriber(broker: &BrokerState, subscriber: &str, message: &[u8]) -> io::Result<()> {
+    let stream = broker
+        .clients()
+        .stream(subscriber)
+        .ok_or(io::ErrorKind::NotConnected)?;
+
+    let message = encode(broker.clients().get_encoding(subscriber), message);
+    let config = broker.config();
+    let result = write_with_retry(
+        &mut **stream.lock().unwrap(),
+        &message,
+        config.delivery_retries,
+        config.delivery_retry_delay,
+    );
+    if let Err(e) = &result {
+        warn!("Failed to write to subscriber: {}", e);
+    }
+    result
+}
+
+/// Writes a message, retrying writes which fail with a transient error, such
+/// as a write timing out while the reader catches up. Writing resumes from
+/// where it stopped so that a partly written message is not repeated. Any
+/// other error, such as a broken pipe, fails straight away.
+/// # Arguments
+/// * `writer` - The writer to write to.
+/// * `message` - The message to write.
+/// * `retries` - The most times to retry in a row without making progress.
+/// * `delay` - How long to wait before each retry.
+fn write_with_retry(
+    mut writer: impl Write,
+    message: &[u8],
+    retries: u32,
+    delay: Duration,
+) -> io::Result<()> {
+    let mut written = 0;
+    let mut attempts = 0;
+    while written < message.len() {
+        match writer.write(&message[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(length) => {
+                written += length;
+                attempts = 0;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) && attempts < retries =>
+            {
+                attempts += 1;
+                thread::sleep(delay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    writer.flush()
+}
+
+/// Authenticates a client with one of the auth tokens or the admin token. A
+/// client that provides the admin token is also granted admin rights.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to authenticate.
+/// * `token` - The token provided by the client.
+fn auth_handler(broker: &BrokerState, client: &str, token: &str) {
+    let config = broker.config();
+    let admin = config.admin_token.as_deref() == Some(token);
+    if admin || config.auth_tokens.contains(token) {
+        broker.clients().set_authenticated(client, token);
+        if admin {
+            broker.clients().set_admin(client);
+        }
+        reply(broker, client, "OK");
+        return;
+    }
+    match broker.clients().addr(client) {
+        Some(addr) => warn!("Failed authentication from {}", addr),
+        None => warn!("Failed authentication"),
+    }
+    reply_error(broker, client, ErrorReply::new("unauthorized"));
+}
+
+/// Checks whether a client may run a command. Once the broker has auth
+/// tokens, see `config::Config::auth_tokens`, a client which has not
+/// authenticated may only authenticate, ping, answer heartbeats, ask for the
+/// version and disconnect.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client running the command.
+/// * `command` - The command.
+fn is_permitted(broker: &BrokerState, client: &str, command: &str) -> bool {
+    broker.config().auth_tokens.is_empty()
+        || matches!(command, "AUTH" | "PING" | "PONG" | "VERSION" | "DISCONNECT")
+        || broker.clients().is_authenticated(client)
+}
+
+/// Checks whether a client's rate limit allows it to run a command, see
+/// `config::Config::client_rate`. Disconnecting and answering heartbeats are
+/// never limited, so that a busy client is not also disconnected for missing
+/// heartbeats.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client running the command.
+/// * `command` - The command.
+fn within_rate_limit(broker: &BrokerState, client: &str, command: &str) -> bool {
+    matches!(command, "PONG" | "DISCONNECT") || broker.clients().take_command_token(client).is_ok()
+}
+
+/// Subscribes a client to every message published on every channel. This is
+/// restricted to admin clients.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to subscribe.
+fn subscribe_all_handler(broker: &BrokerState, client: &str) {
+    let is_admin = broker.clients().is_admin(client);
+    if !is_admin {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
+    }
+    debug!("Subscribing to all channels");
+    broker.subscriptions().add_catch_all(client)
+}
+
+/// Removes a client's catch-all subscription.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to unsubscribe.
+fn unsubscribe_all_handler(broker: &BrokerState, client: &str) {
+    debug!("Unsubscribing from all channels");
+    broker.subscriptions().remove_catch_all(client)
+}
+
+/// Starts a session for a client, replying `SESSION <id>`. Should the client
+/// disconnect, its subscriptions are kept for the session grace period so
+/// that it can resume them using `RESUME <id>`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client starting the session.
+fn session_handler(broker: &BrokerState, client: &str) {
+    if broker.config().session_grace.is_none() {
+        reply_error(broker, client, ErrorReply::new("sessions disabled"));
+        return;
+    }
+    match broker.sessions().start(client) {
+        Some(session_id) => reply(broker, client, &format!("SESSION {}", session_id)),
+        None => reply_error(broker, client, ErrorReply::new("unknown client")),
+    }
+}
+
+/// Resumes a session started by a client that has since disconnected,
+/// restoring its subscriptions to this client. Replies `RESUMED <id>`, after
+/// which the session belongs to this client. Messages published while the
+/// session was suspended are not delivered.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client resuming the session.
+/// * `session_id` - The id of the session.
+fn resume_handler(broker: &BrokerState, client: &str, session_id: &str) {
+    if broker.config().session_grace.is_none() {
+        reply_error(broker, client, ErrorReply::new("sessions disabled"));
+        return;
+    }
+    match broker.sessions().resume(client, session_id.trim()) {
+        true => reply(broker, client, &format!("RESUMED {}", session_id.trim())),
+        false => reply_error(broker, client, ErrorReply::new("unknown session")),
+    }
+}
+
+/// Sets the name a client is listed under.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to name.
+/// * `name` - The name of the client.
+fn name_handler(broker: &BrokerState, client: &str, name: &str) {
+    broker.clients().set_name(client, name.trim());
+}
+
+/// Lists every connected client. This is restricted to admin clients.
+/// Each client is written as a `CLIENT <id> <addr> <subscriptions> [name]`
+/// line, followed by an `END` line.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client requesting the list.
+fn clients_handler(broker: &BrokerState, client: &str) {
+    let client_state = broker.clients();
+    if !client_state.is_admin(client) {
+        reply_error(broker, client, ErrorReply::new("unauthorized"));
+        return;
+    }
+
+    let mut lines: Vec<String> = client_state
+        .list_clients()
+        .iter()
+        .map(|info| {
+            let record = format!("CLIENT {} {} {}", info.id, info.addr, info.subscriptions);
+            match &info.name {
+                Some(name) => format!("{} {}", record, name),
+                None => record,
+            }
+        })
+        .collect();
+    lines.push("END".to_string());
+    for line in lines {
+        reply(broker, client, &line);
+    }
+}
+
+/// Lists every channel with at least one subscriber. Each channel is written
+/// as a `CHANNEL <name>` line, followed by an `END` line.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client requesting the list.
+fn list_handler(broker: &BrokerState, client: &str) {
+    for channel in broker.subscriptions().list_channels() {
+        reply(broker, client, &format!("CHANNEL {}", channel));
+    }
+    reply(broker, client, "END");
+}
+
+/// Counts the clients subscribed to a channel, without subscribing the client
+/// asking, replying `COUNT <channel> <count>`. Catch-all subscribers are not
+/// counted.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client asking.
+/// * `channel` - The channel to count the subscribers of.
+fn count_handler(broker: &BrokerState, client: &str, channel: &str) {
+    let count = broker
+        .subscriptions()
+        .get_subscribers(&channel.to_string())
+        .len();
+    reply(broker, client, &format!("COUNT {} {}", channel, count));
+}
+
+/// Replies with the message retained on a channel, without subscribing the
+/// client asking, as `RETAINED <channel>` followed by the channel separator
+/// and the message. Nothing follows the channel when no message is retained.
+/// Reading a channel requires the same rights as subscribing to it.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client asking.
+/// * `channel` - The channel to read the retained message of.
+fn get_handler(broker: &BrokerState, client: &str, channel: &str) {
+    if !check_acl(broker, client, acl::Operation::Subscribe, channel) {
+        return;
+    }
+    let mut message = format!("RETAINED {}", channel).into_bytes();
+    if let Some(retained) = broker.retained(channel) {
+        message.extend_from_slice(broker.channel_separator().to_string().as_bytes());
+        message.extend_from_slice(&retained);
+    }
+    reply_bytes(broker, client, &message);
+}
+
+/// Replies with the counters of the broker, as `STATS` followed by a JSON
+/// object, see `metrics::Stats`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client asking.
+fn stats_handler(broker: &BrokerState, client: &str) {
+    reply(
+        broker,
+        client,
+        &format!("STATS {}", broker.stats().to_json()),
+    );
+}
+
+/// Negotiates how messages are written to a client. The reply, `ENCODING`
+/// followed by the encoding the client will receive, is written using the
+/// previous encoding, and the encoding is switched before anything else is
+/// written to the client. Unknown encodings, and the binary encoding when the
+/// server only allows text, are downgraded to text.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client negotiating its encoding.
+/// * `encoding` - The encoding the client would prefer.
+fn encoding_handler(broker: &BrokerState, client: &str, encoding: &str) {
+    let encoding = negotiate_encoding(encoding, broker.config().text_only);
+    let written = broker.outbox(client).is_some_and(|outbox| {
+        outbox.write_now(
+            || {
+                let message = format!("ENCODING {}", encoding);
+                let _ = write_to_subscriber(broker, client, message.as_bytes());
+                broker.clients().set_encoding(client, encoding);
+            },
+            |message| write_queued_message(broker, client, message),
+        )
+    });
+    if !written {
+        warn!("Failed to write to client.");
+    }
+}
+
+/// Chooses the encoding a client asking for one is given.
+/// # Arguments
+/// * `requested` - The encoding the client would prefer.
+/// * `text_only` - Whether the server only allows text.
+/// # Returns
+/// The requested encoding, or text if it is unknown or not allowed.
+pub(crate) fn negotiate_encoding(requested: &str, text_only: bool) -> state::Encoding {
+    match requested.parse::<state::Encoding>() {
+        Ok(state::Encoding::Binary) if text_only => state::Encoding::Text,
+        Ok(encoding) => encoding,
+        Err(_) => state::Encoding::Text,
+    }
+}
+
+/// Encodes a message for a client. Text messages are terminated by a
+/// newline, which is only added when the message does not already end with
+/// one. Binary messages are framed, see `protocol::encode_frame`, and are
+/// otherwise written exactly as they were published.
+/// # Arguments
+/// * `encoding` - The encoding of the client.
+/// * `message` - The message to encode.
+/// # Returns
+/// The bytes to write to the client.
+pub(crate) fn encode(encoding: state::Encoding, message: &[u8]) -> Vec<u8> {
+    match encoding {
+        state::Encoding::Text => {
+            let mut line = message.to_vec();
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            line
+        }
+        state::Encoding::Binary => protocol::encode_frame(message),
+    }
+}
+
+/// An error replied to a client. Every error reply is written as
+/// `protocol::ERROR_PREFIX` followed by the reason, so that clients can tell
+/// errors apart from other replies.
+struct ErrorReply(String);
+
+impl ErrorReply {
+    /// Creates an error reply.
+    /// # Arguments
+    /// * `reason` - What went wrong, such as `unauthorized`.
+    fn new(reason: impl Into<String>) -> Self {
+        ErrorReply(reason.into())
+    }
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", protocol::ERROR_PREFIX, self.0)
+    }
+}
+
+/// Writes an error reply to a client.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to reply to.
+/// * `error` - The error.
+fn reply_error(broker: &BrokerState, client: &str, error: ErrorReply) {
+    reply(broker, client, &error.to_string())
+}
+
+/// Writes a single line reply to a client. The reply is written through the
+/// client's outbox, once any message being delivered to the client has been
+/// written, so that it cannot interleave with the message.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to reply to.
+/// * `message` - The reply, without the trailing newline.
+fn reply(broker: &BrokerState, client: &str, message: &str) {
+    reply_bytes(broker, client, message.as_bytes());
+}
+
+/// Writes a reply which need not be UTF-8 to a client, as `reply` does.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to reply to.
+/// * `message` - The reply, without the trailing newline.
+fn reply_bytes(broker: &BrokerState, client: &str, message: &[u8]) {
+    let written = broker.outbox(client).is_some_and(|outbox| {
+        outbox.write_now(
+            || {
+                // A client which cannot be written to is found by its consumer.
+                let _ = write_to_subscriber(broker, client, message);
+            },
+            |message| write_queued_message(broker, client, message),
+        )
+    });
+    if !written {
+        warn!("Failed to write to client.");
+    }
+}
+
+/// Server ping. Responds with a PONG message, followed by the token sent with
+/// the ping if there is one, so that the client can match the reply to its
+/// ping.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client to ping.
+/// * `token` - The token sent with the ping, which may be empty.
+fn ping_handler(broker: &BrokerState, client: &str, token: &str) {
+    if let Some(addr) = broker.clients().addr(client) {
+        debug!("Got ping from {}", addr);
+    }
+    match token.trim() {
+        "" => reply(broker, client, "PONG"),
+        token => reply(broker, client, &format!("PONG {}", token)),
+    }
+}
+
+/// Writes every message delivered to a client from now on with its sequence
+/// number on its channel, replying `SEQUENCE ON`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client asking for sequence numbers.
+fn sequence_handler(broker: &BrokerState, client: &str) {
+    reply(broker, client, "SEQUENCE ON");
+    broker.clients().set_sequenced(client);
+}
+
+/// Replies with the version of the server, as `VERSION <version>`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client asking for the version.
+fn version_handler(broker: &BrokerState, client: &str) {
+    reply(
+        broker,
+        client,
+        &format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+    );
+}
+
+/// Replies with the id the server knows the client by, as `WHOAMI <id>`. This
+/// is the id listed by `CLIENTS` and used to address the client with
+/// `SENDTO`.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `client` - The client asking for its id.
+fn whoami_handler(broker: &BrokerState, client: &str) {
+    reply(broker, client, &format!("WHOAMI {}", client));
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::io::BufRead;
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use uuid::Uuid;
+
+    /// Helper function to create the id of a client which is not connected.
+    fn get_client() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    /// Test that the function is able to split a message into it's components.
+    #[test]
+    fn test_get_message_components() {
+        let [method, message] = get_message_components("SUBSCRIBE test");
+        assert_eq!(method, "SUBSCRIBE");
+        assert_eq!(message, "test");
+    }
+
+    /// Test that the function is able to split a message into multiple
+    /// components even when the message has spaces in it.
+    #[test]
+    fn test_get_message_components_multi_space() {
+        let [method, message] = get_message_components("SUBSCRIBE test channel");
+        assert_eq!(method, "SUBSCRIBE");
+        assert_eq!(message, "test channel");
+    }
+
+    /// Test that the function is to subscribe a client to a channel.
+    #[test]
+    fn test_subscribe_handler() {
+        let broker = BrokerState::default();
+        let client = get_client();
+        let channel = "test".to_string();
+        subscribe_handler(&broker, &client, &channel);
+        assert!(broker.subscriptions().is_subscribed(&client, &channel));
+    }
+
+    /// Test that empty and whitespace only channel names, and channel names
+    /// containing the separator, are invalid.
+    #[test]
+    fn test_validate_channel() {
+        assert!(validate_channel("", '\u{1f}').is_err());
+        assert!(validate_channel("   ", '\u{1f}').is_err());
+        assert!(validate_channel("te\u{1f}st", '\u{1f}').is_err());
+        assert!(validate_channel("te|st", '|').is_err());
+        assert!(validate_channel("my test", '\u{1f}').is_ok());
+    }
+
+    /// Test that a client subscribing after a message is retained receives
+    /// it, and that an empty message clears it.
+    #[test]
+    fn test_retain_handler() {
+        let broker = BrokerState::default();
+        let channel = "test_retain_handler".to_string();
+        let publisher = get_client();
+        retain_handler(
+            &broker,
+            &publisher,
+            format!("{}\u{1f}up", channel).as_bytes(),
+        );
+        assert_eq!(broker.retained(&channel), Some(b"up".to_vec()));
+
+        let (client, subscriber) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        subscribe_handler(&broker, &client, &channel);
+        let mut line = String::new();
+        BufReader::new(subscriber).read_line(&mut line).unwrap();
+        assert_eq!(line, "up\n");
+        broker.clients().remove_client(&client);
+
+        retain_handler(&broker, &publisher, format!("{}\u{1f}", channel).as_bytes());
+        assert_eq!(broker.retained(&channel), None);
+    }
+
+    /// Test that a message is retained as the hooks transformed it, and that
+    /// a message a hook dropped is not retained.
+    #[test]
+    fn test_retain_handler_hook() {
+        let hook = |_: &str, message: String| match message.starts_with("drop") {
+            true => None,
+            false => Some(message.replace("secret", "******")),
+        };
+        let broker = BrokerState::new(Config::default(), None, vec![Box::new(hook)]);
+        let publisher = get_client();
+        retain_handler(&broker, &publisher, b"status\x1fthe secret is out");
+        assert_eq!(
+            broker.retained("status"),
+            Some(b"the ****** is out".to_vec())
+        );
+        retain_handler(&broker, &publisher, b"status\x1fdrop me");
+        assert_eq!(
+            broker.retained("status"),
+            Some(b"the ****** is out".to_vec())
+        );
+        retain_handler(&broker, &publisher, b"other\x1fdrop me");
+        assert_eq!(broker.retained("other"), None);
+    }
+
+    /// Test that a retained message which is not UTF-8 is delivered to later
+    /// subscribers unchanged.
+    #[test]
+    fn test_retain_handler_binary() {
+        let broker = BrokerState::default();
+        let channel = "test_retain_handler_binary";
+        let publisher = get_client();
+        let mut message = format!("{}\u{1f}", channel).into_bytes();
+        message.extend_from_slice(&[0xff, 0x00, 0xfe]);
+        retain_handler(&broker, &publisher, &message);
+        assert_eq!(broker.retained(channel), Some(vec![0xff, 0x00, 0xfe]));
+
+        let (client, subscriber) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        subscribe_handler(&broker, &client, &channel.to_string());
+        let mut line = Vec::new();
+        BufReader::new(subscriber)
+            .read_until(b'\n', &mut line)
+            .unwrap();
+        assert_eq!(line, [0xff, 0x00, 0xfe, b'\n']);
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that a request is delivered to subscribers with its correlation id,
+    /// and that the reply is routed back to the requester only once.
+    #[test]
+    fn test_request_reply_handlers() {
+        let broker = BrokerState::default();
+        let channel = "test_request_reply_handlers".to_string();
+        let (requester, requester_client) = get_stream_pair();
+        let (responder, responder_client) = get_stream_pair();
+        let requester = broker.clients().add_client(Box::new(requester));
+        let responder = broker.clients().add_client(Box::new(responder));
+        subscribe_handler(&broker, &responder, &channel);
+
+        request_handler(&broker, &requester, &format!("7 {}\u{1f}ping", channel));
+        let mut line = String::new();
+        let mut reader = BufReader::new(responder_client);
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "\u{4}7 ping\n");
+
+        reply_handler(&broker, &responder, "7 pong");
+        reply_handler(&broker, &responder, "7 again");
+        line.clear();
+        BufReader::new(requester_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "REPLY 7 pong\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR unknown request: 7\n");
+        broker.clients().remove_client(&requester);
+        broker.clients().remove_client(&responder);
+    }
+
+    /// Test that subscribing without a channel name is rejected.
+    #[test]
+    fn test_subscribe_handler_empty_channel() {
+        let broker = BrokerState::default();
+        for command in ["SUBSCRIBE", "SUBSCRIBE   "] {
+            let (client, subscriber) = get_stream_pair();
+            let client = broker.clients().add_client(Box::new(client));
+            let [_, channel] = get_message_components(command);
+            subscribe_handler(&broker, &client, &channel);
+            assert!(!broker.subscriptions().is_subscribed(&client, &channel));
+
+            let mut line = String::new();
+            BufReader::new(subscriber).read_line(&mut line).unwrap();
+            assert_eq!(line, "ERR invalid channel: channel name cannot be empty\n");
+            broker.clients().remove_client(&client);
+        }
+    }
+
+    /// Test that subscribing with a valid channel name does not reply with an
+    /// error.
+    #[test]
+    fn test_subscribe_handler_valid_channel() {
+        let broker = BrokerState::default();
+        let (client, subscriber) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        let [_, channel] = get_message_components("SUBSCRIBE test_subscribe_handler_valid");
+        subscribe_handler(&broker, &client, &channel);
+        assert!(broker.subscriptions().is_subscribed(&client, &channel));
+
+        subscriber
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut line = String::new();
+        assert!(BufReader::new(subscriber).read_line(&mut line).is_err());
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that the function is to unsubscribe a client from a channel.
+    #[test]
+    fn test_unsubscribe_handler() {
+        let broker = BrokerState::default();
+        let client = get_client();
+        let channel = "test".to_string();
+        broker.subscriptions().add_subscription(&client, &channel);
+        unsubscribe_handler(&broker, &client, &channel);
+        assert!(!broker.subscriptions().is_subscribed(&client, &channel));
+    }
+
+    /// Test that unsubscribing from a pattern removes the pattern
+    /// subscription, but not subscriptions to the channels it matches.
+    #[test]
+    fn test_unsubscribe_handler_pattern() {
+        let broker = BrokerState::default();
+        let client = get_client();
+        let subscriptions = broker.subscriptions();
+        subscriptions.add_pattern_subscription(&client, &"sensors.*".to_string());
+        subscriptions.add_subscription(&client, &"sensors.hall".to_string());
+        assert!(subscriptions
+            .get_pattern_subscribers("sensors.kitchen")
+            .contains(&client));
+
+        unsubscribe_handler(&broker, &client, &"sensors.*".to_string());
+        assert!(subscriptions
+            .get_pattern_subscribers("sensors.kitchen")
+            .is_empty());
+        assert!(subscriptions.is_subscribed(&client, &"sensors.hall".to_string()));
+    }
+
+    /// Test that a client without admin rights is unable to subscribe to
+    /// every channel.
+    #[test]
+    fn test_subscribe_all_handler_unauthorized() {
+        let broker = BrokerState::default();
+        let client = get_client();
+        subscribe_all_handler(&broker, &client);
+        assert!(!broker.subscriptions().is_subscribed_to_all(&client));
+    }
+
+    /// Test that the function grants admin rights only when the correct token
+    /// is provided.
+    #[test]
+    fn test_auth_handler() {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let broker = BrokerState::new(config, None, Vec::new());
+        let client = get_client();
+        auth_handler(&broker, &client, "wrong");
+        assert!(!broker.clients().is_admin(&client));
+        auth_handler(&broker, &client, "secret");
+        assert!(broker.clients().is_admin(&client));
+        broker.clients().remove_client(&client);
+    }
+
+    /// Helper function to create a connected pair of streams. The first is
+    /// the server's end of the connection and the second is the client's end.
+    fn get_stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    /// Test that presence events are published on the presence channel.
+    #[test]
+    fn test_publish_presence_event() {
+        let broker = BrokerState::default();
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = PRESENCE_CHANNEL.to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+        publish_presence_event(&broker, "JOIN", "test_publish_presence_event");
+        broker
+            .subscriptions()
+            .remove_subscription(&subscriber, &channel);
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "JOIN test_publish_presence_event\n");
+    }
+
+    /// Test that clients are announced on the presence channel by their id,
+    /// and only once.
+    #[test]
+    fn test_join_presence() {
+        let broker = BrokerState::new(
+            Config {
+                presence: true,
+                ..Config::default()
+            },
+            None,
+            Vec::new(),
+        );
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &PRESENCE_CHANNEL.to_string());
+
+        let client = get_client();
+        join_presence(&broker, &client);
+        join_presence(&broker, &client);
+        publish_presence_event(&broker, "JOIN", "last");
+        broker.clients().remove_client(&subscriber);
+
+        let lines: Vec<String> = BufReader::new(subscriber_client)
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            lines,
+            vec![format!("JOIN {}", client), "JOIN last".to_string()]
+        );
+    }
+
+    /// Test that clients are unable to publish on the presence channel.
+    #[test]
+    fn test_client_publish_handler_presence_channel() {
+        let broker = BrokerState::default();
+        let (client, publisher) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        client_publish_handler(
+            &broker,
+            &client,
+            b"$presence JOIN spoofed\n",
+            state::Priority::High,
+        );
+
+        let mut line = String::new();
+        BufReader::new(publisher).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR reserved channel\n");
+    }
+
+    /// Test that a heartbeat from a client that is not present does not add
+    /// it to the presence channel when presence is disabled.
+    #[test]
+    fn test_heartbeat_handler_presence_disabled() {
+        let broker = BrokerState::default();
+        let client = get_client();
+        heartbeat_handler(&broker, &client);
+        assert!(!broker.presence().heartbeat(&client));
+    }
+
+    /// Test that a message published with a priority is delivered to a
+    /// subscriber.
+    #[test]
+    fn test_publish_priority_handler() {
+        let broker = BrokerState::default();
+        let publisher = get_client();
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = "test_publish_priority_handler".to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+        publish_priority_handler(
+            &broker,
+            &publisher,
+            format!("high {} urgent\n", channel).as_bytes(),
+        );
+        broker
+            .subscriptions()
+            .remove_subscription(&subscriber, &channel);
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "urgent\n");
+    }
+
+    /// Test that an unknown priority is rejected.
+    #[test]
+    fn test_publish_priority_handler_unknown_priority() {
+        let broker = BrokerState::default();
+        let (client, publisher) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        publish_priority_handler(&broker, &client, b"urgent channel message\n");
+
+        let mut line = String::new();
+        BufReader::new(publisher).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR Unknown priority: urgent\n");
+    }
+
+    /// Test that system messages are delivered with the system message marker
+    /// and only admins are able to publish them.
+    #[test]
+    fn test_publish_system_handler() {
+        let broker = BrokerState::default();
+        let (publisher, publisher_client) = get_stream_pair();
+        let publisher = broker.clients().add_client(Box::new(publisher));
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = "test_publish_system_handler".to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        publish_system_handler(&broker, &publisher, &format!("{} closing\n", channel));
+        let mut line = String::new();
+        BufReader::new(publisher_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "ERR unauthorized\n");
+
+        broker.clients().set_admin(&publisher);
+        publish_system_handler(&broker, &publisher, &format!("{} closing\n", channel));
+        broker
+            .subscriptions()
+            .remove_subscription(&subscriber, &channel);
+        broker.clients().remove_client(&publisher);
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, format!("{}closing\n", SYSTEM_MESSAGE_MARKER));
+    }
+
+    /// Test that messages are delivered as transformed by the registered
+    /// hooks, and not at all when a hook drops them.
+    #[test]
+    fn test_publish_handler_hook() {
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = "test_publish_handler_hook".to_string();
+        let hook = |_: &str, message: String| match message.starts_with("drop") {
+            true => None,
+            false => Some(message.to_uppercase()),
+        };
+        let broker = BrokerState::new(Config::default(), None, vec![Box::new(hook)]);
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        publish_handler(&broker, &format!("{} drop me\n", channel));
+        publish_handler(&broker, &format!("{} hello\n", channel));
+        broker
+            .subscriptions()
+            .remove_subscription(&subscriber, &channel);
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "HELLO\n");
+    }
+
+    /// Test that the channel is split from the body at the separator, so that
+    /// channels may contain spaces and bodies may start with the separator.
+    #[test]
+    fn test_publish_handler_separator() {
+        let broker = BrokerState::default();
+        let channel = "test publish handler separator".to_string();
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .clients()
+            .set_encoding(&subscriber, state::Encoding::Binary);
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        let messages = ["\u{1f}starts with the separator", "a \u{1f} b"];
+        for message in messages {
+            publish_handler(&broker, &format!("{}\u{1f}{}", channel, message));
+        }
+
+        let mut reader = BufReader::new(subscriber_client);
+        for message in messages {
+            assert_eq!(
+                protocol::read_frame(&mut reader).unwrap(),
+                message.as_bytes()
+            );
+        }
+
+        broker
+            .subscriptions()
+            .remove_subscription(&subscriber, &channel);
+        broker.clients().remove_client(&subscriber);
+    }
+
+    /// Test that messages are delivered with their channel to pattern
+    /// subscribers, once however many of their subscriptions match.
+    #[test]
+    fn test_publish_handler_patterns() {
+        let broker = BrokerState::default();
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        for pattern in ["sensors.*.temp", "sensors.#"] {
+            psubscribe_handler(&broker, &subscriber, &pattern.to_string());
+        }
+        subscribe_handler(&broker, &subscriber, &"sensors.hall.temp".to_string());
+
+        publish_handler(&broker, "sensors.kitchen.temp\u{1f}21");
+        publish_handler(&broker, "lights.kitchen\u{1f}on");
+        publish_handler(&broker, "sensors.hall.temp\u{1f}19");
+        punsubscribe_handler(&broker, &subscriber, &"sensors.#".to_string());
+        publish_handler(&broker, "sensors.kitchen\u{1f}closed");
+        publish_handler(&broker, "sensors.kitchen.temp\u{1f}22");
+        broker.clients().remove_client(&subscriber);
+
+        let lines: Vec<String> = BufReader::new(subscriber_client)
+            .lines()
+            .take(3)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "sensors.kitchen.temp\u{1f}21",
+                "19",
+                "sensors.kitchen.temp\u{1f}22"
+            ]
+        );
+    }
+
+    /// Test that subscribing to an empty pattern is rejected.
+    #[test]
+    fn test_psubscribe_handler_empty_pattern() {
+        let broker = BrokerState::default();
+        let (client, subscriber) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        psubscribe_handler(&broker, &client, &String::new());
+        assert!(broker
+            .subscriptions()
+            .get_pattern_subscribers("")
+            .is_empty());
+
+        let mut line = String::new();
+        BufReader::new(subscriber).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR invalid pattern: channel name cannot be empty\n");
+    }
+
+    /// Test that only subscribers which asked for sequence numbers receive
+    /// them, and that each channel is numbered separately.
+    #[test]
+    fn test_sequence_handler() {
+        let broker = BrokerState::default();
+        let (sequenced, sequenced_client) = get_stream_pair();
+        let (plain, plain_client) = get_stream_pair();
+        let sequenced = broker.clients().add_client(Box::new(sequenced));
+        let plain = broker.clients().add_client(Box::new(plain));
+        sequence_handler(&broker, &sequenced);
+        for channel in ["news", "sport"] {
+            for subscriber in [&sequenced, &plain] {
+                broker
+                    .subscriptions()
+                    .add_subscription(subscriber, &channel.to_string());
+            }
+        }
+
+        publish_handler(&broker, "news\u{1f}first");
+        publish_handler(&broker, "sport\u{1f}goal");
+        publish_handler(&broker, "news\u{1f}second");
+
+        let mut reader = BufReader::new(sequenced_client);
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            received.push(line);
+        }
+        assert_eq!(
+            received,
+            vec![
+                "SEQUENCE ON\n",
+                "\u{3}1 news\u{1f}first\n",
+                "\u{3}1 sport\u{1f}goal\n",
+                "\u{3}2 news\u{1f}second\n",
+            ]
+        );
+        let mut reader = BufReader::new(plain_client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "first\n");
+
+        broker.clients().remove_client(&sequenced);
+        broker.clients().remove_client(&plain);
+    }
+
+    /// Test that subscribers disconnecting, either with `DISCONNECT` or by
+    /// dropping the connection, while messages are published to them heavily
+    /// neither panics a connection nor stops delivery to later subscribers.
+    #[test]
+    fn test_disconnect_while_publishing() {
+        const PUBLISHERS: usize = 4;
+        const SUBSCRIBERS: usize = 40;
+        let channel = "test_disconnect_while_publishing";
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Arc::new(BrokerState::default());
+        let consumers = {
+            let broker = broker.clone();
+            thread::spawn(move || {
+                let mut consumers = Vec::new();
+                for mut stream in listener.incoming().flatten() {
+                    let broker = broker.clone();
+                    consumers.push(thread::spawn(move || consumer(&broker, &mut stream)));
+                    if consumers.len() == PUBLISHERS + SUBSCRIBERS + 1 {
+                        break;
+                    }
+                }
+                consumers
+            })
+        };
+
+        let publishing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let publishers: Vec<thread::JoinHandle<()>> = (0..PUBLISHERS)
+            .map(|_| {
+                let publishing = publishing.clone();
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    while publishing.load(std::sync::atomic::Ordering::SeqCst) {
+                        send_command(
+                            &mut stream,
+                            &format!("PUBLISH {} {}", channel, "x".repeat(512)),
+                        );
+                    }
+                    send_command(&mut stream, "DISCONNECT");
+                })
+            })
+            .collect();
+
+        for subscriber in 0..SUBSCRIBERS {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            send_command(&mut stream, &format!("SUBSCRIBE {}", channel));
+            let mut line = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            reader.read_line(&mut line).unwrap();
+            if subscriber % 2 == 0 {
+                send_command(&mut stream, "DISCONNECT");
+            } else {
+                stream.shutdown(Shutdown::Both).unwrap();
+            }
+        }
+
+        // Messages are still delivered to a subscriber joining afterwards.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        send_command(&mut stream, &format!("SUBSCRIBE {}", channel));
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line.trim_end(), "x".repeat(512));
+        // Kept reading so that publishing is not held up by this subscriber.
+        let drain = thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+
+        publishing.store(false, std::sync::atomic::Ordering::SeqCst);
+        for publisher in publishers {
+            publisher.join().unwrap();
+        }
+        send_command(&mut stream, "DISCONNECT");
+        drain.join().unwrap().unwrap();
+        for consumer in consumers.join().unwrap() {
+            consumer.join().unwrap();
+        }
+        assert!(broker.clients().ids().is_empty());
+    }
+
+    /// Test that messages are delivered to binary subscribers byte for byte,
+    /// with or without a trailing newline, and that text subscribers receive
+    /// each message on its own line.
+    #[test]
+    fn test_publish_handler_preserves_bytes() {
+        let broker = BrokerState::default();
+        let channel = "test_publish_handler_preserves_bytes".to_string();
+        let (binary, binary_client) = get_stream_pair();
+        let (text, text_client) = get_stream_pair();
+        let binary = broker.clients().add_client(Box::new(binary));
+        let text = broker.clients().add_client(Box::new(text));
+        broker
+            .clients()
+            .set_encoding(&binary, state::Encoding::Binary);
+        for subscriber in [&binary, &text] {
+            broker
+                .subscriptions()
+                .add_subscription(subscriber, &channel);
+        }
+
+        for message in ["no newline", "  padded \n", "two\nlines\n"] {
+            publish_handler(&broker, &format!("{} {}", channel, message));
+        }
+
+        let mut reader = BufReader::new(binary_client);
+        for message in ["no newline", "  padded \n", "two\nlines\n"] {
+            assert_eq!(
+                protocol::read_frame(&mut reader).unwrap(),
+                message.as_bytes()
+            );
+        }
+        let mut received = String::new();
+        let mut reader = BufReader::new(text_client);
+        for _ in 0..4 {
+            reader.read_line(&mut received).unwrap();
+        }
+        assert_eq!(received, "no newline\n  padded \ntwo\nlines\n");
+
+        for subscriber in [&binary, &text] {
+            broker
+                .subscriptions()
+                .remove_subscription(subscriber, &channel);
+        }
+        broker.clients().remove_client(&binary);
+    }
+
+    /// Test that messages published to a channel over its rate are dropped
+    /// with a reply, or delayed when so configured, without affecting other
+    /// channels.
+    #[test]
+    fn test_client_publish_handler_rate_limited() {
+        let config = Config {
+            channel_rate: Some(20),
+            channel_burst: Some(1),
+            ..Config::default()
+        };
+        let broker = BrokerState::new(config.clone(), None, Vec::new());
+        let (publisher, publisher_client) = get_stream_pair();
+        let publisher = broker.clients().add_client(Box::new(publisher));
+        let (subscriber_stream, subscriber_client) = get_stream_pair();
+        let channel = "test_client_publish_handler_rate_limited".to_string();
+        let subscriber = broker
+            .clients()
+            .add_client(Box::new(subscriber_stream.try_clone().unwrap()));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        for message in ["first", "dropped"] {
+            let message = format!("{} {}", channel, message);
+            client_publish_handler(
+                &broker,
+                &publisher,
+                message.as_bytes(),
+                state::Priority::Normal,
+            );
+        }
+        client_publish_handler(&broker, &publisher, b"other hello", state::Priority::Normal);
+        let mut line = String::new();
+        BufReader::new(publisher_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, format!("RATE_LIMITED {}\n", channel));
+
+        let broker = BrokerState::new(
+            Config {
+                channel_rate_delay: true,
+                ..config
+            },
+            None,
+            Vec::new(),
+        );
+        let subscriber = broker.clients().add_client(Box::new(subscriber_stream));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+        let start = Instant::now();
+        for message in ["second", "third"] {
+            let message = format!("{} {}", channel, message);
+            client_publish_handler(
+                &broker,
+                &publisher,
+                message.as_bytes(),
+                state::Priority::Normal,
+            );
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        let mut received = String::new();
+        let mut reader = BufReader::new(subscriber_client);
+        for _ in 0..3 {
+            reader.read_line(&mut received).unwrap();
+        }
+        assert_eq!(received, "first\nsecond\nthird\n");
+    }
+
+    /// Test that messages left queued for a subscriber are delivered when the
+    /// outboxes are flushed.
+    #[test]
+    fn test_flush_outboxes() {
+        let broker = BrokerState::default();
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        let subscriber_id = subscriber.clone();
+        let outbox = broker.outbox(&subscriber_id).unwrap();
+        outbox.push(state::QueuedMessage::new(
+            state::Priority::Normal,
+            "test_flush_outboxes",
+            false,
+            b"queued\n",
+        ));
+
+        assert!(flush_outboxes(&broker, Duration::from_secs(1)));
+        assert!(outbox.is_empty());
+        broker.remove_outbox(&subscriber_id);
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "queued\n");
+    }
+
+    /// Test that a client without admin rights is unable to shut down the
+    /// server.
+    #[test]
+    fn test_shutdown_handler_unauthorized() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        shutdown_handler(&broker, &client);
+
+        let mut line = String::new();
+        BufReader::new(requester).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR unauthorized\n");
+    }
+
+    /// Test that a subscription is acknowledged once in place, and an invalid
+    /// channel is rejected.
+    #[test]
+    fn test_subscribe_ack_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        let channel = "test_subscribe_ack_handler".to_string();
+        subscribe_ack_handler(&broker, &client, &channel);
+        assert!(broker.subscriptions().is_subscribed(&client, &channel));
+        subscribe_ack_handler(&broker, &client, &" ".to_string());
+        broker.clients().remove_client(&client);
+
+        let mut reader = BufReader::new(requester);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("ACK SUBSCRIBE {}\n", channel));
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("ERR invalid channel"));
+    }
+
+    /// Test that a message published again with the same id is acknowledged
+    /// without being delivered twice.
+    #[test]
+    fn test_publish_id_handler() {
+        let broker = BrokerState::new(
+            Config {
+                dedup_window: 8,
+                ..Config::default()
+            },
+            None,
+            Vec::new(),
+        );
+        let (publisher, publisher_client) = get_stream_pair();
+        let publisher = broker.clients().add_client(Box::new(publisher));
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = "test_publish_id_handler".to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        publish_id_handler(
+            &broker,
+            &publisher,
+            format!("7 {} first", channel).as_bytes(),
+        );
+        publish_id_handler(
+            &broker,
+            &publisher,
+            format!("7 {} first", channel).as_bytes(),
+        );
+        publish_id_handler(
+            &broker,
+            &publisher,
+            format!("8 {} second", channel).as_bytes(),
+        );
+        broker.clients().remove_client(&publisher);
+        broker
+            .subscriptions()
+            .remove_subscription(&subscriber, &channel);
+
+        let mut reader = BufReader::new(publisher_client);
+        for ack in ["ACK 7\n", "ACK 7\n", "ACK 8\n"] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, ack);
+        }
+        let mut reader = BufReader::new(subscriber_client);
+        for message in ["first\n", "second\n"] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, message);
+        }
+    }
+
+    /// Test that a direct message reaches only its recipient, marked with the
+    /// sender's id.
+    #[test]
+    fn test_sendto_handler() {
+        let broker = BrokerState::default();
+        let (sender, sender_client) = get_stream_pair();
+        let sender = broker.clients().add_client(Box::new(sender));
+        let (recipient, recipient_client) = get_stream_pair();
+        let recipient = broker.clients().add_client(Box::new(recipient));
+        let recipient_id = recipient.clone();
+
+        sendto_handler(&broker, &sender, &format!("{} hello there", recipient_id));
+        sendto_handler(&broker, &sender, "0 hello");
+        broker.clients().remove_client(&recipient);
+        sendto_handler(&broker, &sender, &format!("{} gone", recipient_id));
+
+        let mut reader = BufReader::new(sender_client);
+        for expected in [
+            format!("DELIVERED {}\n", recipient_id),
+            "NOT_FOUND 0\n".to_string(),
+            format!("NOT_FOUND {}\n", recipient_id),
+        ] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+        let mut line = String::new();
+        BufReader::new(recipient_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(
+            line,
+            format!("{}{} hello there\n", DIRECT_MESSAGE_MARKER, sender.clone())
+        );
+    }
+
+    /// Test that only admins may send direct messages when so configured.
+    #[test]
+    fn test_sendto_handler_admin_only() {
+        let broker = BrokerState::new(
+            Config {
+                sendto_admin_only: true,
+                ..Config::default()
+            },
+            None,
+            Vec::new(),
+        );
+        let (sender, sender_client) = get_stream_pair();
+        let sender = broker.clients().add_client(Box::new(sender));
+        sendto_handler(&broker, &sender, "0 hello");
+
+        let mut line = String::new();
+        BufReader::new(sender_client).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR unauthorized\n");
+    }
+
+    /// Test that a broadcast reaches every client, including the one
+    /// broadcasting, and that a client which cannot be written to does not
+    /// stop the others receiving it.
+    #[test]
+    fn test_broadcast_handler() {
+        let broker = BrokerState::default();
+        let pairs: Vec<(String, TcpStream)> = (0..3)
+            .map(|_| {
+                let (stream, client) = get_stream_pair();
+                (broker.clients().add_client(Box::new(stream)), client)
+            })
+            .collect();
+        let (dead, _dead_client) = get_stream_pair();
+        dead.shutdown(Shutdown::Write).unwrap();
+        let dead = broker.clients().add_client(Box::new(dead));
+
+        broadcast_handler(&broker, &pairs[0].0, "hello everyone");
+        for (_, client) in &pairs {
+            let mut line = String::new();
+            BufReader::new(client).read_line(&mut line).unwrap();
+            assert_eq!(line, "hello everyone\n");
+        }
+        broker.clients().remove_client(&dead);
+        for (client, _) in &pairs {
+            broker.clients().remove_client(client);
+        }
+    }
+
+    /// Test that only admins may broadcast once the broker has an admin
+    /// token, and that an empty broadcast is refused.
+    #[test]
+    fn test_broadcast_handler_refused() {
+        let broker = BrokerState::new(
+            Config {
+                admin_token: Some("secret".to_string()),
+                ..Config::default()
+            },
+            None,
+            Vec::new(),
+        );
+        let (client, client_end) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        broadcast_handler(&broker, &client, "hello");
+        broker.clients().set_admin(&client);
+        broadcast_handler(&broker, &client, "");
+
+        let mut reader = BufReader::new(client_end);
+        for expected in ["ERR unauthorized\n", "ERR missing message\n"] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that dropping a channel unsubscribes and notifies its subscribers
+    /// and is restricted to admins.
+    #[test]
+    fn test_drop_handler() {
+        let broker = BrokerState::default();
+        let (admin, admin_client) = get_stream_pair();
+        let admin = broker.clients().add_client(Box::new(admin));
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = "test_drop_handler".to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+        let mut admin_reader = BufReader::new(admin_client);
+
+        drop_handler(&broker, &admin, &channel);
+        let mut line = String::new();
+        admin_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR unauthorized\n");
+        assert!(broker.subscriptions().is_subscribed(&subscriber, &channel));
+
+        broker.clients().set_admin(&admin);
+        drop_handler(&broker, &admin, &format!("{} channel retired", channel));
+        let mut line = String::new();
+        admin_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "DROPPED 1\n");
+        assert!(!broker.subscriptions().is_subscribed(&subscriber, &channel));
+        assert!(broker.clients().is_connected(&subscriber));
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, format!("{}channel retired\n", SYSTEM_MESSAGE_MARKER));
+
+        drop_handler(&broker, &admin, &channel);
+        let mut line = String::new();
+        admin_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "DROPPED 0\n");
+        broker.clients().remove_client(&admin);
+        broker.clients().remove_client(&subscriber);
+    }
+
+    /// Test that a subscriber that never reads is unsubscribed once writing to
+    /// it times out.
+    #[test]
+    fn test_publish_handler_write_timeout() {
+        let broker = BrokerState::default();
+        let (subscriber, _subscriber_client) = get_stream_pair();
+        subscriber
+            .set_write_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let channel = "test_publish_handler_write_timeout".to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        let message = format!("{} {}\n", channel, "x".repeat(8 * 1024 * 1024));
+        for _ in 0..16 {
+            publish_handler(&broker, &message);
+            let subscribed = broker.subscriptions().is_subscribed(&subscriber, &channel);
+            if !subscribed {
+                break;
+            }
+        }
+        assert!(!broker.subscriptions().is_subscribed(&subscriber, &channel));
+    }
+
+    /// Test that a subscriber which has stopped reading, by shutting down the
+    /// read half of its socket, is pruned once writing to it times out.
+    #[test]
+    fn test_half_closed_subscriber_pruned() {
+        let broker = Arc::new(BrokerState::new(
+            Config {
+                write_timeout: Some(Duration::from_millis(100)),
+                ..Config::default()
+            },
+            None,
+            Vec::new(),
+        ));
+        let (mut server_end, mut client_end) = get_stream_pair();
+        let consumer = {
+            let broker = broker.clone();
+            thread::spawn(move || {
+                consumer(&broker, &mut server_end);
+                !broker.clients().ids().is_empty()
+            })
+        };
+        let channel = "test_half_closed_subscriber_pruned";
+        client_end
+            .write_all(&protocol::encode_frame(
+                format!("SUBSCRIBE_ACK {}", channel).as_bytes(),
+            ))
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(client_end.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        client_end.shutdown(Shutdown::Read).unwrap();
+
+        let message = format!("{} {}", channel, "x".repeat(8 * 1024 * 1024));
+        for _ in 0..16 {
+            publish_handler(&broker, &message);
+            if broker.subscriptions().channels().is_empty() {
+                break;
+            }
+        }
+        assert!(!consumer.join().unwrap());
+    }
+
+    /// Test that probing closes connections the client has reset, and leaves
+    /// the others open.
+    #[test]
+    fn test_probe_clients() {
+        let broker = BrokerState::default();
+        let (live, _live_client) = get_stream_pair();
+        let (mut reset, reset_client) = get_stream_pair();
+        // Closing a socket with unread data resets the connection.
+        reset.write_all(b"unread\n").unwrap();
+        let live = broker.clients().add_client(Box::new(live));
+        let reset = broker.clients().add_client(Box::new(reset));
+
+        thread::sleep(Duration::from_millis(50));
+        drop(reset_client);
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(probe_clients(&broker), 1);
+        assert_eq!(probe_clients(&broker), 1);
+        broker.clients().remove_client(&reset);
+        assert_eq!(probe_clients(&broker), 0);
+        broker.clients().remove_client(&live);
+    }
+
+    /// Test that a client which never answers heartbeats is disconnected and
+    /// removed once it has missed too many, and that one which answers stays.
+    #[test]
+    fn test_send_heartbeats() {
+        let broker = Arc::new(BrokerState::default());
+        let mut clients = Vec::new();
+        let mut consumers = Vec::new();
+        for _ in 0..2 {
+            let (mut server_end, client_end) = get_stream_pair();
+            let broker = broker.clone();
+            consumers.push(thread::spawn(move || consumer(&broker, &mut server_end)));
+            clients.push((BufReader::new(client_end.try_clone().unwrap()), client_end));
+        }
+        while broker.clients().ids().len() < 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut line = String::new();
+        for _ in 0..3 {
+            assert_eq!(send_heartbeats(&broker, 2), 0);
+            for (reader, _) in &mut clients {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line, "PING\n");
+            }
+            // Only the second client answers.
+            let mut writer = &clients[1].1;
+            writer.write_all(&protocol::encode_frame(b"PONG")).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(send_heartbeats(&broker, 2), 1);
+        consumers.remove(0).join().unwrap();
+        assert_eq!(broker.clients().ids().len(), 1);
+
+        let mut writer = &clients[1].1;
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumers.remove(0).join().unwrap();
+    }
+
+    /// Test that a client without admin rights is unable to list clients.
+    #[test]
+    fn test_clients_handler_unauthorized() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        clients_handler(&broker, &client);
+
+        let mut line = String::new();
+        BufReader::new(requester).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR unauthorized\n");
+    }
+
+    /// Test that an admin client receives a record for each client followed
+    /// by an end marker.
+    #[test]
+    fn test_clients_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        broker.clients().set_admin(&client);
+        name_handler(&broker, &client, "test_clients_handler");
+        clients_handler(&broker, &client);
+
+        let mut reader = BufReader::new(requester);
+        let mut found = false;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "END\n" {
+                break;
+            }
+            assert!(line.starts_with("CLIENT "));
+            found |= line.ends_with(" test_clients_handler\n");
+        }
+        assert!(found);
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that each channel with subscribers is listed, followed by an end
+    /// marker.
+    #[test]
+    fn test_list_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        list_handler(&broker, &client);
+        broker
+            .subscriptions()
+            .add_subscription(&client, &"news".to_string());
+        broker
+            .subscriptions()
+            .add_subscription(&client, &"sport".to_string());
+        list_handler(&broker, &client);
+
+        let mut reader = BufReader::new(requester);
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            lines.push(line);
+        }
+        assert_eq!(
+            lines,
+            vec!["END\n", "CHANNEL news\n", "CHANNEL sport\n", "END\n"]
+        );
+    }
+
+    /// Test that the subscribers of a channel are counted, and that an unknown
+    /// channel has none.
+    #[test]
+    fn test_count_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        let subscriber = get_client();
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &"my news".to_string());
+        count_handler(&broker, &client, "my news");
+        count_handler(&broker, &client, "unknown");
+
+        let mut reader = BufReader::new(requester);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "COUNT my news 1\n");
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "COUNT unknown 0\n");
+    }
+
+    /// Test that the message retained on a channel is replied with, byte for
+    /// byte, without subscribing the client asking.
+    #[test]
+    fn test_get_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        broker.set_retained("status", &[0xff, b'u', b'p']);
+        get_handler(&broker, &client, "status");
+        get_handler(&broker, &client, "unknown");
+        assert!(!broker
+            .subscriptions()
+            .is_subscribed(&client, &"status".to_string()));
+
+        let mut reader = BufReader::new(requester);
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(line, b"RETAINED status\x1f\xffup\n");
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "RETAINED unknown\n");
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that publishing counts the message and the size of its body,
+    /// whether or not anyone is subscribed, and that the counters are replied
+    /// with as JSON.
+    #[test]
+    fn test_stats_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        client_publish_handler(&broker, &client, b"news\x1fhello", state::Priority::Normal);
+        client_publish_handler(&broker, &client, b"sport\x1fgoal!", state::Priority::Normal);
+        assert_eq!(broker.stats().messages_published, 2);
+        assert_eq!(broker.stats().bytes_published, 10);
+
+        stats_handler(&broker, &client);
+        let mut line = String::new();
+        BufReader::new(requester).read_line(&mut line).unwrap();
+        assert_eq!(
+            line,
+            "STATS {\"total_connections\":1,\"current_connections\":1,\"channels\":0,\
+             \"messages_published\":2,\"bytes_published\":10}\n"
+        );
+    }
+
+    /// Starts a server on a free port, handling each connection in its own
+    /// thread as the server binary does.
+    /// # Returns
+    /// The address the server is listening on.
+    fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Arc::new(BrokerState::default());
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let broker = broker.clone();
+                thread::spawn(move || consumer(&broker, &mut stream));
+            }
+        });
+        addr
+    }
+
+    /// Sends a command to the server, framed as the client does.
+    fn send_command(stream: &mut TcpStream, command: &str) {
+        stream
+            .write_all(&protocol::encode_frame(command.as_bytes()))
+            .unwrap();
+    }
+
+    /// Test that when many publishers publish concurrently, every subscriber
+    /// receives each published message exactly once and intact.
+    #[test]
+    fn test_concurrent_publish_subscribe() {
+        const SUBSCRIBERS: usize = 24;
+        const PUBLISHERS: usize = 4;
+        const MESSAGES: usize = 50;
+        let channel = "test_concurrent_publish_subscribe";
+        let addr = start_server();
+
+        let subscribers: Vec<BufReader<TcpStream>> = (0..SUBSCRIBERS)
+            .map(|_| {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(30)))
+                    .unwrap();
+                send_command(&mut stream, &format!("SUBSCRIBE {}", channel));
+                // Commands are handled in order, so once the server has
+                // replied to the ping the subscription is in place.
+                send_command(&mut stream, "PING");
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line, "PONG\n");
+                reader
+            })
+            .collect();
+
+        let publishers: Vec<thread::JoinHandle<()>> = (0..PUBLISHERS)
+            .map(|publisher| {
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    for message in 0..MESSAGES {
+                        send_command(
+                            &mut stream,
+                            &format!("PUBLISH {} {}-{}\n", channel, publisher, message),
+                        );
+                    }
+                    send_command(&mut stream, "DISCONNECT");
+                })
+            })
+            .collect();
+        for publisher in publishers {
+            publisher.join().unwrap();
+        }
+
+        let expected: HashSet<String> = (0..PUBLISHERS)
+            .flat_map(|publisher| {
+                (0..MESSAGES).map(move |message| format!("{}-{}", publisher, message))
+            })
+            .collect();
+        for mut reader in subscribers {
+            let mut received = HashSet::new();
+            for _ in 0..expected.len() {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                assert!(received.insert(line.trim_end().to_string()));
+            }
+            assert_eq!(received, expected);
+            send_command(reader.get_mut(), "DISCONNECT");
+        }
+    }
+
+    /// Test that commands and messages whose lengths fall either side of
+    /// common buffer sizes are read and delivered exactly, whether the
+    /// boundary falls on the whole command or on the message alone.
+    #[test]
+    fn test_publish_length_boundaries() {
+        let channel = "test_publish_length_boundaries";
+        let addr = start_server();
+        let mut subscriber = TcpStream::connect(addr).unwrap();
+        subscriber
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        send_command(&mut subscriber, "ENCODING BINARY");
+        send_command(&mut subscriber, &format!("SUBSCRIBE {}", channel));
+        send_command(&mut subscriber, "PING");
+        let mut reader = BufReader::new(subscriber);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ENCODING BINARY\n");
+
+        let read_frame = |reader: &mut BufReader<TcpStream>| {
+            String::from_utf8(protocol::read_frame(reader).unwrap()).unwrap()
+        };
+        assert_eq!(read_frame(&mut reader), "PONG");
+
+        let command_overhead = format!("PUBLISH {} ", channel).len();
+        let mut messages = Vec::new();
+        for size in [63, 64, 65, 127, 128] {
+            messages.push("x".repeat(size));
+            messages.push("x".repeat(size - command_overhead));
+            // Multi-byte characters are counted in bytes, not characters.
+            messages.push(format!("{}{}", "é".repeat(size / 2), "x".repeat(size % 2)));
+        }
+        let mut publisher = TcpStream::connect(addr).unwrap();
+        for message in &messages {
+            send_command(&mut publisher, &format!("PUBLISH {} {}", channel, message));
+        }
+
+        for message in &messages {
+            assert_eq!(&read_frame(&mut reader), message);
+        }
+    }
+
+    /// Test that frames sent back-to-back in a single write are each handled.
+    #[test]
+    fn test_consumer_pipelined_frames() {
+        let (mut server_end, mut client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || consumer(&BrokerState::default(), &mut server_end));
+
+        let mut frames = Vec::new();
+        for command in ["PING", "PING", "DISCONNECT"] {
+            frames.extend(protocol::encode_frame(command.as_bytes()));
+        }
+        client_end.write_all(&frames).unwrap();
+
+        let mut reader = BufReader::new(client_end);
+        for _ in 0..2 {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "PONG\n");
+        }
+        consumer.join().unwrap();
+    }
+
+    /// Test that a header which arrives a byte at a time is assembled before
+    /// it is parsed.
+    #[test]
+    fn test_consumer_fragmented_header() {
+        let (mut server_end, mut client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || consumer(&BrokerState::default(), &mut server_end));
+
+        client_end.set_nodelay(true).unwrap();
+        for byte in protocol::encode_frame(b"PING") {
+            client_end.write_all(&[byte]).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut line = String::new();
+        BufReader::new(client_end.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "PONG\n");
+        client_end
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that a message which arrives in two chunks is reassembled before
+    /// it is published, rather than being cut short at the first chunk.
+    #[test]
+    fn test_consumer_message_in_chunks() {
+        let broker = Arc::new(BrokerState::default());
+        let (subscriber, subscriber_client) = get_stream_pair();
+        let channel = "test_consumer_message_in_chunks".to_string();
+        let subscriber = broker.clients().add_client(Box::new(subscriber));
+        broker
+            .subscriptions()
+            .add_subscription(&subscriber, &channel);
+
+        let (mut server_end, mut client_end) = get_stream_pair();
+        let consumer = {
+            let broker = broker.clone();
+            thread::spawn(move || consumer(&broker, &mut server_end))
+        };
+
+        let message = "x".repeat(10_000);
+        let frame = protocol::encode_frame(format!("PUBLISH {} {}", channel, message).as_bytes());
+        let (first, second) = frame.split_at(frame.len() / 2);
+        client_end.set_nodelay(true).unwrap();
+        client_end.write_all(first).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client_end.write_all(second).unwrap();
+
+        let mut line = String::new();
+        BufReader::new(subscriber_client)
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, format!("{}\n", message));
+        client_end
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that unknown commands and publishes without a body are replied to
+    /// with an error frame, rather than being silently ignored.
+    #[test]
+    fn test_consumer_error_replies() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let broker = BrokerState::default();
+            consumer(&broker, &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        writer
+            .write_all(&protocol::encode_frame(b"ENCODING binary"))
+            .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ENCODING BINARY\n");
+
+        for (command, error) in [
+            ("SUBSCRIBEE news", "ERR unknown command: SUBSCRIBEE"),
+            ("PUBLISH news", "ERR bad publish format"),
+        ] {
+            writer
+                .write_all(&protocol::encode_frame(command.as_bytes()))
+                .unwrap();
+            assert_eq!(protocol::read_frame(&mut reader).unwrap(), error.as_bytes());
+        }
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that `DISCONNECT` is replied to with `BYE` in the client's
+    /// encoding once the client has been removed, after which the connection
+    /// is closed.
+    #[test]
+    fn test_consumer_goodbye() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let broker = Arc::new(BrokerState::default());
+        let consumer = {
+            let broker = broker.clone();
+            thread::spawn(move || consumer(&broker, &mut server_end))
+        };
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        writer
+            .write_all(&protocol::encode_frame(b"ENCODING binary"))
+            .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ENCODING BINARY\n");
+
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        assert_eq!(protocol::read_frame(&mut reader).unwrap(), b"BYE");
+        assert!(broker.clients().ids().is_empty());
+        consumer.join().unwrap();
+        assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    /// Test that once auth tokens are configured, a client is refused until
+    /// it authenticates with a valid token, while it may still ping.
+    #[test]
+    fn test_consumer_requires_auth() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let config = Config {
+                auth_tokens: ["letmein".to_string()].into(),
+                ..Config::default()
+            };
+            let broker = BrokerState::new(config, None, Vec::new());
+            consumer(&broker, &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        for (command, reply) in [
+            ("SUBSCRIBE news", "ERR unauthorized\n"),
+            ("PUBLISH news\u{1f}hello", "ERR unauthorized\n"),
+            ("PING", "PONG\n"),
+            ("AUTH wrong", "ERR unauthorized\n"),
+            ("AUTH letmein", "OK\n"),
+            ("COUNT news", "COUNT news 0\n"),
+        ] {
+            writer
+                .write_all(&protocol::encode_frame(command.as_bytes()))
+                .unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, reply, "{}", command);
+        }
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that commands a client sends over its rate are refused, and that
+    /// the client may send commands again once the rate allows.
+    #[test]
+    fn test_consumer_rate_limited() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let config = Config {
+                client_rate: Some(20),
+                client_burst: Some(2),
+                ..Config::default()
+            };
+            let broker = BrokerState::new(config, None, Vec::new());
+            consumer(&broker, &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        for (command, reply) in [
+            ("PING", "PONG\n"),
+            ("PING", "PONG\n"),
+            ("PING", "ERR rate limited\n"),
+            ("COUNT news", "ERR rate limited\n"),
+        ] {
+            writer
+                .write_all(&protocol::encode_frame(command.as_bytes()))
+                .unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, reply, "{}", command);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+        writer.write_all(&protocol::encode_frame(b"PING")).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that a payload which is not UTF-8 is delivered byte for byte,
+    /// rather than bringing down the publisher's consumer.
+    #[test]
+    fn test_consumer_binary_payload() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            consumer(&BrokerState::default(), &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        writer
+            .write_all(&protocol::encode_frame(b"SUBSCRIBE_ACK news"))
+            .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ACK SUBSCRIBE news\n");
+
+        writer
+            .write_all(&protocol::encode_frame(b"PUBLISH news \xff\x00\xfe"))
+            .unwrap();
+        let mut message = Vec::new();
+        reader.read_until(b'\n', &mut message).unwrap();
+        assert_eq!(message, b"\xff\x00\xfe\n");
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that a client may only subscribe and publish where the ACL allows
+    /// the token it authenticated with.
+    #[test]
+    fn test_consumer_acl() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let config = Config {
+                auth_tokens: ["sensor".to_string(), "dashboard".to_string()].into(),
+                acl: Some(
+                    "sensor pub sensors.#\ndashboard sub sensors.#"
+                        .parse()
+                        .unwrap(),
+                ),
+                ..Config::default()
+            };
+            let broker = BrokerState::new(config, None, Vec::new());
+            consumer(&broker, &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        for (command, reply) in [
+            ("AUTH dashboard", "OK\n"),
+            (
+                "SUBSCRIBE_ACK sensors.kitchen",
+                "ACK SUBSCRIBE sensors.kitchen\n",
+            ),
+            ("SUBSCRIBE_ACK lights.kitchen", "ERR forbidden\n"),
+            ("PSUBSCRIBE #", "ERR forbidden\n"),
+            ("PUBLISH sensors.kitchen\u{1f}21", "ERR forbidden\n"),
+            ("AUTH sensor", "OK\n"),
+            ("PUBLISH sensors.kitchen\u{1f}21", "21\n"),
+            ("SUBSCRIBE_ACK sensors.hall", "ERR forbidden\n"),
+        ] {
+            writer
+                .write_all(&protocol::encode_frame(command.as_bytes()))
+                .unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, reply, "{}", command);
+        }
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that a client declaring a command larger than the limit is told
+    /// so before the command is read, and that the server carries on.
+    #[test]
+    fn test_consumer_message_too_large() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let broker = BrokerState::default();
+            consumer(&broker, &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        writer.write_all(&100000000u32.to_be_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR message too large\n");
+
+        // The rest of the command never arrives, which ends the connection.
+        drop(reader);
+        drop(client_end);
+        consumer.join().unwrap();
+    }
+
+    /// Test that a command over the configured limit is skipped, leaving the
+    /// client able to send further commands.
+    #[test]
+    fn test_consumer_skips_large_message() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let config = Config {
+                max_message_size: Some(16),
+                ..Config::default()
+            };
+            let broker = BrokerState::new(config, None, Vec::new());
+            consumer(&broker, &mut server_end);
+        });
+
+        let mut writer = &client_end;
+        let mut reader = BufReader::new(&client_end);
+        let command = format!(
+            "PUBLISH news{}{}",
+            config::CHANNEL_SEPARATOR,
+            "x".repeat(32)
+        );
+        for command in [command.as_str(), "PING"] {
+            writer
+                .write_all(&protocol::encode_frame(command.as_bytes()))
+                .unwrap();
+        }
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR message too large\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+
+        writer
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        consumer.join().unwrap();
+    }
+
+    /// Test that the consumer stops and removes the client once the client
+    /// closes the connection.
+    #[test]
+    fn test_consumer_connection_closed() {
+        let (mut server_end, client_end) = get_stream_pair();
+        let consumer = thread::spawn(move || {
+            let broker = BrokerState::default();
+            consumer(&broker, &mut server_end);
+            !broker.clients().ids().is_empty()
+        });
+        drop(client_end);
+        assert!(!consumer.join().unwrap());
+    }
+
+    /// Test that a client which disconnects part way through a frame, or
+    /// straight after sending a command, is cleaned up without the consumer
+    /// panicking.
+    #[test]
+    fn test_consumer_disconnect_mid_stream() {
+        let subscribe = protocol::encode_frame(b"SUBSCRIBE news");
+        for rest in [
+            &protocol::encode_frame(b"PUBLISH news")[..8],
+            &[0, 0][..],
+            &protocol::encode_frame(b"PING")[..],
+        ] {
+            let frame = [&subscribe[..], rest].concat();
+            let (mut server_end, mut client_end) = get_stream_pair();
+            let consumer = thread::spawn(move || {
+                let broker = BrokerState::default();
+                consumer(&broker, &mut server_end);
+                (
+                    !broker.clients().ids().is_empty(),
+                    broker
+                        .subscriptions()
+                        .get_subscribers(&"news".to_string())
+                        .len(),
+                )
+            });
+            client_end.write_all(&frame).unwrap();
+            client_end.shutdown(Shutdown::Both).unwrap();
+            assert_eq!(consumer.join().unwrap(), (false, 0));
+        }
+    }
+
+    /// Test that the token sent with a ping is echoed back.
+    #[test]
+    fn test_ping_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        let mut reader = BufReader::new(requester);
+        ping_handler(&broker, &client, "");
+        ping_handler(&broker, &client, "42");
+        for expected in ["PONG\n", "PONG 42\n"] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+    }
+
+    /// Test that the version of the server is sent back.
+    #[test]
+    fn test_version_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        version_handler(&broker, &client);
+        let mut line = String::new();
+        BufReader::new(requester).read_line(&mut line).unwrap();
+        assert_eq!(line, format!("VERSION {}\n", env!("CARGO_PKG_VERSION")));
+    }
+
+    /// A writer which fails with an error a number of times before accepting
+    /// a few bytes at a time.
+    struct FlakyWriter {
+        failures: u32,
+        error: io::ErrorKind,
+        written: Vec<u8>,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(self.error.into());
+            }
+            let length = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..length]);
+            self.failures = 1;
+            Ok(length)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Test that transient write errors are retried up to the limit, resuming
+    /// where the write stopped, and that other errors are not retried.
+    #[test]
+    fn test_write_with_retry() {
+        let delay = Duration::from_millis(1);
+        let mut writer = FlakyWriter {
+            failures: 2,
+            error: io::ErrorKind::WouldBlock,
+            written: Vec::new(),
+        };
+        write_with_retry(&mut writer, b"hello world", 2, delay).unwrap();
+        assert_eq!(writer.written, b"hello world");
+
+        writer.failures = 3;
+        let error = write_with_retry(&mut writer, b"hello", 2, delay).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        writer.failures = 1;
+        writer.error = io::ErrorKind::BrokenPipe;
+        let error = write_with_retry(&mut writer, b"hello", 2, delay).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    /// Test that text messages are terminated by a single newline and that
+    /// binary messages are framed with their length, bytes unchanged.
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(state::Encoding::Text, b"hello"), b"hello\n");
+        assert_eq!(encode(state::Encoding::Text, b"hello\n"), b"hello\n");
+        assert_eq!(
+            encode(state::Encoding::Binary, b"hello\n"),
+            [&[0, 0, 0, 6][..], b"hello\n"].concat()
+        );
+        assert_eq!(
+            encode(state::Encoding::Binary, b"a\nb"),
+            [&[0, 0, 0, 3][..], b"a\nb"].concat()
+        );
+    }
+
+    /// Test that the encoding is only switched once the reply has been
+    /// written, and that unknown encodings are downgraded to text.
+    #[test]
+    fn test_encoding_handler() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        let mut reader = BufReader::new(requester);
+
+        encoding_handler(&broker, &client, "binary");
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ENCODING BINARY\n");
+
+        encoding_handler(&broker, &client, "morse");
+        assert_eq!(protocol::read_frame(&mut reader).unwrap(), b"ENCODING TEXT");
+
+        ping_handler(&broker, &client, "");
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that a disconnected client's subscriptions are kept under its
+    /// session and restored to the client that resumes it.
+    #[test]
+    fn test_resume_handler() {
+        let broker = BrokerState::new(
+            Config {
+                session_grace: Some(Duration::from_secs(60)),
+                ..Config::default()
+            },
+            None,
+            Vec::new(),
+        );
+        let channel = "test_resume_handler".to_string();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        subscribe_handler(&broker, &client, &channel);
+        session_handler(&broker, &client);
+        let mut line = String::new();
+        BufReader::new(requester).read_line(&mut line).unwrap();
+        let session_id = line.trim().strip_prefix("SESSION ").unwrap().to_string();
+        disconnect_handler(&broker, &client);
+        assert!(!broker.subscriptions().is_subscribed(&client, &channel));
+
+        let (resumer, requester) = get_stream_pair();
+        let resumer = broker.clients().add_client(Box::new(resumer));
+        let mut reader = BufReader::new(requester);
+        resume_handler(&broker, &resumer, "unknown");
+        resume_handler(&broker, &resumer, &session_id);
+        for expected in [
+            "ERR unknown session\n".to_string(),
+            format!("RESUMED {}\n", session_id),
+        ] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+        assert!(broker.subscriptions().is_subscribed(&resumer, &channel));
+        disconnect_handler(&broker, &resumer);
+    }
+
+    /// Test that sessions cannot be started unless a grace period is set.
+    #[test]
+    fn test_session_handler_disabled() {
+        let broker = BrokerState::default();
+        let (client, requester) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        session_handler(&broker, &client);
+        let mut line = String::new();
+        BufReader::new(requester).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR sessions disabled\n");
+        broker.clients().remove_client(&client);
+    }
+
+    /// Test that the function is to disconnect a client.
+    #[test]
+    fn test_disconnect_handler() {
+        let broker = BrokerState::default();
+        let (client, _client_end) = get_stream_pair();
+        let client = broker.clients().add_client(Box::new(client));
+        disconnect_handler(&broker, &client);
+        assert!(!broker.clients().is_connected(&client));
     }
 }
@@ -0,0 +1,143 @@
+//! # Message Hooks
+//! This library contains the hooks which published messages pass through
+//! before they are delivered, allowing messages to be transformed (e.g.
+//! redacted or enriched) or dropped.
+//! Hooks run on the thread publishing the message, in the order they were
+//! registered, so they should be fast.
+
+/// Transforms messages as they are published.
+pub trait MessageHook: Send + Sync {
+    /// Transforms a message before it is delivered to subscribers. By default
+    /// the message is left unchanged.
+    /// # Arguments
+    /// * `channel` - The channel the message was published on.
+    /// * `message` - The message.
+    /// # Returns
+    /// The message to deliver, or `None` to drop the message.
+    fn transform(&self, channel: &str, message: String) -> Option<String> {
+        let _ = channel;
+        Some(message)
+    }
+
+    /// Transforms a message which is not valid UTF-8, and so cannot be passed
+    /// to `transform`. By default the message is left unchanged, so hooks
+    /// which must see every message, such as ones redacting or dropping
+    /// messages, should transform these too.
+    /// # Arguments
+    /// * `channel` - The channel the message was published on.
+    /// * `message` - The bytes of the message.
+    /// # Returns
+    /// The message to deliver, or `None` to drop the message.
+    fn transform_bytes(&self, channel: &str, message: Vec<u8>) -> Option<Vec<u8>> {
+        let _ = channel;
+        Some(message)
+    }
+}
+
+impl<F> MessageHook for F
+where
+    F: Fn(&str, String) -> Option<String> + Send + Sync,
+{
+    fn transform(&self, channel: &str, message: String) -> Option<String> {
+        self(channel, message)
+    }
+}
+
+/// Passes a message through hooks in turn.
+/// # Arguments
+/// * `hooks` - The hooks to pass the message through.
+/// * `channel` - The channel the message was published on.
+/// * `message` - The message.
+/// # Returns
+/// The message to deliver, or `None` if a hook dropped the message.
+pub fn apply(hooks: &[Box<dyn MessageHook>], channel: &str, message: String) -> Option<String> {
+    hooks
+        .iter()
+        .try_fold(message, |message, hook| hook.transform(channel, message))
+}
+
+/// Passes a message which may not be UTF-8 through hooks in turn. A UTF-8
+/// message is passed to each hook's `transform`, any other message to its
+/// `transform_bytes`.
+/// # Arguments
+/// * `hooks` - The hooks to pass the message through.
+/// * `channel` - The channel the message was published on.
+/// * `message` - The bytes of the message.
+/// # Returns
+/// The message to deliver, or `None` if a hook dropped the message.
+pub fn apply_bytes(
+    hooks: &[Box<dyn MessageHook>],
+    channel: &str,
+    message: Vec<u8>,
+) -> Option<Vec<u8>> {
+    match String::from_utf8(message) {
+        Ok(message) => apply(hooks, channel, message).map(String::into_bytes),
+        Err(e) => hooks.iter().try_fold(e.into_bytes(), |message, hook| {
+            hook.transform_bytes(channel, message)
+        }),
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hook which relies on the default transformation.
+    struct NoOp;
+
+    impl MessageHook for NoOp {}
+
+    /// Test that hooks run in the order they were registered and that a hook
+    /// can drop a message.
+    #[test]
+    fn test_apply() {
+        let hooks: Vec<Box<dyn MessageHook>> = vec![
+            Box::new(NoOp),
+            Box::new(|_: &str, message: String| Some(message.replace("secret", "******"))),
+            Box::new(|channel: &str, message: String| match channel {
+                "drop" => None,
+                _ => Some(format!("[redacted] {}", message)),
+            }),
+        ];
+
+        assert_eq!(
+            apply(&hooks, "news", "the secret".to_string()),
+            Some("[redacted] the ******".to_string())
+        );
+        assert_eq!(apply(&hooks, "drop", "hello".to_string()), None);
+    }
+
+    /// A hook which drops every message, whether it is UTF-8 or not.
+    struct DropAll;
+
+    impl MessageHook for DropAll {
+        fn transform(&self, _: &str, _: String) -> Option<String> {
+            None
+        }
+
+        fn transform_bytes(&self, _: &str, _: Vec<u8>) -> Option<Vec<u8>> {
+            None
+        }
+    }
+
+    /// Test that UTF-8 messages are transformed as text, and that other
+    /// messages are only transformed by hooks which handle bytes.
+    #[test]
+    fn test_apply_bytes() {
+        let hooks: Vec<Box<dyn MessageHook>> = vec![Box::new(|_: &str, message: String| {
+            Some(message.to_uppercase())
+        })];
+        assert_eq!(
+            apply_bytes(&hooks, "news", b"hello".to_vec()),
+            Some(b"HELLO".to_vec())
+        );
+        assert_eq!(
+            apply_bytes(&hooks, "news", vec![0xff, b'a']),
+            Some(vec![0xff, b'a'])
+        );
+
+        let hooks: Vec<Box<dyn MessageHook>> = vec![Box::new(DropAll)];
+        assert_eq!(apply_bytes(&hooks, "news", vec![0xff]), None);
+    }
+}
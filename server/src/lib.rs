@@ -1,4 +1,14 @@
-extern crate lazy_static;
-
+pub mod acl;
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod audit;
+pub mod config;
 pub mod consumer;
+pub mod hook;
+pub mod metrics;
+pub mod pool;
+pub use common::protocol;
+pub mod server;
 pub mod state;
+pub use common::stream;
+pub mod tls;
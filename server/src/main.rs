@@ -4,52 +4,61 @@
 //! It is the main entry point for the server and is ultimately responsible for
 //! facilitating the communication between the client and the server.
 
-use server::{consumer, state};
-use std::net::TcpListener;
-use std::thread;
+use log::info;
+use server::{acl::Acl, audit, config, server::ServerBuilder, tls};
+use std::{sync::mpsc, time::Duration};
+use structopt::StructOpt;
 
-struct Server {
-    listener: TcpListener,
-}
+fn main() {
+    // Log at info unless RUST_LOG says otherwise, so that connections are
+    // reported by default.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-impl Server {
-    fn new(listener: TcpListener) -> Server {
-        Server { listener }
+    // Get host and port from command line arguments or use defaults
+    let options = config::Options::from_args();
+    let mut config = options.config();
+    if let Some(path) = &options.auth_tokens_file {
+        let tokens = config::read_tokens(path).expect("Could not read the auth tokens file");
+        config.auth_tokens.extend(tokens);
+    }
+    if let Some(path) = &options.acl_file {
+        config.acl = Some(Acl::load(path).expect("Could not load the ACL file"));
+    }
+    let mut builder = ServerBuilder::new(options.host.clone(), options.port).config(config);
+    for host in &options.bind {
+        builder = builder.bind(host.clone());
     }
 
-    fn run(&self) {
-        for stream in self.listener.incoming() {
-            let client = state::Client::new();
-            match stream {
-                Ok(mut stream) => {
-                    client.add_client(&stream);
-                    println!("New client connected");
-                    thread::spawn(move || {
-                        consumer::consumer(&mut stream);
-                    });
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                }
-            }
-        }
+    if let Some(path) = options.audit_log.clone() {
+        let log = audit::AuditLog::open(path, options.audit_log_max_size, options.audit_log_body)
+            .expect("Could not open the audit log");
+        builder = builder.audit_log(log);
     }
-}
 
-fn main() {
-    // Get host and port from command line arguments or use defaults
-    let args: Vec<String> = std::env::args().collect();
+    if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+        let config =
+            tls::server_config(cert, key).expect("Could not load the TLS certificate and key");
+        builder = builder.tls(config);
+    }
 
-    let host = if args.len() > 1 {
-        &args[1]
-    } else {
-        "localhost"
-    };
-    let port = if args.len() > 2 { &args[2] } else { "7878" };
+    let server = builder.start().expect("Could not bind to port");
+    for addr in server.local_addrs() {
+        info!("Listening on {}", addr);
+    }
 
-    let listener: TcpListener =
-        TcpListener::bind(format!("{}:{}", host, port)).expect("Could not bind to port");
-    let server = Server::new(listener);
-    println!("Listening on {}:{}", host, port);
-    server.run();
+    // Shut down cleanly on Ctrl-C, so that queued messages are delivered and
+    // clients are told the server is going away.
+    let (interrupted, interrupt) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = interrupted.send(());
+    })
+    .expect("Could not handle Ctrl-C");
+    // An admin client may stop the server with SHUTDOWN instead.
+    while !server.is_stopped() {
+        if interrupt.recv_timeout(Duration::from_millis(100)).is_ok() {
+            info!("Shutting down");
+            break;
+        }
+    }
+    server.shutdown();
 }
@@ -0,0 +1,131 @@
+//! # Metrics
+//! This library contains the counters a broker keeps for observability, such
+//! as how many clients are connected and how many messages have been
+//! published. The counters are atomic, so they are updated without taking a
+//! lock, and are read together as a `Stats` snapshot, which the `STATS`
+//! command replies with as JSON.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The counters of a broker.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    total_connections: AtomicU64,
+    current_connections: AtomicU64,
+    messages_published: AtomicU64,
+    bytes_published: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a client connecting.
+    pub fn connected(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.current_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client disconnecting. Each client connecting must only be
+    /// counted as disconnecting once.
+    pub fn disconnected(&self) {
+        self.current_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts a message being published.
+    /// # Arguments
+    /// * `bytes` - The size of the body of the message.
+    pub fn published(&self, bytes: usize) {
+        self.messages_published.fetch_add(1, Ordering::Relaxed);
+        self.bytes_published
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Reads the counters.
+    /// # Arguments
+    /// * `channels` - The number of channels with at least one subscriber,
+    ///   which is known from the subscriptions rather than counted here.
+    pub fn snapshot(&self, channels: usize) -> Stats {
+        Stats {
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            current_connections: self.current_connections.load(Ordering::Relaxed),
+            channels: channels as u64,
+            messages_published: self.messages_published.load(Ordering::Relaxed),
+            bytes_published: self.bytes_published.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters of a broker at one moment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of clients which have connected since the broker started.
+    pub total_connections: u64,
+    /// The number of clients connected now.
+    pub current_connections: u64,
+    /// The number of channels with at least one subscriber.
+    pub channels: u64,
+    /// The number of messages published since the broker started, including
+    /// those nobody was subscribed to.
+    pub messages_published: u64,
+    /// The total size of the bodies of the messages published.
+    pub bytes_published: u64,
+}
+
+impl Stats {
+    /// Formats the counters as a JSON object, such as
+    /// `{"total_connections":3,"current_connections":1,...}`.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"total_connections\":{},\"current_connections\":{},\"channels\":{},\
+             \"messages_published\":{},\"bytes_published\":{}}}",
+            self.total_connections,
+            self.current_connections,
+            self.channels,
+            self.messages_published,
+            self.bytes_published
+        )
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that connections are counted both in total and while connected,
+    /// and that published messages are counted with their size.
+    #[test]
+    fn test_snapshot() {
+        let metrics = Metrics::default();
+        metrics.connected();
+        metrics.connected();
+        metrics.disconnected();
+        metrics.published(5);
+        metrics.published(0);
+        assert_eq!(
+            metrics.snapshot(3),
+            Stats {
+                total_connections: 2,
+                current_connections: 1,
+                channels: 3,
+                messages_published: 2,
+                bytes_published: 5,
+            }
+        );
+    }
+
+    /// Test that the counters are formatted as a JSON object.
+    #[test]
+    fn test_to_json() {
+        let stats = Stats {
+            total_connections: 4,
+            current_connections: 2,
+            channels: 1,
+            messages_published: 10,
+            bytes_published: 120,
+        };
+        assert_eq!(
+            stats.to_json(),
+            "{\"total_connections\":4,\"current_connections\":2,\"channels\":1,\
+             \"messages_published\":10,\"bytes_published\":120}"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! # Worker Pool
+//! This library contains a fixed set of worker threads which connections are
+//! handed to, so that a flood of connections cannot make the server spawn a
+//! thread for each of them.
+//! Connections waiting for a worker are held in a bounded queue. Once the
+//! queue is full, further connections are refused rather than queued.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// A piece of work run by a worker, such as handling a connection.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads running jobs from a bounded queue.
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts the worker threads.
+    /// # Arguments
+    /// * `workers` - The number of threads, at least 1.
+    /// * `queue` - How many jobs may wait for a thread at once.
+    pub fn new(workers: usize, queue: usize) -> WorkerPool {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || work(&receiver))
+            })
+            .collect();
+        WorkerPool { sender, workers }
+    }
+
+    /// Queues a job to be run by the next free worker.
+    /// # Arguments
+    /// * `job` - The job to run.
+    /// # Returns
+    /// False if the queue is full, in which case the job is dropped without
+    /// being run.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            // The workers only stop once the pool is joined, which needs the
+            // pool itself.
+            Err(TrySendError::Disconnected(_)) => unreachable!("workers stopped"),
+        }
+    }
+
+    /// Waits for the queued jobs to be run and stops the worker threads.
+    pub fn join(self) {
+        drop(self.sender);
+        for worker in self.workers {
+            worker.join().unwrap();
+        }
+    }
+}
+
+/// Runs jobs until the pool is joined.
+/// # Arguments
+/// * `receiver` - The queue of jobs, shared by every worker.
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is released before the job runs, so that the other workers
+        // are able to take jobs meanwhile.
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            // A job which panics must not take the worker down with it, or
+            // the pool would shrink with every panic.
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Test that a pool runs more jobs than it has workers.
+    #[test]
+    fn test_runs_more_jobs_than_workers() {
+        let pool = WorkerPool::new(2, 10);
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..8 {
+            let sender = sender.clone();
+            assert!(pool.execute(move || sender.send(i).unwrap()));
+        }
+        pool.join();
+
+        let mut done: Vec<i32> = receiver.try_iter().collect();
+        done.sort();
+        assert_eq!(done, (0..8).collect::<Vec<_>>());
+    }
+
+    /// Test that a worker carries on running jobs after one panics.
+    #[test]
+    fn test_panicking_job() {
+        let pool = WorkerPool::new(1, 10);
+        let (sender, receiver) = mpsc::channel();
+        assert!(pool.execute(|| panic!("job failed")));
+        assert!(pool.execute(move || sender.send(()).unwrap()));
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.join();
+    }
+
+    /// Test that jobs are refused once every worker is busy and the queue is
+    /// full.
+    #[test]
+    fn test_full_queue() {
+        let pool = WorkerPool::new(1, 1);
+        let (started, start) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        assert!(pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = finished.recv();
+        }));
+        start.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(pool.execute(|| ()));
+        assert!(!pool.execute(|| ()));
+        drop(finish);
+        pool.join();
+    }
+}
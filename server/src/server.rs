@@ -0,0 +1,653 @@
+//! # Embedded Server
+//! This library allows the broker to be run from within other programs. A
+//! `ServerBuilder` configures the server and `start` returns a
+//! `RunningServer` which can be shut down.
+//! Each server owns its state, so several servers are able to run in the same
+//! process.
+
+use super::pool::WorkerPool;
+use super::state::BrokerState;
+use super::stream::Stream;
+use super::{audit, config, consumer, hook, metrics, tls};
+use log::{error, info, warn};
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Configures a server before it starts listening for connections.
+pub struct ServerBuilder {
+    hosts: Vec<String>,
+    port: u16,
+    config: config::Config,
+    audit_log: Option<audit::AuditLog>,
+    hooks: Vec<Box<dyn hook::MessageHook>>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl ServerBuilder {
+    /// Creates a builder for a server.
+    /// # Arguments
+    /// * `host` - The hostname to listen on.
+    /// * `port` - The port to listen on, or 0 for any free port.
+    pub fn new(host: String, port: u16) -> ServerBuilder {
+        ServerBuilder {
+            hosts: vec![host],
+            port,
+            config: config::Config::default(),
+            audit_log: None,
+            hooks: Vec::new(),
+            tls: None,
+        }
+    }
+
+    /// Adds another hostname to listen on, using the same port. This allows
+    /// the server to listen on several interfaces or address families at
+    /// once, such as `0.0.0.0` and `::`. When the port is 0, the free port
+    /// found for the first hostname is used for the others.
+    /// # Arguments
+    /// * `host` - The hostname to listen on.
+    /// # Remarks
+    /// Some systems, including Linux by default, accept IPv4 connections on
+    /// sockets listening on `::`, in which case `::` cannot be listened on
+    /// alongside `0.0.0.0` using the same port. Listening on `::` alone
+    /// accepts both.
+    pub fn bind(mut self, host: String) -> ServerBuilder {
+        self.hosts.push(host);
+        self
+    }
+
+    /// Sets the configuration of the server. The default configuration is
+    /// used when none is set.
+    /// # Arguments
+    /// * `config` - The configuration.
+    pub fn config(mut self, config: config::Config) -> ServerBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Sets the audit log published messages are recorded to.
+    /// # Arguments
+    /// * `log` - The audit log.
+    pub fn audit_log(mut self, log: audit::AuditLog) -> ServerBuilder {
+        self.audit_log = Some(log);
+        self
+    }
+
+    /// Requires clients to connect over TLS, see `tls::server_config`.
+    /// # Arguments
+    /// * `config` - The TLS configuration, holding the server's certificate.
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> ServerBuilder {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Adds a hook which every published message passes through, see
+    /// `hook::MessageHook`.
+    /// # Arguments
+    /// * `hook` - The hook to add.
+    pub fn hook(mut self, hook: impl hook::MessageHook + 'static) -> ServerBuilder {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Starts listening for connections. Each hostname is listened on by its
+    /// own thread. Connections are handled by a fixed set of worker threads,
+    /// see `config::Config::workers`, until the server is shut down.
+    /// # Returns
+    /// The running server, or the error from the first hostname which could
+    /// not be listened on.
+    pub fn start(self) -> io::Result<RunningServer> {
+        let mut listeners = Vec::new();
+        let mut port = self.port;
+        for host in &self.hosts {
+            let listener = TcpListener::bind((host.as_str(), port))?;
+            let addr = listener.local_addr()?;
+            port = addr.port();
+            listeners.push((addr, listener));
+        }
+
+        let has_audit_log = self.audit_log.is_some();
+        let broker = Arc::new(BrokerState::new(self.config, self.audit_log, self.hooks));
+
+        // Periodically remove clients that have stopped sending heartbeats
+        // from the presence channel.
+        let config = broker.config();
+        if let (true, Some(timeout)) = (config.presence, config.presence_timeout) {
+            let broker = broker.clone();
+            thread::spawn(move || {
+                while !broker.is_stopped() {
+                    thread::sleep(timeout / 2);
+                    consumer::reap_presence(&broker, timeout);
+                }
+            });
+        }
+
+        // Connections reset by clients are found even when nothing is being
+        // written to them.
+        if let Some(interval) = config.keepalive {
+            let broker = broker.clone();
+            thread::spawn(move || {
+                while !broker.is_stopped() {
+                    thread::sleep(interval);
+                    consumer::probe_clients(&broker);
+                }
+            });
+        }
+
+        // Clients which stop answering heartbeats are disconnected.
+        if let Some(interval) = config.heartbeat {
+            let broker = broker.clone();
+            let misses = config.heartbeat_misses;
+            thread::spawn(move || {
+                while !broker.is_stopped() {
+                    thread::sleep(interval);
+                    consumer::send_heartbeats(&broker, misses);
+                }
+            });
+        }
+
+        // Buffered audit records are flushed periodically rather than per
+        // message.
+        if has_audit_log {
+            let broker = broker.clone();
+            thread::spawn(move || {
+                while !broker.is_stopped() {
+                    thread::sleep(Duration::from_secs(1));
+                    broker.flush_audit_log();
+                }
+            });
+        }
+
+        let workers = config
+            .workers
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get) * 4);
+        let queue = config.worker_queue.unwrap_or(config::DEFAULT_WORKER_QUEUE);
+        let pool = Arc::new(WorkerPool::new(workers, queue));
+
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let mut addrs = Vec::new();
+        let mut handles = Vec::new();
+        for (addr, listener) in listeners {
+            let broker = broker.clone();
+            let connections = connections.clone();
+            let pool = pool.clone();
+            let tls = self.tls.clone();
+            addrs.push(addr);
+            handles.push(thread::spawn(move || {
+                accept(broker, listener, connections, &pool, tls)
+            }));
+        }
+
+        broker.set_listen_addrs(addrs.clone());
+        Ok(RunningServer {
+            addrs,
+            broker,
+            connections,
+            pool,
+            handles,
+        })
+    }
+}
+
+/// A connection being handled by the server, and a token held by the job
+/// handling it. The job drops its clone of the token once it has finished,
+/// however it finishes.
+type Connection = (Box<dyn Stream>, Arc<()>);
+
+/// How long a client may take over the TLS handshake, so that clients which
+/// never complete it do not hold on to a worker.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts connections until the server is stopped.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `listener` - The listener to accept connections from.
+/// * `connections` - The connections accepted so far.
+/// * `pool` - The workers handling the connections.
+/// * `tls` - The TLS configuration, if connections are encrypted.
+fn accept(
+    broker: Arc<BrokerState>,
+    listener: TcpListener,
+    connections: Arc<Mutex<Vec<Connection>>>,
+    pool: &WorkerPool,
+    tls: Option<Arc<rustls::ServerConfig>>,
+) {
+    for stream in listener.incoming() {
+        if broker.is_stopped() {
+            break;
+        }
+        match stream {
+            Ok(mut stream) => {
+                let job_connections = connections.clone();
+                let mut connections = connections.lock().unwrap();
+                connections.retain(|(_, token)| Arc::strong_count(token) > 1);
+                if let Some(max) = broker.config().max_connections {
+                    if connections.len() >= max {
+                        let reason = format!("{} connections open", connections.len());
+                        reject(&mut stream, &reason, "SERVER_BUSY");
+                        continue;
+                    }
+                }
+
+                info!("New client connected");
+                let mut connection = match stream.try_clone() {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                };
+                // The consumer registers the client as the stream must not
+                // move once registered.
+                let broker = broker.clone();
+                let token = Arc::new(());
+                let job_token = token.clone();
+                let tls = tls.clone();
+                let queued = pool.execute(move || match tls {
+                    Some(config) => {
+                        handle_tls(&broker, config, stream, &job_connections, &job_token)
+                    }
+                    None => {
+                        let _token = job_token;
+                        consumer::consumer(&broker, &mut stream);
+                    }
+                });
+                match queued {
+                    true => connections.push((Box::new(connection), token)),
+                    false => reject(&mut connection, "no worker free", "ERR server busy"),
+                }
+            }
+            Err(e) => {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Completes the TLS handshake with a client and then handles its
+/// connection. The connection tracked for the server's shutdown is replaced
+/// with the encrypted one, so that the session is closed cleanly when the
+/// server shuts down.
+/// # Arguments
+/// * `broker` - The state of the broker.
+/// * `config` - The TLS configuration of the server.
+/// * `stream` - The connection accepted from the client.
+/// * `connections` - The connections accepted so far.
+/// * `token` - The token of the connection, held until it has been handled.
+fn handle_tls(
+    broker: &BrokerState,
+    config: Arc<rustls::ServerConfig>,
+    stream: TcpStream,
+    connections: &Mutex<Vec<Connection>>,
+    token: &Arc<()>,
+) {
+    // A client which connects but never completes the handshake would
+    // otherwise hold on to the worker forever.
+    if let Err(e) = stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+    {
+        warn!("Failed to set handshake timeout: {}", e);
+        return;
+    }
+    let mut stream = match tls::accept(config, stream) {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("TLS handshake failed: {}", e);
+            return;
+        }
+    };
+    // The client may stay quiet for as long as it likes once connected, and
+    // the consumer sets its own write timeout.
+    if let Err(e) = stream.socket().set_read_timeout(None) {
+        warn!("Failed to clear handshake timeout: {}", e);
+        return;
+    }
+    if let Ok(clone) = stream.try_clone() {
+        let mut connections = connections.lock().unwrap();
+        if let Some(connection) = connections.iter_mut().find(|(_, t)| Arc::ptr_eq(t, token)) {
+            connection.0 = clone;
+        }
+    }
+    consumer::consumer(broker, &mut stream);
+}
+
+/// Turns away a connection as the server is too busy to handle it, such as
+/// when it is at its connection limit. The client is told why before the
+/// connection is closed.
+/// # Arguments
+/// * `stream` - The connection to turn away.
+/// * `reason` - Why the connection is turned away, for the log.
+/// * `reply` - What the client is told, without the trailing newline.
+fn reject(stream: &mut TcpStream, reason: &str, reply: &str) {
+    match stream.peer_addr() {
+        Ok(addr) => warn!("Rejecting {}: {}", addr, reason),
+        Err(_) => warn!("Rejecting connection: {}", reason),
+    }
+    // The client may already be gone, in which case there is nobody to tell.
+    let _ = stream.write_all(format!("{}\n", reply).as_bytes());
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// A server which is listening for connections.
+pub struct RunningServer {
+    addrs: Vec<SocketAddr>,
+    broker: Arc<BrokerState>,
+    connections: Arc<Mutex<Vec<Connection>>>,
+    pool: Arc<WorkerPool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl RunningServer {
+    /// Gets the address the server is listening on, or the first address if
+    /// it is listening on several.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// Gets every address the server is listening on.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Gets every channel with at least one subscriber, along with its number
+    /// of subscribers, without going through a connection.
+    /// # Returns
+    /// The channels and their subscriber counts, sorted by channel.
+    pub fn channels(&self) -> Vec<(String, usize)> {
+        self.broker.subscriptions().channels()
+    }
+
+    /// Reads the counters of the server, such as how many clients are
+    /// connected, without going through a connection.
+    pub fn stats(&self) -> metrics::Stats {
+        self.broker.stats()
+    }
+
+    /// Checks if the server has stopped accepting connections, such as when an
+    /// admin client has sent `SHUTDOWN`. The server still has to be shut down
+    /// to close its connections.
+    pub fn is_stopped(&self) -> bool {
+        self.broker.is_stopped()
+    }
+
+    /// Blocks until the server stops accepting connections.
+    pub fn wait(self) {
+        for handle in self.handles {
+            handle.join().unwrap();
+        }
+    }
+
+    /// Shuts down the server. The server stops accepting connections, delivers
+    /// the messages still queued for subscribers, for at most the shutdown
+    /// timeout, and then tells every client `SHUTDOWN` and closes its
+    /// connection. The ports listened on are released once this returns.
+    pub fn shutdown(self) {
+        // The server may already have been stopped by an admin client.
+        consumer::stop(&self.broker);
+        for handle in self.handles {
+            handle.join().unwrap();
+        }
+
+        let connections = std::mem::take(&mut *self.connections.lock().unwrap());
+        for (stream, _) in connections {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        // The listeners, which shared the pool, have stopped. Every connection
+        // has been closed, so the workers finish once their consumers see it.
+        if let Ok(pool) = Arc::try_unwrap(self.pool) {
+            pool.join();
+        }
+    }
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use std::io::{BufRead, BufReader, Write};
+
+    /// Test that a started server handles commands until it is shut down,
+    /// after which its connections are closed.
+    #[test]
+    fn test_start_and_shutdown() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .start()
+            .unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(&protocol::encode_frame(b"PING")).unwrap();
+
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+
+        server.shutdown();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "SHUTDOWN\n");
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    /// Test that an admin client sending `SHUTDOWN` stops an embedded server
+    /// without ending the process running it, which then closes the
+    /// connections.
+    #[test]
+    fn test_shutdown_command() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .config(config::Config {
+                admin_token: Some("secret".to_string()),
+                ..config::Config::default()
+            })
+            .start()
+            .unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        for (command, reply) in [("AUTH secret", "OK\n"), ("SHUTDOWN", "OK\n")] {
+            client
+                .write_all(&protocol::encode_frame(command.as_bytes()))
+                .unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, reply);
+        }
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "SHUTDOWN\n");
+        assert!(server.is_stopped());
+
+        server.shutdown();
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    /// Test that binary clients are told of the shutdown with a frame, and
+    /// that the port can be listened on again once the server has shut down.
+    #[test]
+    fn test_shutdown_releases_port() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .start()
+            .unwrap();
+        let addr = server.local_addr();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(&protocol::encode_frame(b"ENCODING BINARY"))
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ENCODING BINARY\n");
+
+        server.shutdown();
+        assert_eq!(protocol::read_frame(&mut reader).unwrap(), b"SHUTDOWN");
+        ServerBuilder::new("127.0.0.1".to_string(), addr.port())
+            .start()
+            .unwrap()
+            .shutdown();
+    }
+
+    /// Test that the channels of an embedded server can be listed directly.
+    #[test]
+    fn test_channels() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .start()
+            .unwrap();
+        assert!(server.channels().is_empty());
+
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client
+            .write_all(&protocol::encode_frame(b"SUBSCRIBE_ACK news"))
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(client.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "ACK SUBSCRIBE news\n");
+        assert_eq!(server.channels(), vec![("news".to_string(), 1)]);
+        server.shutdown();
+    }
+
+    /// Test that connections over the limit are told the server is busy and
+    /// closed, while the connections already open are unaffected.
+    #[test]
+    fn test_max_connections() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .config(config::Config {
+                max_connections: Some(1),
+                ..config::Config::default()
+            })
+            .start()
+            .unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(&protocol::encode_frame(b"PING")).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+
+        let rejected = TcpStream::connect(server.local_addr()).unwrap();
+        let mut rejected = BufReader::new(rejected);
+        let mut line = String::new();
+        rejected.read_line(&mut line).unwrap();
+        assert_eq!(line, "SERVER_BUSY\n");
+        let mut line = String::new();
+        assert_eq!(rejected.read_line(&mut line).unwrap(), 0);
+
+        client.write_all(&protocol::encode_frame(b"PING")).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+        server.shutdown();
+    }
+
+    /// Test that a server handles more connections over its lifetime than it
+    /// has workers, as each worker moves on once its connection closes.
+    #[test]
+    fn test_more_connections_than_workers() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .config(config::Config {
+                workers: Some(2),
+                ..config::Config::default()
+            })
+            .start()
+            .unwrap();
+        for _ in 0..6 {
+            let mut client = TcpStream::connect(server.local_addr()).unwrap();
+            client
+                .write_all(
+                    &[
+                        protocol::encode_frame(b"PING"),
+                        protocol::encode_frame(b"DISCONNECT"),
+                    ]
+                    .concat(),
+                )
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(client).read_line(&mut line).unwrap();
+            assert_eq!(line, "PONG\n");
+        }
+        server.shutdown();
+    }
+
+    /// Test that connections wait for a worker while every worker is busy,
+    /// and that connections beyond the queue are told the server is busy.
+    #[test]
+    fn test_worker_queue_full() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .config(config::Config {
+                workers: Some(1),
+                worker_queue: Some(1),
+                ..config::Config::default()
+            })
+            .start()
+            .unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(&protocol::encode_frame(b"PING")).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+
+        let mut queued = TcpStream::connect(server.local_addr()).unwrap();
+        queued.write_all(&protocol::encode_frame(b"PING")).unwrap();
+        let rejected = TcpStream::connect(server.local_addr()).unwrap();
+        let mut line = String::new();
+        BufReader::new(rejected).read_line(&mut line).unwrap();
+        assert_eq!(line, "ERR server busy\n");
+
+        // The queued connection is handled once the worker is free.
+        client
+            .write_all(&protocol::encode_frame(b"DISCONNECT"))
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(queued.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "PONG\n");
+        server.shutdown();
+    }
+
+    /// Test that a server listening on several addresses uses the same port
+    /// for each, and shares its state between them.
+    #[test]
+    fn test_bind_several() {
+        let server = ServerBuilder::new("127.0.0.1".to_string(), 0)
+            .bind("::1".to_string())
+            .start()
+            .unwrap();
+        let addrs = server.local_addrs().to_vec();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].port(), addrs[1].port());
+
+        let mut subscriber = TcpStream::connect(addrs[0]).unwrap();
+        subscriber
+            .write_all(
+                &[
+                    protocol::encode_frame(b"SUBSCRIBE news"),
+                    protocol::encode_frame(b"PING"),
+                ]
+                .concat(),
+            )
+            .unwrap();
+        let mut reader = BufReader::new(subscriber.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "PONG\n");
+
+        let mut publisher = TcpStream::connect(addrs[1]).unwrap();
+        publisher
+            .write_all(&protocol::encode_frame(b"PUBLISH news hello"))
+            .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+        server.shutdown();
+    }
+}
@@ -0,0 +1,129 @@
+//! # TLS
+//! This library contains the optional TLS layer of the server, which encrypts
+//! connections using a certificate and private key loaded from PEM files.
+//! The consumer reads and writes the resulting `TlsStream` just as it does a
+//! plain `TcpStream`, see `stream::Stream`.
+
+use crate::stream::TlsStream;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+/// Loads the TLS configuration of the server.
+/// # Arguments
+/// * `cert` - A PEM file holding the certificate chain, starting with the
+///   server's own certificate.
+/// * `key` - A PEM file holding the private key of the certificate.
+/// # Returns
+/// The configuration, or an error if either file cannot be read or they do
+/// not hold a usable certificate and key.
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", cert.display()),
+        ));
+    }
+    let key =
+        rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No private key found in {}", key.display()),
+            )
+        })?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+/// Completes the TLS handshake with a client. Any timeouts set on the
+/// connection bound the handshake.
+/// # Arguments
+/// * `config` - The TLS configuration of the server.
+/// * `stream` - The connection accepted from the client.
+/// # Returns
+/// The encrypted connection, or an error if the handshake failed.
+pub fn accept(config: Arc<rustls::ServerConfig>, stream: TcpStream) -> io::Result<TlsStream> {
+    let connection = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+    TlsStream::new(connection.into(), stream)
+}
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::Stream;
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{Shutdown, TcpListener},
+        thread,
+    };
+
+    /// Test that a certificate file without a certificate is refused, naming
+    /// the file.
+    #[test]
+    fn test_missing_certificate() {
+        let path = std::env::temp_dir().join(format!("tls-empty-{}.pem", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let error = server_config(&path, &path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("No certificate found"));
+    }
+
+    /// Test that traffic is decrypted both ways, including through clones of
+    /// the stream, and that shutting the stream down closes the session.
+    #[test]
+    fn test_accept() {
+        let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("../client/tests/tls");
+        let config = server_config(&tests.join("server.pem"), &tests.join("server.key")).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(
+            File::open(tests.join("ca.pem")).unwrap(),
+        )) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            accept(config, stream).unwrap()
+        });
+        let name = "localhost".try_into().unwrap();
+        let connection = rustls::ClientConnection::new(client_config, name).unwrap();
+        let mut client =
+            TlsStream::new(connection.into(), TcpStream::connect(addr).unwrap()).unwrap();
+        let mut server = server.join().unwrap();
+        // The client is seen at its own address.
+        assert_eq!(
+            server.socket().peer_addr().unwrap(),
+            client.socket().local_addr().unwrap()
+        );
+
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        server.try_clone().unwrap().write_all(b"pong").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"pong");
+
+        server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+}
@@ -0,0 +1,77 @@
+//! # Async Server Load
+//! These tests connect many idle subscribers to the async server, which needs
+//! the `async` feature. They run in their own process, so that the threads
+//! counted are only those of the server and the test.
+#![cfg(all(feature = "async", target_os = "linux"))]
+
+use server::{async_server::AsyncServer, config::Config, protocol};
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// The number of idle subscribers connected at once.
+const SUBSCRIBERS: usize = 2000;
+
+/// Counts the threads of this process.
+fn thread_count() -> usize {
+    fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+/// Test that thousands of idle subscribers are handled without a thread
+/// each, and that they are all still delivered to.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_many_idle_subscribers() {
+    let server = Arc::new(
+        AsyncServer::bind("127.0.0.1", 0, Config::default())
+            .await
+            .unwrap(),
+    );
+    let addr = server.local_addr().unwrap();
+    let running = server.clone();
+    tokio::spawn(async move { running.run().await });
+    let threads = thread_count();
+
+    let mut subscribers = Vec::with_capacity(SUBSCRIBERS);
+    for _ in 0..SUBSCRIBERS {
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        subscriber
+            .write_all(&protocol::encode_frame(b"SUBSCRIBE idle"))
+            .await
+            .unwrap();
+        subscribers.push(BufReader::new(subscriber));
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.subscriptions().count("idle") < SUBSCRIBERS {
+        assert!(
+            Instant::now() < deadline,
+            "the subscribers were not all added"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Connections are tasks on the runtime's existing workers, so none of
+    // them has a thread of its own.
+    assert!(
+        thread_count() <= threads + 2,
+        "{} threads for {} connections, up from {}",
+        thread_count(),
+        SUBSCRIBERS,
+        threads
+    );
+
+    let mut publisher = TcpStream::connect(addr).await.unwrap();
+    publisher
+        .write_all(&protocol::encode_frame(b"PUBLISH idle\x1fwake up"))
+        .await
+        .unwrap();
+    for subscriber in &mut subscribers {
+        let mut line = String::new();
+        subscriber.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "wake up\n");
+    }
+}